@@ -0,0 +1,44 @@
+//! Emulation speed: `cargo bench --bench emulation`.
+//!
+//! Throughput is reported in emulated clocks, so `Melem/s` reads as the
+//! emulated MHz (the real machine runs at 2MHz).
+
+#[macro_use]
+extern crate criterion;
+extern crate wasm_invaders;
+
+use criterion::{Benchmark, Criterion, Throughput};
+use wasm_invaders::{Game, Ev};
+
+const CLOCK: u32 = 2_000_000;
+/// One emulated second for every iteration.
+const FRAMES: u32 = 60;
+
+fn attract_mode(c: &mut Criterion) {
+    c.bench("emulation", Benchmark::new("attract_mode", |b| {
+        let mut game = Game::new();
+        let mut si = game.space_invaders();
+        b.iter(|| for _ in 0..FRAMES {
+            si.next_frame()
+        })
+    }).throughput(Throughput::Elements(CLOCK)));
+}
+
+fn playing(c: &mut Criterion) {
+    c.bench("emulation", Benchmark::new("playing", |b| {
+        let mut game = Game::new();
+        let mut si = game.space_invaders();
+        for &(ev, frames) in [(Ev::Coin, 10), (Ev::P1Start, 10), (Ev::P1Shoot, 0)].iter() {
+            si.set_inputs(ev.mask());
+            for _ in 0..frames {
+                si.next_frame()
+            }
+        }
+        b.iter(|| for _ in 0..FRAMES {
+            si.next_frame()
+        })
+    }).throughput(Throughput::Elements(CLOCK)));
+}
+
+criterion_group!(benches, attract_mode, playing);
+criterion_main!(benches);
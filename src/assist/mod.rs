@@ -0,0 +1,362 @@
+//! Input assists: autofire and macros.
+//!
+//! Both work on emulated frames, so they are deterministic: the machine
+//! sees (and a movie records) the inputs they produce.
+//!
+//! An autofire action held down is pressed for `period` frames and released
+//! for as many, starting from the press. A macro is a recorded sequence of
+//! input changes that can be replayed over the user inputs. Its text format
+//! has a line for every change: the frame (from the start of the macro), the
+//! input name (as `Ev::name()`) and `down` or `up`.
+//!
+//! The autofire counters and the macro in play are part of the machine state
+//! (see `AssistState` and `snapshot`); the macros and the recording are not.
+//!
+//! ```text
+//! 0 p1left down
+//! 12 p1shoot down
+//! 14 p1shoot up
+//! 20 p1left up
+//! ```
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use Ev;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MacroError {
+    Frame(usize),
+    Input(usize, String),
+    State(usize, String),
+    /// Frames must be in order.
+    Order(usize),
+}
+
+impl fmt::Display for MacroError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MacroError::Frame(line) => write!(f, "Line {}: invalid frame number", line),
+            MacroError::Input(line, ref name) => write!(f, "Line {}: unknown input '{}'", line, name),
+            MacroError::State(line, ref state) =>
+                write!(f, "Line {}: '{}' should be 'down' or 'up'", line, state),
+            MacroError::Order(line) => write!(f, "Line {}: frame before the previous one", line),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Macro {
+    events: Vec<(u64, Ev, bool)>,
+}
+
+impl Macro {
+    pub fn parse(text: &str) -> Result<Self, MacroError> {
+        let mut m = Macro::default();
+        for (n, line) in text.lines().enumerate().map(|(n, l)| (n + 1, l)) {
+            let line = line.split('#').next().unwrap_or("").trim();
+            let mut words = line.split_whitespace();
+            let frame = match words.next() {
+                Some(w) => w.parse().map_err(|_| MacroError::Frame(n))?,
+                None => continue,
+            };
+            let name = words.next().unwrap_or("");
+            let ev = Ev::from_name(name).ok_or_else(|| MacroError::Input(n, name.to_string()))?;
+            let pressed = match words.next().unwrap_or("") {
+                "down" => true,
+                "up" => false,
+                state => return Err(MacroError::State(n, state.to_string())),
+            };
+            if m.events.last().map(|&(f, _, _)| f > frame).unwrap_or(false) {
+                return Err(MacroError::Order(n));
+            }
+            m.events.push((frame, ev, pressed));
+        }
+        Ok(m)
+    }
+
+    /// A macro from its `events`: `None` if they are not in frame order.
+    pub fn from_events(events: Vec<(u64, Ev, bool)>) -> Option<Self> {
+        match events.windows(2).all(|w| w[0].0 <= w[1].0) {
+            true => Some(Macro { events }),
+            false => None,
+        }
+    }
+
+    pub fn events(&self) -> &[(u64, Ev, bool)] {
+        &self.events
+    }
+
+    /// Frames to replay it.
+    pub fn len(&self) -> u64 {
+        self.events.last().map(|&(f, _, _)| f + 1).unwrap_or(0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+}
+
+impl fmt::Display for Macro {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for &(frame, ev, pressed) in self.events.iter() {
+            writeln!(f, "{} {} {}", frame, ev.name(), if pressed { "down" } else { "up" })?;
+        }
+        Ok(())
+    }
+}
+
+/// Macro events at `frame` to change the inputs `from` to `to`.
+fn changes(from: u32, to: u32, frame: u64) -> Vec<(u64, Ev, bool)> {
+    Ev::ALL.iter()
+        .filter(|ev| (from ^ to) & ev.mask() != 0)
+        .map(|&ev| (frame, ev, to & ev.mask() != 0))
+        .collect()
+}
+
+struct Recording {
+    name: String,
+    frame: u64,
+    inputs: u32,
+    recorded: Macro,
+}
+
+/// A macro in play.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Playing {
+    /// Frames played.
+    pub frame: u64,
+    /// The next event to apply.
+    pub next: usize,
+    pub inputs: u32,
+    pub events: Macro,
+}
+
+/// The `Assist` state that changes while the machine is running.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AssistState {
+    pub autofire: [u32; 10],
+    pub held: [u32; 10],
+    pub playing: Option<Playing>,
+}
+
+#[derive(Default)]
+pub struct Assist {
+    autofire: [u32; 10],
+    held: [u32; 10],
+    macros: BTreeMap<String, Macro>,
+    recording: Option<Recording>,
+    playing: Option<Playing>,
+}
+
+impl Assist {
+    pub fn state(&self) -> AssistState {
+        AssistState {
+            autofire: self.autofire,
+            held: self.held,
+            playing: self.playing.clone(),
+        }
+    }
+
+    pub fn restore(&mut self, state: &AssistState) {
+        self.autofire = state.autofire;
+        self.held = state.held;
+        self.playing = state.playing.clone();
+    }
+
+    /// Autofire `ev` with `period` frames: 0 disables it.
+    pub fn set_autofire(&mut self, ev: Ev, period: u32) {
+        self.autofire[ev as usize] = period;
+        self.held[ev as usize] = 0;
+    }
+
+    pub fn autofire(&self, ev: Ev) -> u32 {
+        self.autofire[ev as usize]
+    }
+
+    /// Start recording the user inputs in macro `name`: it's saved by
+    /// `stop_recording()`.
+    pub fn record(&mut self, name: &str) {
+        self.recording = Some(Recording {
+            name: name.to_string(),
+            frame: 0,
+            inputs: 0,
+            recorded: Default::default(),
+        });
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording.is_some()
+    }
+
+    /// Save the recorded macro (releasing what is still pressed) and return
+    /// its name.
+    pub fn stop_recording(&mut self) -> Option<String> {
+        self.recording.take().map(|mut r| {
+            r.recorded.events.extend(changes(r.inputs, 0, r.frame));
+            self.macros.insert(r.name.clone(), r.recorded);
+            r.name
+        })
+    }
+
+    pub fn macros(&self) -> &BTreeMap<String, Macro> {
+        &self.macros
+    }
+
+    pub fn set_macro(&mut self, name: &str, m: Macro) {
+        self.macros.insert(name.to_string(), m);
+    }
+
+    pub fn remove_macro(&mut self, name: &str) -> Option<Macro> {
+        self.macros.remove(name)
+    }
+
+    /// Replay macro `name` from the next frame: return `false` if there is
+    /// no such macro.
+    pub fn play(&mut self, name: &str) -> bool {
+        match self.macros.get(name) {
+            Some(m) => {
+                self.playing = Some(Playing { frame: 0, next: 0, inputs: 0, events: m.clone() });
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.playing.is_some()
+    }
+
+    pub fn stop_playing(&mut self) {
+        self.playing = None;
+    }
+
+    /// The inputs of a frame: record the user `inputs` and apply the macro
+    /// in play and the autofire.
+    pub fn step(&mut self, inputs: u32) -> u32 {
+        if let Some(ref mut r) = self.recording {
+            r.recorded.events.extend(changes(r.inputs, inputs, r.frame));
+            r.inputs = inputs;
+            r.frame += 1;
+        }
+        let mut inputs = inputs | self.macro_inputs();
+        for &ev in Ev::ALL.iter() {
+            let i = ev as usize;
+            if self.autofire[i] == 0 {
+                continue;
+            }
+            if inputs & ev.mask() == 0 {
+                self.held[i] = 0;
+                continue;
+            }
+            if (self.held[i] / self.autofire[i]) % 2 == 1 {
+                inputs &= !ev.mask();
+            }
+            self.held[i] = (self.held[i] + 1) % (2 * self.autofire[i]);
+        }
+        inputs
+    }
+
+    fn macro_inputs(&mut self) -> u32 {
+        let (inputs, done) = match self.playing {
+            Some(ref mut p) => {
+                while let Some(&(frame, ev, pressed)) = p.events.events.get(p.next) {
+                    if frame > p.frame {
+                        break;
+                    }
+                    p.inputs = if pressed { p.inputs | ev.mask() } else { p.inputs & !ev.mask() };
+                    p.next += 1;
+                }
+                p.frame += 1;
+                (p.inputs, p.next == p.events.events.len())
+            }
+            None => return 0,
+        };
+        if done {
+            self.playing = None;
+        }
+        inputs
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rstest::rstest;
+
+    fn assist() -> Assist {
+        Assist::default()
+    }
+
+    fn run(assist: &mut Assist, inputs: &[u32]) -> Vec<u32> {
+        inputs.iter().map(|&i| assist.step(i)).collect()
+    }
+
+    #[rstest]
+    fn autofire_should_toggle_held_actions(mut assist: Assist) {
+        let shoot = Ev::P1Shoot.mask();
+        let left = Ev::P1Left.mask();
+        assist.set_autofire(Ev::P1Shoot, 2);
+
+        assert_eq!(vec![shoot | left, shoot, left, 0, shoot, shoot, 0, shoot],
+                   run(&mut assist, &[shoot | left, shoot, shoot | left, shoot, shoot, shoot, 0, shoot]));
+    }
+
+    #[rstest]
+    fn recorded_macro_should_replay_inputs(mut assist: Assist) {
+        let left = Ev::P1Left.mask();
+        let shoot = Ev::P1Shoot.mask();
+        assist.record("dodge");
+        run(&mut assist, &[0, left, left | shoot, left]);
+        assert_eq!(Some("dodge".to_string()), assist.stop_recording());
+
+        assert!(assist.play("dodge"));
+        assert_eq!(vec![0, left, left | shoot, left, 0, 0],
+                   run(&mut assist, &[0, 0, 0, 0, 0, 0]));
+        assert!(!assist.is_playing());
+    }
+
+    #[rstest]
+    fn macro_should_play_over_user_inputs(mut assist: Assist) {
+        assist.set_macro("fire", Macro::parse("0 p1shoot down\n1 p1shoot up").unwrap());
+
+        assist.play("fire");
+
+        assert_eq!(vec![Ev::P1Left.mask() | Ev::P1Shoot.mask(), Ev::P1Left.mask()],
+                   run(&mut assist, &[Ev::P1Left.mask(), Ev::P1Left.mask()]));
+    }
+
+    #[rstest]
+    fn restored_state_should_play_the_same(mut assist: Assist) {
+        let shoot = Ev::P1Shoot.mask();
+        assist.set_autofire(Ev::P1Shoot, 3);
+        assist.set_macro("left", Macro::parse("0 p1left down\n5 p1left up").unwrap());
+        assist.play("left");
+        run(&mut assist, &[shoot, shoot]);
+        let state = assist.state();
+        let expected = run(&mut assist, &[shoot; 6]);
+
+        let mut restored = Assist::default();
+        restored.restore(&state);
+
+        assert_eq!(expected, run(&mut restored, &[shoot; 6]));
+    }
+
+    #[test]
+    fn macro_text_round_trip() {
+        let text = "0 p1left down\n12 p1shoot down\n14 p1shoot up\n20 p1left up\n";
+
+        let m = Macro::parse(text).unwrap();
+
+        assert_eq!(21, m.len());
+        assert_eq!(text, m.to_string());
+    }
+
+    #[test]
+    fn invalid_macros() {
+        assert_eq!(Err(MacroError::Frame(1)), Macro::parse("x coin down"));
+        assert_eq!(Err(MacroError::Input(1, "p3".to_string())), Macro::parse("1 p3 down"));
+        assert_eq!(Err(MacroError::State(1, "".to_string())), Macro::parse("1 coin"));
+        assert_eq!(Err(MacroError::Order(2)), Macro::parse("5 coin down\n1 coin up"));
+    }
+}
@@ -0,0 +1,267 @@
+//! Play Space Invaders in a terminal: `cargo run --release --bin terminal`.
+//!
+//! The screen is drawn with braille characters (2x4 pixels for every cell,
+//! 112x64 cells) or, with `--half-block`, with half block characters (1x2
+//! pixels, 224x128 cells), colored by the overlay. It needs a unicode
+//! terminal with ANSI colors: on linux the tty is put in raw mode by `stty`.
+//!
+//! `--hiscore FILE` keeps the high score in `FILE`.
+//!
+//! `--hold FRAMES` changes how long a key is held (see below).
+//!
+//! Keys: `c` coin, `1`/`2` start, arrows and space player 1, `a` `d` `w`
+//! player 2, `t` tilt, `p` pause and `q` (or Ctrl-C) quit.
+//!
+//! A terminal sends just key presses (with auto repeat) and no releases, so
+//! every key is kept pressed for `HOLD` frames (600 ms) after the last press:
+//! it must be longer than the auto repeat delay (250-500 ms on most
+//! terminals) or a key held down is released till the repeat starts.
+
+extern crate wasm_invaders;
+
+#[cfg(not(target_arch = "wasm32"))]
+mod terminal {
+    use std::io::{self, Read, Write};
+    use std::process::{Command, Stdio};
+    use std::sync::mpsc::{channel, Receiver};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use wasm_invaders::{Game, Ev, SpaceInvaders};
+    use wasm_invaders::hiscore::FileStorage;
+    use wasm_invaders::render::{lit, overlay, Rgb, WIDTH, HEIGHT, RED, GREEN};
+
+    const HOLD: u32 = 36;
+    const FRAME: Duration = Duration::from_micros(16_667);
+
+    const ESC: u8 = 0x1b;
+    const CTRL_C: u8 = 0x03;
+
+    #[derive(Clone, Copy, PartialEq, Eq)]
+    enum Mode {
+        Braille,
+        HalfBlock,
+    }
+
+    enum Key {
+        Press(Ev),
+        Pause,
+        Quit,
+    }
+
+    /// Put the terminal in raw mode till dropped.
+    struct RawMode {
+        saved: String,
+    }
+
+    fn stty(args: &[&str]) -> io::Result<String> {
+        let out = Command::new("stty").args(args).stdin(Stdio::inherit()).output()?;
+        match out.status.success() {
+            true => Ok(String::from_utf8_lossy(&out.stdout).trim().to_string()),
+            false => Err(io::Error::new(io::ErrorKind::Other, "stty failed: is stdin a tty?")),
+        }
+    }
+
+    impl RawMode {
+        fn enable() -> io::Result<Self> {
+            let saved = stty(&["-g"])?;
+            stty(&["raw", "-echo"])?;
+            print!("\x1b[?25l\x1b[2J");
+            Ok(RawMode { saved })
+        }
+    }
+
+    impl Drop for RawMode {
+        fn drop(&mut self) {
+            print!("\x1b[0m\x1b[?25h\r\n");
+            let _ = io::stdout().flush();
+            let _ = stty(&[&self.saved]);
+        }
+    }
+
+    fn key(byte: u8, escape: &mut Vec<u8>) -> Option<Key> {
+        if !escape.is_empty() || byte == ESC {
+            escape.push(byte);
+            return match escape.as_slice() {
+                [ESC] | [ESC, b'['] => None,
+                [ESC, b'[', code] => {
+                    let ev = match *code {
+                        b'D' => Some(Key::Press(Ev::P1Left)),
+                        b'C' => Some(Key::Press(Ev::P1Right)),
+                        _ => None,
+                    };
+                    escape.clear();
+                    ev
+                }
+                _ => {
+                    escape.clear();
+                    None
+                }
+            };
+        }
+        match byte {
+            b'c' => Some(Key::Press(Ev::Coin)),
+            b't' => Some(Key::Press(Ev::Tilt)),
+            b'1' => Some(Key::Press(Ev::P1Start)),
+            b'2' => Some(Key::Press(Ev::P2Start)),
+            b' ' => Some(Key::Press(Ev::P1Shoot)),
+            b'a' => Some(Key::Press(Ev::P2Left)),
+            b'd' => Some(Key::Press(Ev::P2Right)),
+            b'w' => Some(Key::Press(Ev::P2Shoot)),
+            b'p' => Some(Key::Pause),
+            b'q' | CTRL_C => Some(Key::Quit),
+            _ => None,
+        }
+    }
+
+    fn keys() -> Receiver<Key> {
+        let (tx, rx) = channel();
+        thread::spawn(move || {
+            let mut escape = Vec::new();
+            for byte in io::stdin().bytes() {
+                let byte = match byte {
+                    Ok(b) => b,
+                    Err(_) => break,
+                };
+                if let Some(k) = key(byte, &mut escape) {
+                    if tx.send(k).is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+        rx
+    }
+
+    fn color(c: Rgb) -> &'static str {
+        match c {
+            RED => "\x1b[31m",
+            GREEN => "\x1b[32m",
+            _ => "\x1b[37m",
+        }
+    }
+
+    /// Draw the screen: `(w, h)` is the pixels size of a cell and `glyph`
+    /// build the character from the cell lit pixels (bit `y * w + x`).
+    fn draw(si: &SpaceInvaders, w: usize, h: usize, glyph: fn(u32) -> char) -> String {
+        let vram = si.video_ram();
+        let flipped = si.flipped();
+        let pixel = |x: usize, y: usize| match flipped {
+            true => (WIDTH - 1 - x, HEIGHT - 1 - y),
+            false => (x, y),
+        };
+        let mut out = String::from("\x1b[H");
+        let mut current = "";
+        for cy in 0..HEIGHT / h {
+            for cx in 0..WIDTH / w {
+                let mut bits = 0;
+                for dy in 0..h {
+                    for dx in 0..w {
+                        let (x, y) = pixel(cx * w + dx, cy * h + dy);
+                        if lit(&vram, x, y) {
+                            bits |= 1 << (dy * w + dx);
+                        }
+                    }
+                }
+                let (x, y) = pixel(cx * w, cy * h);
+                let c = color(overlay(x, y));
+                if c != current {
+                    out.push_str(c);
+                    current = c;
+                }
+                out.push(glyph(bits));
+            }
+            out.push_str("\r\n");
+        }
+        out
+    }
+
+    fn braille(bits: u32) -> char {
+        // Braille dots numbering: 1 4 / 2 5 / 3 6 / 7 8
+        const DOTS: [u32; 8] = [0x01, 0x08, 0x02, 0x10, 0x04, 0x20, 0x40, 0x80];
+        let code = (0..8).filter(|i| bits & (1 << i) != 0).fold(0, |acc, i| acc | DOTS[i]);
+        ::std::char::from_u32(0x2800 + code).unwrap_or(' ')
+    }
+
+    fn half_block(bits: u32) -> char {
+        match bits {
+            0 => ' ',
+            1 => '▀',
+            2 => '▄',
+            _ => '█',
+        }
+    }
+
+    pub fn main() -> io::Result<()> {
+        let mode = match ::std::env::args().any(|a| a == "--half-block") {
+            true => Mode::HalfBlock,
+            false => Mode::Braille,
+        };
+        let mut game = Game::new();
+        let mut si = game.space_invaders();
+        let args: Vec<String> = ::std::env::args().collect();
+        if let Some(path) = args.iter().position(|a| a == "--hiscore").and_then(|i| args.get(i + 1)) {
+            si.set_hiscore_storage(FileStorage::new(path.as_str()));
+        }
+        let hold = match args.iter().position(|a| a == "--hold").and_then(|i| args.get(i + 1)) {
+            Some(frames) => frames.parse::<u32>()
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "--hold needs a number of frames"))?,
+            None => HOLD,
+        };
+        let _raw = RawMode::enable()?;
+        let keys = keys();
+        let mut held = [0u32; 10];
+        let mut paused = false;
+        let mut last = Instant::now();
+        let stdout = io::stdout();
+
+        loop {
+            while let Ok(k) = keys.try_recv() {
+                match k {
+                    Key::Press(ev) => held[ev as usize] = hold,
+                    Key::Pause => {
+                        paused = !paused;
+                        si.pause(paused);
+                    }
+                    Key::Quit => return Ok(()),
+                }
+            }
+            let mask = Ev::ALL.iter()
+                .filter(|&&ev| held[ev as usize] > 0)
+                .fold(0, |mask, &ev| mask | ev.mask());
+            si.set_inputs(mask);
+
+            let now = Instant::now();
+            let elapsed = now - last;
+            last = now;
+            let frames = si.run_for(elapsed.as_secs() as f64 * 1000.0 + elapsed.subsec_micros() as f64 / 1000.0);
+            for h in held.iter_mut() {
+                *h = h.saturating_sub(frames);
+            }
+
+            let screen = match mode {
+                Mode::Braille => draw(&si, 2, 4, braille),
+                Mode::HalfBlock => draw(&si, 1, 2, half_block),
+            };
+            let mut out = stdout.lock();
+            out.write_all(screen.as_bytes())?;
+            out.flush()?;
+
+            let spent = now.elapsed();
+            if spent < FRAME {
+                thread::sleep(FRAME - spent);
+            }
+        }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn main() {
+    if let Err(e) = terminal::main() {
+        eprintln!("{}", e);
+        ::std::process::exit(1);
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+fn main() {}
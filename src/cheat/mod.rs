@@ -0,0 +1,399 @@
+//! Cheat engine: ram search and frozen addresses.
+//!
+//! Cheats can be loaded and saved in a subset of the MAME cheat XML format:
+//! every `cheat` element is a list of `action`s that write a byte in the main
+//! cpu address space (`maincpu.pb@ADDR=VALUE`, hex values) and they are applied
+//! at the begin of every frame while the cheat is enabled.
+//!
+//! ```xml
+//! <mamecheat version="1">
+//!   <cheat desc="Infinite Lives P1">
+//!     <script state="run">
+//!       <action>maincpu.pb@21FF=03</action>
+//!     </script>
+//!   </cheat>
+//! </mamecheat>
+//! ```
+
+use std::fmt;
+
+use rs8080::{Address, Byte};
+use si::memory::Ram;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Cmp {
+    /// Not changed since last snapshot.
+    Equal,
+    Changed,
+    Greater,
+    Less,
+    /// Has exactly this value.
+    Value(Byte),
+}
+
+impl Cmp {
+    fn matches(&self, old: Byte, new: Byte) -> bool {
+        match *self {
+            Cmp::Equal => new == old,
+            Cmp::Changed => new != old,
+            Cmp::Greater => new > old,
+            Cmp::Less => new < old,
+            Cmp::Value(v) => new == v,
+        }
+    }
+}
+
+/// Iterative ram search: every `filter()` call keep just the candidates
+/// addresses that satisfy the comparison against the previous snapshot.
+#[derive(Default)]
+pub struct Search {
+    candidates: Vec<Address>,
+    snapshot: Vec<Byte>,
+    base: Address,
+}
+
+impl Search {
+    pub fn start(ram: &Ram) -> Self {
+        let addresses = ram.addresses();
+        Search {
+            base: addresses.start,
+            candidates: addresses.collect(),
+            snapshot: ram.snapshot(),
+        }
+    }
+
+    pub fn filter(&mut self, ram: &Ram, cmp: Cmp) {
+        let current = ram.snapshot();
+        {
+            let base = self.base;
+            let snapshot = &self.snapshot;
+            self.candidates.retain(|&a| {
+                let offset = (a - base) as usize;
+                cmp.matches(snapshot[offset], current[offset])
+            });
+        }
+        self.snapshot = current;
+    }
+
+    pub fn candidates(&self) -> &[Address] {
+        &self.candidates
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Poke {
+    pub address: Address,
+    pub value: Byte,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cheat {
+    pub desc: String,
+    pub enabled: bool,
+    pub pokes: Vec<Poke>,
+}
+
+impl Cheat {
+    pub fn freeze(address: Address, value: Byte) -> Self {
+        Cheat {
+            desc: format!("Freeze {:04X}", address),
+            enabled: true,
+            pokes: vec![Poke { address, value }],
+        }
+    }
+
+    /// Is it the cheat added by `Cheats::freeze()` for `address`?
+    pub fn is_freeze(&self, address: Address) -> bool {
+        self.pokes.len() == 1 && self.pokes[0].address == address
+            && self.desc == format!("Freeze {:04X}", address)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum CheatError {
+    Syntax(String),
+    Action(String),
+    Address(Address),
+}
+
+impl fmt::Display for CheatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CheatError::Syntax(ref msg) => write!(f, "Invalid cheat file: {}", msg),
+            CheatError::Action(ref action) => write!(f, "Unsupported cheat action '{}'", action),
+            CheatError::Address(address) => write!(f, "Address {:04X} is not in ram", address),
+        }
+    }
+}
+
+/// The list of the known cheats: the enabled ones are applied every frame.
+#[derive(Default)]
+pub struct Cheats {
+    list: Vec<Cheat>,
+}
+
+impl Cheats {
+    pub fn apply(&self, ram: &Ram) {
+        for poke in self.list.iter().filter(|c| c.enabled).flat_map(|c| c.pokes.iter()) {
+            ram.set(poke.address, poke.value);
+        }
+    }
+
+    pub fn add(&mut self, cheat: Cheat) {
+        self.list.push(cheat);
+    }
+
+    pub fn freeze(&mut self, ram: &Ram, address: Address, value: Byte) -> Result<(), CheatError> {
+        if ram.get(address).is_none() {
+            return Err(CheatError::Address(address));
+        }
+        self.unfreeze(address);
+        self.add(Cheat::freeze(address, value));
+        Ok(())
+    }
+
+    /// Remove the freeze of `address`: other cheats that write at `address`
+    /// are kept.
+    pub fn unfreeze(&mut self, address: Address) {
+        self.list.retain(|c| !c.is_freeze(address))
+    }
+
+    pub fn enable(&mut self, desc: &str, enabled: bool) -> bool {
+        let mut found = false;
+        for cheat in self.list.iter_mut().filter(|c| c.desc == desc) {
+            cheat.enabled = enabled;
+            found = true;
+        }
+        found
+    }
+
+    pub fn list(&self) -> &[Cheat] {
+        &self.list
+    }
+
+    pub fn clear(&mut self) {
+        self.list.clear();
+    }
+
+    /// Load cheats from MAME cheat XML: loaded cheats are disabled.
+    pub fn load(&mut self, xml: &str) -> Result<usize, CheatError> {
+        let cheats = parse(xml)?;
+        let n = cheats.len();
+        self.list.extend(cheats);
+        Ok(n)
+    }
+
+    pub fn save(&self) -> String {
+        let mut out = String::from("<mamecheat version=\"1\">\n");
+        for cheat in self.list.iter() {
+            out.push_str(&format!("  <cheat desc=\"{}\">\n", escape(&cheat.desc)));
+            out.push_str("    <script state=\"run\">\n");
+            for poke in cheat.pokes.iter() {
+                out.push_str(&format!("      <action>maincpu.pb@{:04X}={:02X}</action>\n",
+                                      poke.address, poke.value));
+            }
+            out.push_str("    </script>\n");
+            out.push_str("  </cheat>\n");
+        }
+        out.push_str("</mamecheat>\n");
+        out
+    }
+}
+
+fn parse(xml: &str) -> Result<Vec<Cheat>, CheatError> {
+    let mut cheats = Vec::new();
+    let mut rest = xml;
+    while let Some(start) = find_tag(rest, "cheat") {
+        rest = &rest[start..];
+        let end = rest.find("</cheat>")
+            .ok_or_else(|| CheatError::Syntax("missing </cheat>".to_string()))?;
+        let body = &rest[..end];
+        let tag = &body[..body.find('>').unwrap_or(body.len())];
+        let desc = attribute(tag, "desc")
+            .ok_or_else(|| CheatError::Syntax("cheat without desc".to_string()))?;
+        let pokes = elements(body, "action").into_iter()
+            .map(action)
+            .collect::<Result<Vec<_>, _>>()?;
+        cheats.push(Cheat { desc: unescape(desc), enabled: false, pokes });
+        rest = &rest[end + "</cheat>".len()..];
+    }
+    Ok(cheats)
+}
+
+/// Where the `name` element starts: `<name` followed by a blank or `>`.
+fn find_tag(xml: &str, name: &str) -> Option<usize> {
+    let open = format!("<{}", name);
+    xml.match_indices(open.as_str())
+        .map(|(pos, _)| pos)
+        .find(|&pos| xml[pos + open.len()..].chars().next()
+            .map(|c| c == '>' || c.is_whitespace())
+            .unwrap_or(false))
+}
+
+fn attribute<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    let key = format!("{}=\"", name);
+    let start = tag.find(&key)? + key.len();
+    let len = tag[start..].find('"')?;
+    Some(&tag[start..start + len])
+}
+
+fn elements<'a>(body: &'a str, name: &str) -> Vec<&'a str> {
+    let open = format!("<{}>", name);
+    let close = format!("</{}>", name);
+    body.split(open.as_str())
+        .skip(1)
+        .filter_map(|s| s.find(close.as_str()).map(|end| s[..end].trim()))
+        .collect()
+}
+
+fn action(action: &str) -> Result<Poke, CheatError> {
+    let err = || CheatError::Action(action.to_string());
+    const PREFIX: &str = "maincpu.pb@";
+    if !action.starts_with(PREFIX) {
+        return Err(err());
+    }
+    let assign = &action[PREFIX.len()..];
+    let mut parts = assign.splitn(2, '=');
+    let address = parts.next().and_then(|a| Address::from_str_radix(a.trim(), 16).ok())
+        .ok_or_else(err)?;
+    let value = parts.next().and_then(|v| Byte::from_str_radix(v.trim(), 16).ok())
+        .ok_or_else(err)?;
+    Ok(Poke { address, value })
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('"', "&quot;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+fn unescape(s: &str) -> String {
+    s.replace("&quot;", "\"").replace("&lt;", "<").replace("&gt;", ">").replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rstest::rstest_parametrize;
+
+    fn ram() -> Ram {
+        Ram::default()
+    }
+
+    const LIVES: &str = r#"<mamecheat version="1">
+  <cheat desc="Infinite Lives P1">
+    <script state="run">
+      <action>maincpu.pb@21FF=03</action>
+    </script>
+  </cheat>
+  <cheat desc="Two &amp; more">
+    <script state="run">
+      <action>maincpu.pb@20EB=99</action>
+      <action>maincpu.pb@21FF=05</action>
+    </script>
+  </cheat>
+</mamecheat>
+"#;
+
+    #[rstest_parametrize(
+    cmp, old, new, expected,
+    case(Unwrap("Cmp::Equal"), 3, 3, true),
+    case(Unwrap("Cmp::Equal"), 3, 2, false),
+    case(Unwrap("Cmp::Changed"), 3, 2, true),
+    case(Unwrap("Cmp::Greater"), 3, 4, true),
+    case(Unwrap("Cmp::Greater"), 3, 2, false),
+    case(Unwrap("Cmp::Less"), 3, 2, true),
+    case(Unwrap("Cmp::Value(7)"), 3, 7, true),
+    )]
+    fn compare(cmp: Cmp, old: Byte, new: Byte, expected: bool) {
+        assert_eq!(expected, cmp.matches(old, new));
+    }
+
+    #[test]
+    fn search_should_find_lives_counter() {
+        let ram = ram();
+        ram.set(0x21FF, 3);
+        let mut search = Search::start(&ram);
+
+        ram.set(0x21FF, 2);
+        ram.set(0x2100, 9);
+        search.filter(&ram, Cmp::Less);
+        search.filter(&ram, Cmp::Equal);
+        ram.set(0x21FF, 1);
+        search.filter(&ram, Cmp::Value(1));
+
+        assert_eq!(&[0x21FF], search.candidates());
+    }
+
+    #[test]
+    fn frozen_address_should_be_restored() {
+        let ram = ram();
+        let mut cheats = Cheats::default();
+        cheats.freeze(&ram, 0x21FF, 3).unwrap();
+
+        ram.set(0x21FF, 1);
+        cheats.apply(&ram);
+        assert_eq!(Some(3), ram.get(0x21FF));
+
+        cheats.unfreeze(0x21FF);
+        ram.set(0x21FF, 1);
+        cheats.apply(&ram);
+        assert_eq!(Some(1), ram.get(0x21FF));
+    }
+
+    #[test]
+    fn freeze_should_keep_named_cheats() {
+        let ram = ram();
+        let mut cheats = Cheats::default();
+        cheats.load(LIVES).unwrap();
+        cheats.add(Cheat { desc: "Empty".to_string(), enabled: false, pokes: vec![] });
+
+        cheats.freeze(&ram, 0x21FF, 9).unwrap();
+        cheats.freeze(&ram, 0x21FF, 7).unwrap();
+        assert_eq!(4, cheats.list().len());
+        cheats.unfreeze(0x21FF);
+
+        let descs = cheats.list().iter().map(|c| c.desc.as_str()).collect::<Vec<_>>();
+        assert_eq!(vec!["Infinite Lives P1", "Two & more", "Empty"], descs);
+    }
+
+    #[test]
+    fn cannot_freeze_rom() {
+        assert_eq!(Err(CheatError::Address(0x0010)),
+                   Cheats::default().freeze(&ram(), 0x0010, 0));
+    }
+
+    #[test]
+    fn load_xml() {
+        let mut cheats = Cheats::default();
+
+        assert_eq!(Ok(2), cheats.load(LIVES));
+
+        let list = cheats.list();
+        assert_eq!("Infinite Lives P1", list[0].desc);
+        assert_eq!(vec![Poke { address: 0x21FF, value: 0x03 }], list[0].pokes);
+        assert_eq!("Two & more", list[1].desc);
+        assert_eq!(2, list[1].pokes.len());
+        assert!(list.iter().all(|c| !c.enabled));
+    }
+
+    #[test]
+    fn save_and_reload() {
+        let mut cheats = Cheats::default();
+        cheats.load(LIVES).unwrap();
+
+        let mut reloaded = Cheats::default();
+        reloaded.load(&cheats.save()).unwrap();
+
+        assert_eq!(cheats.list(), reloaded.list());
+    }
+
+    #[rstest_parametrize(
+    xml,
+    case("<cheat desc=\"a\"><action>maincpu.pb@21FF=03</action>"),
+    case("<cheat><action>maincpu.pb@21FF=03</action></cheat>"),
+    case("<cheat desc=\"a\"><action>maincpu.pw@21FF=0003</action></cheat>"),
+    case("<cheat desc=\"a\"><action>maincpu.pb@21FF=XX</action></cheat>"),
+    )]
+    fn load_invalid(xml: &str) {
+        assert!(Cheats::default().load(xml).is_err());
+    }
+}
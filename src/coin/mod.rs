@@ -0,0 +1,281 @@
+//! Coin mechanism and bookkeeping.
+//!
+//! The coin switch goes through the mech before the cpu sees it:
+//!
+//! * a coin is valid just if the switch stays closed for at least
+//!   `min_pulse` frames (shorter pulses are bounces and are ignored)
+//! * the lockout coil rejects coins when the operator enables it or when the
+//!   credits are already at the maximum (99): rejected coins never reach the
+//!   cpu
+//! * every valid coin advances the mechanical coin counter, that is never
+//!   reset (but can be set to restore a saved one)
+//!
+//! The mech also keeps the session bookkeeping: coins inserted and rejected,
+//! credits spent and games started (read from ram after every frame).
+//!
+//! In free play the mech injects its own coin pulses (that are not counted
+//! as coins) whenever the credits are below the configured ones, so Start
+//! always begins a game.
+
+pub const DEFAULT_MIN_PULSE: u32 = 2;
+pub const MAX_CREDITS: u32 = 99;
+
+/// Free play coin pulse: frames closed and then open before the next one.
+const FREE_PULSE: u32 = 4;
+const FREE_GAP: u32 = 8;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Bookkeeping {
+    pub coins: u64,
+    pub rejected: u64,
+    pub credits_used: u64,
+    pub games: u64,
+    /// Coin pulses injected by free play.
+    pub free: u64,
+}
+
+/// The `CoinMech` state that changes while the machine is running (the
+/// settings are not included).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CoinState {
+    pub closed: u32,
+    pub accepted: bool,
+    pub credits: u32,
+    pub playing: bool,
+    pub free_phase: u32,
+    pub counter: u64,
+    pub bookkeeping: Bookkeeping,
+}
+
+pub struct CoinMech {
+    min_pulse: u32,
+    lockout: bool,
+    counter: u64,
+    /// Frames since the switch was closed.
+    closed: u32,
+    accepted: bool,
+    bookkeeping: Bookkeeping,
+    credits: u32,
+    playing: bool,
+    free_play: Option<u32>,
+    /// Frames since the last free play pulse started.
+    free_phase: u32,
+}
+
+impl Default for CoinMech {
+    fn default() -> Self {
+        Self::new(DEFAULT_MIN_PULSE)
+    }
+}
+
+impl CoinMech {
+    pub fn new(min_pulse: u32) -> Self {
+        CoinMech {
+            min_pulse: min_pulse.max(1),
+            lockout: false,
+            counter: 0,
+            closed: 0,
+            accepted: false,
+            bookkeeping: Default::default(),
+            credits: 0,
+            playing: false,
+            free_play: None,
+            free_phase: FREE_PULSE + FREE_GAP,
+        }
+    }
+
+    pub fn state(&self) -> CoinState {
+        CoinState {
+            closed: self.closed,
+            accepted: self.accepted,
+            credits: self.credits,
+            playing: self.playing,
+            free_phase: self.free_phase,
+            counter: self.counter,
+            bookkeeping: self.bookkeeping,
+        }
+    }
+
+    pub fn restore(&mut self, state: &CoinState) {
+        self.closed = state.closed;
+        self.accepted = state.accepted;
+        self.credits = state.credits;
+        self.playing = state.playing;
+        self.free_phase = state.free_phase;
+        self.counter = state.counter;
+        self.bookkeeping = state.bookkeeping;
+    }
+
+    pub fn min_pulse(&self) -> u32 {
+        self.min_pulse
+    }
+
+    pub fn set_min_pulse(&mut self, frames: u32) {
+        self.min_pulse = frames.max(1);
+    }
+
+    pub fn lockout(&self) -> bool {
+        self.lockout
+    }
+
+    pub fn set_lockout(&mut self, lockout: bool) {
+        self.lockout = lockout;
+    }
+
+    pub fn counter(&self) -> u64 {
+        self.counter
+    }
+
+    pub fn set_counter(&mut self, counter: u64) {
+        self.counter = counter;
+    }
+
+    pub fn bookkeeping(&self) -> Bookkeeping {
+        self.bookkeeping
+    }
+
+    pub fn reset_bookkeeping(&mut self) {
+        self.bookkeeping = Default::default();
+    }
+
+    pub fn free_play(&self) -> Option<u32> {
+        self.free_play
+    }
+
+    /// Keep at least `credits` credits (clamped to 1 - 99); `None` disables
+    /// free play.
+    pub fn set_free_play(&mut self, credits: Option<u32>) {
+        self.free_play = credits.map(|c| c.max(1).min(MAX_CREDITS));
+    }
+
+    /// Filter the coin switch state (`pressed`) for the next frame and
+    /// return the coin line seen by the cpu.
+    pub fn step(&mut self, pressed: bool) -> bool {
+        let coin = self.coin(pressed);
+        let free = self.free_pulse();
+        coin || free
+    }
+
+    fn free_pulse(&mut self) -> bool {
+        let target = match self.free_play {
+            Some(target) => target,
+            None => return false,
+        };
+        if self.free_phase >= FREE_PULSE + FREE_GAP {
+            if self.credits >= target {
+                return false;
+            }
+            self.free_phase = 0;
+            self.bookkeeping.free += 1;
+        }
+        self.free_phase += 1;
+        self.free_phase <= FREE_PULSE
+    }
+
+    fn coin(&mut self, pressed: bool) -> bool {
+        if !pressed {
+            self.closed = 0;
+            return false;
+        }
+        self.closed = self.closed.saturating_add(1);
+        if self.closed == self.min_pulse {
+            self.accepted = !self.lockout && self.credits < MAX_CREDITS;
+            match self.accepted {
+                true => {
+                    self.bookkeeping.coins += 1;
+                    self.counter += 1;
+                }
+                false => self.bookkeeping.rejected += 1,
+            }
+        }
+        self.closed >= self.min_pulse && self.accepted
+    }
+
+    /// Look at the game state after a frame: spent credits and started games.
+    pub fn observe(&mut self, playing: bool, credits: u32) {
+        if credits < self.credits {
+            self.bookkeeping.credits_used += (self.credits - credits) as u64;
+        }
+        if playing && !self.playing {
+            self.bookkeeping.games += 1;
+        }
+        self.credits = credits;
+        self.playing = playing;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn pulse(mech: &mut CoinMech, frames: u32) -> Vec<bool> {
+        let mut out = (0..frames).map(|_| mech.step(true)).collect::<Vec<_>>();
+        out.push(mech.step(false));
+        out
+    }
+
+    #[test]
+    fn short_pulses_should_be_ignored() {
+        let mut mech = CoinMech::new(3);
+
+        assert_eq!(vec![false, false, false], pulse(&mut mech, 2));
+        assert_eq!(0, mech.counter());
+    }
+
+    #[test]
+    fn valid_coin_should_close_the_line_and_count() {
+        let mut mech = CoinMech::new(2);
+
+        assert_eq!(vec![false, true, true, false], pulse(&mut mech, 3));
+        assert_eq!(1, mech.counter());
+        assert_eq!(1, mech.bookkeeping().coins);
+    }
+
+    #[test]
+    fn lockout_should_reject_coins() {
+        let mut mech = CoinMech::new(1);
+        mech.set_lockout(true);
+
+        assert_eq!(vec![false, false], pulse(&mut mech, 1));
+        assert_eq!(0, mech.counter());
+        assert_eq!(1, mech.bookkeeping().rejected);
+    }
+
+    #[test]
+    fn full_credits_should_lock_out() {
+        let mut mech = CoinMech::new(1);
+        mech.observe(false, MAX_CREDITS);
+
+        pulse(&mut mech, 1);
+
+        assert_eq!(1, mech.bookkeeping().rejected);
+    }
+
+    #[test]
+    fn should_count_games_and_credits() {
+        let mut mech = CoinMech::new(1);
+
+        mech.observe(false, 3);
+        mech.observe(true, 1);
+        mech.observe(true, 1);
+        mech.observe(false, 1);
+        mech.observe(true, 0);
+
+        assert_eq!(Bookkeeping { coins: 0, rejected: 0, credits_used: 3, games: 2, free: 0 }, mech.bookkeeping());
+    }
+
+    #[test]
+    fn free_play_should_pulse_till_credits() {
+        let mut mech = CoinMech::new(1);
+        mech.set_free_play(Some(1));
+
+        let line = (0..FREE_PULSE + FREE_GAP).map(|_| mech.step(false)).collect::<Vec<_>>();
+        mech.observe(false, 1);
+        let after = (0..20).map(|_| mech.step(false)).collect::<Vec<_>>();
+
+        assert_eq!(vec![true, true, true, true, false, false, false, false, false, false, false, false], line);
+        assert!(after.iter().all(|&l| !l));
+        assert_eq!(1, mech.bookkeeping().free);
+        assert_eq!(0, mech.counter());
+    }
+}
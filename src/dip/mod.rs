@@ -0,0 +1,155 @@
+//! Declarative DIP switches.
+//!
+//! A board describes its switches by a static table of `DipSwitch`: every switch
+//! is a group of bits (`mask`) in an input `port` that can assume one of the
+//! listed `values`. `DipSettings` keep the selected value for each switch and
+//! can compute the input ports state.
+
+use std::fmt;
+
+use rs8080::Byte;
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct DipValue {
+    pub value: Byte,
+    pub label: &'static str,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct DipSwitch {
+    pub name: &'static str,
+    pub port: u8,
+    pub mask: Byte,
+    pub values: &'static [DipValue],
+    /// Index in `values` of the default setting.
+    pub default: usize,
+}
+
+impl DipSwitch {
+    pub fn apply(&self, port: Byte, value: usize) -> Byte {
+        (port & !self.mask) | (self.values[value].value & self.mask)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DipError {
+    UnknownSwitch(usize),
+    UnknownValue(usize, usize),
+}
+
+impl fmt::Display for DipError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DipError::UnknownSwitch(s) => write!(f, "Unknown dip switch {}", s),
+            DipError::UnknownValue(s, v) => write!(f, "Unknown value {} for dip switch {}", v, s),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DipSettings {
+    switches: &'static [DipSwitch],
+    selected: Vec<usize>,
+}
+
+impl DipSettings {
+    pub fn new(switches: &'static [DipSwitch]) -> Self {
+        DipSettings {
+            switches,
+            selected: switches.iter().map(|s| s.default).collect(),
+        }
+    }
+
+    pub fn switches(&self) -> &'static [DipSwitch] {
+        self.switches
+    }
+
+    pub fn switch(&self, switch: usize) -> Result<&'static DipSwitch, DipError> {
+        self.switches.get(switch).ok_or(DipError::UnknownSwitch(switch))
+    }
+
+    pub fn get(&self, switch: usize) -> Result<usize, DipError> {
+        self.selected.get(switch).cloned().ok_or(DipError::UnknownSwitch(switch))
+    }
+
+    pub fn set(&mut self, switch: usize, value: usize) -> Result<(), DipError> {
+        if self.switch(switch)?.values.len() <= value {
+            return Err(DipError::UnknownValue(switch, value));
+        }
+        self.selected[switch] = value;
+        Ok(())
+    }
+
+    /// Apply all switches that live in `port` to `state`.
+    pub fn port(&self, port: u8, state: Byte) -> Byte {
+        self.switches.iter().zip(self.selected.iter())
+            .filter(|&(s, _)| s.port == port)
+            .fold(state, |state, (s, &v)| s.apply(state, v))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rstest::rstest_parametrize;
+
+    static SWITCHES: &[DipSwitch] = &[
+        DipSwitch {
+            name: "Lives",
+            port: 2,
+            mask: 0x03,
+            values: &[DipValue { value: 0x00, label: "3" }, DipValue { value: 0x01, label: "4" },
+                DipValue { value: 0x02, label: "5" }],
+            default: 0,
+        },
+        DipSwitch {
+            name: "Demo Sounds",
+            port: 2,
+            mask: 0x80,
+            values: &[DipValue { value: 0x80, label: "Off" }, DipValue { value: 0x00, label: "On" }],
+            default: 1,
+        },
+        DipSwitch {
+            name: "Other Port",
+            port: 1,
+            mask: 0x01,
+            values: &[DipValue { value: 0x00, label: "Off" }, DipValue { value: 0x01, label: "On" }],
+            default: 1,
+        },
+    ];
+
+    fn settings() -> DipSettings {
+        DipSettings::new(SWITCHES)
+    }
+
+    #[test]
+    fn should_start_from_defaults() {
+        let settings = settings();
+
+        assert_eq!(Ok(0), settings.get(0));
+        assert_eq!(Ok(1), settings.get(1));
+        assert_eq!(0x00, settings.port(2, 0x00));
+        assert_eq!(0x01, settings.port(1, 0x00));
+    }
+
+    #[rstest_parametrize(
+    switch, value, state, expected,
+    case(0, 2, 0x00, 0x02),
+    case(0, 1, 0xFF, 0x7D),
+    case(1, 0, 0x00, 0x80),
+    case(1, 1, 0xFF, 0x7C),
+    )]
+    fn should_change_just_switches_bits(mut settings: DipSettings, switch: usize, value: usize, state: Byte, expected: Byte) {
+        settings.set(switch, value).unwrap();
+
+        assert_eq!(expected, settings.port(2, state));
+    }
+
+    #[test]
+    fn should_reject_unknown_settings() {
+        let mut settings = settings();
+
+        assert_eq!(Err(DipError::UnknownSwitch(3)), settings.set(3, 0));
+        assert_eq!(Err(DipError::UnknownValue(1, 2)), settings.set(1, 2));
+    }
+}
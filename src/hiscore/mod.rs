@@ -0,0 +1,306 @@
+//! High score persistence.
+//!
+//! The rom clears the high score at power up. The `Keeper` restores the
+//! saved one in ram when the boot is done (`BOOT_FRAMES` frames) and saves
+//! it again at the end of every game that beat it.
+//!
+//! The restore writes the ram outside the cpu: `SpaceInvaders::hiscore_restored()`
+//! signals it (a spectator broadcaster sends a keyframe) and netplay sessions
+//! disable the keeper. A loaded snapshot taken after the boot doesn't restore
+//! it again.
+//!
+//! The saved data is a single text line (so it fits in a browser
+//! `localStorage` entry too): the `SIHS` tag, the format version and the
+//! decimal score.
+//!
+//! ```text
+//! SIHS 1 1250
+//! ```
+
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::rc::Rc;
+
+use rs8080::Address;
+use ram_map::{bcd, to_bcd, GAME_MODE, HI_SCORE};
+use si::memory::Ram;
+
+/// The rom clears the ram in the first frames: the high score is restored
+/// after them.
+pub const BOOT_FRAMES: u64 = 120;
+pub const MAX_SCORE: u32 = 9999;
+
+const TAG: &str = "SIHS";
+const VERSION: u32 = 1;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum HiScoreError {
+    Format(String),
+    Version(u32),
+    Storage(String),
+}
+
+impl fmt::Display for HiScoreError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HiScoreError::Format(ref data) => write!(f, "Invalid high score data '{}'", data),
+            HiScoreError::Version(v) => write!(f, "Unsupported high score version {}", v),
+            HiScoreError::Storage(ref e) => write!(f, "High score storage error: {}", e),
+        }
+    }
+}
+
+pub type Result<T> = ::std::result::Result<T, HiScoreError>;
+
+pub fn encode(score: u32) -> String {
+    format!("{} {} {}", TAG, VERSION, score.min(MAX_SCORE))
+}
+
+pub fn decode(data: &str) -> Result<u32> {
+    let err = || HiScoreError::Format(data.to_string());
+    let mut words = data.split_whitespace();
+    if words.next() != Some(TAG) {
+        return Err(err());
+    }
+    let version = words.next().and_then(|v| v.parse().ok()).ok_or_else(err)?;
+    if version != VERSION {
+        return Err(HiScoreError::Version(version));
+    }
+    match (words.next().and_then(|s| s.parse().ok()), words.next()) {
+        (Some(score), None) if score <= MAX_SCORE => Ok(score),
+        _ => Err(err()),
+    }
+}
+
+/// Where the high score is kept.
+pub trait Storage {
+    fn load(&mut self) -> Result<Option<String>>;
+    fn save(&mut self, data: &str) -> Result<()>;
+}
+
+/// In memory storage: clones share the data, so the front end can read what
+/// is saved (e.g. to copy it in `localStorage`).
+#[derive(Clone, Default)]
+pub struct MemoryStorage {
+    data: Rc<RefCell<Option<String>>>,
+    changed: Rc<Cell<bool>>,
+}
+
+impl MemoryStorage {
+    pub fn new(data: Option<String>) -> Self {
+        MemoryStorage { data: Rc::new(RefCell::new(data)), changed: Default::default() }
+    }
+
+    pub fn data(&self) -> Option<String> {
+        self.data.borrow().clone()
+    }
+
+    /// The data saved since the last call, if any.
+    pub fn take_changed(&self) -> Option<String> {
+        match self.changed.replace(false) {
+            true => self.data(),
+            false => None,
+        }
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn load(&mut self) -> Result<Option<String>> {
+        Ok(self.data())
+    }
+
+    fn save(&mut self, data: &str) -> Result<()> {
+        *self.data.borrow_mut() = Some(data.to_string());
+        self.changed.set(true);
+        Ok(())
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub use self::file::FileStorage;
+
+#[cfg(not(target_arch = "wasm32"))]
+mod file {
+    use std::fs;
+    use std::io::ErrorKind;
+    use std::path::PathBuf;
+
+    use super::{Storage, Result, HiScoreError};
+
+    /// Keep the high score in a file: a missing file is no high score.
+    pub struct FileStorage {
+        path: PathBuf,
+    }
+
+    impl FileStorage {
+        pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+            FileStorage { path: path.into() }
+        }
+    }
+
+    impl Storage for FileStorage {
+        fn load(&mut self) -> Result<Option<String>> {
+            match fs::read_to_string(&self.path) {
+                Ok(data) => Ok(Some(data)),
+                Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(HiScoreError::Storage(e.to_string())),
+            }
+        }
+
+        fn save(&mut self, data: &str) -> Result<()> {
+            fs::write(&self.path, format!("{}\n", data))
+                .map_err(|e| HiScoreError::Storage(e.to_string()))
+        }
+    }
+}
+
+fn read_score(ram: &Ram, address: Address) -> u32 {
+    let bytes = [ram.get(address).unwrap_or(0), ram.get(address + 1).unwrap_or(0)];
+    bcd(&bytes).unwrap_or(0)
+}
+
+/// Restore and save the high score of a running machine: call `frame()`
+/// after every frame.
+pub struct Keeper {
+    storage: Box<dyn Storage>,
+    best: u32,
+    restored: bool,
+    playing: bool,
+}
+
+impl Keeper {
+    /// Invalid saved data are reported (`warn!`) and ignored.
+    pub fn new<S: Storage + 'static>(mut storage: S) -> Self {
+        let best = match storage.load().and_then(|d| d.map(|d| decode(&d)).unwrap_or(Ok(0))) {
+            Ok(best) => best,
+            Err(e) => {
+                warn!("{}", e);
+                0
+            }
+        };
+        Keeper { storage: Box::new(storage), best, restored: false, playing: false }
+    }
+
+    pub fn best(&self) -> u32 {
+        self.best
+    }
+
+    /// Look at the ram after `frame`: return `true` if the saved high score
+    /// was written in ram.
+    pub fn frame(&mut self, frame: u64, ram: &Ram) -> bool {
+        let mut written = false;
+        if !self.restored && frame >= BOOT_FRAMES {
+            self.restored = true;
+            if self.best > read_score(ram, HI_SCORE) {
+                for (i, b) in to_bcd(self.best, 2).into_iter().enumerate() {
+                    ram.set(HI_SCORE + i as Address, b);
+                }
+                written = true;
+            }
+        }
+        let playing = ram.get(GAME_MODE).unwrap_or(0) != 0;
+        if self.playing && !playing {
+            self.game_over(ram);
+        }
+        self.playing = playing;
+        written
+    }
+
+    /// The machine state was loaded from a snapshot taken after `frame`: the
+    /// restore is already in it if the boot was done.
+    pub fn loaded(&mut self, frame: u64, ram: &Ram) {
+        self.restored = frame >= BOOT_FRAMES;
+        self.playing = ram.get(GAME_MODE).unwrap_or(0) != 0;
+    }
+
+    fn game_over(&mut self, ram: &Ram) {
+        let score = read_score(ram, HI_SCORE);
+        if score <= self.best {
+            return;
+        }
+        self.best = score;
+        if let Err(e) = self.storage.save(&encode(score)) {
+            warn!("{}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rstest::rstest_parametrize;
+
+    #[test]
+    fn encode_and_decode() {
+        assert_eq!("SIHS 1 1250", encode(1250));
+        assert_eq!(Ok(1250), decode("SIHS 1 1250\n"));
+    }
+
+    #[rstest_parametrize(
+    data,
+    case("1250"),
+    case("SIHS 1"),
+    case("SIHS 1 10000"),
+    case("SIHS 1 12 13"),
+    case("HISC 1 12"),
+    )]
+    fn invalid_data(data: &str) {
+        assert!(decode(data).is_err());
+    }
+
+    #[test]
+    fn unknown_version() {
+        assert_eq!(Err(HiScoreError::Version(2)), decode("SIHS 2 100"));
+    }
+
+    fn set_score(ram: &Ram, address: Address, score: u32) {
+        for (i, b) in to_bcd(score, 2).into_iter().enumerate() {
+            ram.set(address + i as Address, b);
+        }
+    }
+
+    #[test]
+    fn should_restore_after_boot() {
+        let ram = Ram::default();
+        let mut keeper = Keeper::new(MemoryStorage::new(Some(encode(770))));
+
+        keeper.frame(BOOT_FRAMES - 1, &ram);
+        assert_eq!(0, read_score(&ram, HI_SCORE));
+        keeper.frame(BOOT_FRAMES, &ram);
+
+        assert_eq!(770, read_score(&ram, HI_SCORE));
+    }
+
+    #[test]
+    fn should_save_at_game_over_when_beaten() {
+        let ram = Ram::default();
+        let storage = MemoryStorage::new(Some(encode(100)));
+        let mut keeper = Keeper::new(storage.clone());
+
+        ram.set(GAME_MODE, 1);
+        keeper.frame(BOOT_FRAMES, &ram);
+        set_score(&ram, HI_SCORE, 350);
+        keeper.frame(BOOT_FRAMES + 1, &ram);
+        assert_eq!(None, storage.take_changed());
+        ram.set(GAME_MODE, 0);
+        keeper.frame(BOOT_FRAMES + 2, &ram);
+
+        assert_eq!(Some("SIHS 1 350".to_string()), storage.take_changed());
+        assert_eq!(None, storage.take_changed());
+        assert_eq!(350, keeper.best());
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    #[test]
+    fn file_storage() {
+        let path = ::std::env::temp_dir().join(format!("wasm-invaders-hiscore-{}", ::std::process::id()));
+        let mut storage = FileStorage::new(&path);
+        let _ = ::std::fs::remove_file(&path);
+
+        assert_eq!(Ok(None), storage.load());
+        storage.save(&encode(42)).unwrap();
+
+        assert_eq!(Ok(42), decode(&storage.load().unwrap().unwrap()));
+        ::std::fs::remove_file(&path).unwrap();
+    }
+}
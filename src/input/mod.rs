@@ -0,0 +1,392 @@
+//! Map physical inputs (keyboard, gamepads and touch screen) to `Ev`s.
+//!
+//! A `Profile` binds every action to any number of sources. Its text format
+//! has a line for every action: the action name (as `Ev::name()`) followed by
+//! its sources; `deadzone` sets the analog dead zone and `#` starts a
+//! comment.
+//!
+//! ```text
+//! deadzone 0.3
+//! p1left key:ArrowLeft button:0:14 axis:0:0-
+//! p1shoot key:Space button:0:0 touch:0.33,0.5,0.34,0.5
+//! ```
+//!
+//! Sources are `key:CODE` (the `KeyboardEvent.code` of the browser),
+//! `button:PAD:BUTTON`, `axis:PAD:AXIS+` or `axis:PAD:AXIS-` (pushed over the
+//! dead zone in that direction) and `touch:X,Y,W,H` (a screen zone, in
+//! fractions of the screen size).
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use Ev;
+
+pub const DEFAULT_DEAD_ZONE: f32 = 0.25;
+
+const DEFAULT_PROFILE: &str = "
+coin key:KeyC button:0:8
+tilt key:KeyT
+p1start key:Digit1 button:0:9
+p1shoot key:Space button:0:0 touch:0.33,0.5,0.34,0.5
+p1left key:ArrowLeft button:0:14 axis:0:0- touch:0,0.5,0.33,0.5
+p1right key:ArrowRight button:0:15 axis:0:0+ touch:0.67,0.5,0.33,0.5
+p2start key:Digit2
+p2shoot key:KeyW button:1:0
+p2left key:KeyA button:1:14 axis:1:0-
+p2right key:KeyD button:1:15 axis:1:0+
+";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum InputError {
+    Action(usize, String),
+    Source(usize, String),
+    DeadZone(usize),
+}
+
+impl fmt::Display for InputError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            InputError::Action(line, ref name) => write!(f, "Line {}: unknown action '{}'", line, name),
+            InputError::Source(line, ref source) => write!(f, "Line {}: invalid source '{}'", line, source),
+            InputError::DeadZone(line) => write!(f, "Line {}: dead zone should be in [0, 1)", line),
+        }
+    }
+}
+
+/// A digital input: a key, a button or an axis direction.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Control {
+    Key(String),
+    Button(u32, u32),
+    Axis(u32, u32, bool),
+}
+
+/// A screen zone, in fractions of the screen size.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Zone {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+impl Zone {
+    pub fn contains(&self, x: f32, y: f32) -> bool {
+        x >= self.x && x < self.x + self.width && y >= self.y && y < self.y + self.height
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Source {
+    Control(Control),
+    Touch(Zone),
+}
+
+impl Source {
+    pub fn parse(source: &str) -> Option<Self> {
+        let mut parts = source.splitn(2, ':');
+        let kind = parts.next()?;
+        let args = parts.next()?;
+        Some(match kind {
+            "key" if !args.is_empty() => Source::Control(Control::Key(args.to_string())),
+            "button" => {
+                let n = args.split(':').map(|n| n.parse().ok()).collect::<Option<Vec<u32>>>()?;
+                match n.as_slice() {
+                    &[pad, button] => Source::Control(Control::Button(pad, button)),
+                    _ => return None,
+                }
+            }
+            "axis" => {
+                let (args, positive) = match args.chars().last()? {
+                    '+' => (&args[..args.len() - 1], true),
+                    '-' => (&args[..args.len() - 1], false),
+                    _ => return None,
+                };
+                let mut n = args.split(':').map(|n| n.parse().ok());
+                match (n.next()?, n.next()?, n.next()) {
+                    (Some(pad), Some(axis), None) => Source::Control(Control::Axis(pad, axis, positive)),
+                    _ => return None,
+                }
+            }
+            "touch" => {
+                let z = args.split(',').map(|n| n.parse().ok()).collect::<Option<Vec<f32>>>()?;
+                match z.as_slice() {
+                    &[x, y, width, height] if width > 0.0 && height > 0.0 =>
+                        Source::Touch(Zone { x, y, width, height }),
+                    _ => return None,
+                }
+            }
+            _ => return None,
+        })
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Source::Control(Control::Key(ref code)) => write!(f, "key:{}", code),
+            Source::Control(Control::Button(pad, button)) => write!(f, "button:{}:{}", pad, button),
+            Source::Control(Control::Axis(pad, axis, positive)) =>
+                write!(f, "axis:{}:{}{}", pad, axis, if positive { '+' } else { '-' }),
+            Source::Touch(z) => write!(f, "touch:{},{},{},{}", z.x, z.y, z.width, z.height),
+        }
+    }
+}
+
+/// The bindings of every action.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Profile {
+    bindings: Vec<(Ev, Source)>,
+    dead_zone: f32,
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Profile::parse(DEFAULT_PROFILE).unwrap()
+    }
+}
+
+impl Profile {
+    /// No bindings at all.
+    pub fn empty() -> Self {
+        Profile { bindings: Vec::new(), dead_zone: DEFAULT_DEAD_ZONE }
+    }
+
+    pub fn parse(text: &str) -> Result<Self, InputError> {
+        let mut profile = Profile::empty();
+        for (n, line) in text.lines().enumerate().map(|(n, l)| (n + 1, l)) {
+            let line = line.split('#').next().unwrap_or("").trim();
+            let mut words = line.split_whitespace();
+            let first = match words.next() {
+                Some(w) => w,
+                None => continue,
+            };
+            if first == "deadzone" {
+                profile.dead_zone = words.next().and_then(|w| w.parse().ok())
+                    .filter(|d| *d >= 0.0 && *d < 1.0)
+                    .ok_or(InputError::DeadZone(n))?;
+                continue;
+            }
+            let ev = Ev::from_name(first).ok_or_else(|| InputError::Action(n, first.to_string()))?;
+            for w in words {
+                let source = Source::parse(w).ok_or_else(|| InputError::Source(n, w.to_string()))?;
+                profile.bind(ev, source);
+            }
+        }
+        Ok(profile)
+    }
+
+    /// Add a binding: an action can have any number of them.
+    pub fn bind(&mut self, ev: Ev, source: Source) {
+        if !self.bindings.contains(&(ev, source.clone())) {
+            self.bindings.push((ev, source));
+        }
+    }
+
+    /// Remove every binding of `source`.
+    pub fn unbind(&mut self, source: &Source) {
+        self.bindings.retain(|&(_, ref s)| s != source);
+    }
+
+    pub fn sources(&self, ev: Ev) -> impl Iterator<Item=&Source> {
+        self.bindings.iter().filter(move |&&(e, _)| e == ev).map(|&(_, ref s)| s)
+    }
+
+    pub fn dead_zone(&self) -> f32 {
+        self.dead_zone
+    }
+
+    pub fn set_dead_zone(&mut self, dead_zone: f32) {
+        self.dead_zone = dead_zone.max(0.0).min(0.99);
+    }
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "deadzone {}", self.dead_zone)?;
+        for &ev in Ev::ALL.iter() {
+            let sources = self.sources(ev).map(|s| s.to_string()).collect::<Vec<_>>();
+            if !sources.is_empty() {
+                writeln!(f, "{} {}", ev.name(), sources.join(" "))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Track the physical inputs and turn them in `Ev` presses and releases.
+#[derive(Default)]
+pub struct Mapper {
+    profile: Profile,
+    active: HashSet<Control>,
+    touches: HashMap<u32, (f32, f32)>,
+    pressed: u32,
+}
+
+impl Mapper {
+    pub fn new(profile: Profile) -> Self {
+        Mapper { profile, ..Default::default() }
+    }
+
+    pub fn profile(&self) -> &Profile {
+        &self.profile
+    }
+
+    /// Change the bindings: return the changes of the mapped events.
+    pub fn set_profile(&mut self, profile: Profile) -> Vec<(Ev, bool)> {
+        self.profile = profile;
+        self.changes()
+    }
+
+    /// Is `code` bound to some action?
+    pub fn is_bound(&self, code: &str) -> bool {
+        let key = Source::Control(Control::Key(code.to_string()));
+        self.profile.bindings.iter().any(|&(_, ref s)| s == &key)
+    }
+
+    pub fn key(&mut self, code: &str, pressed: bool) -> Vec<(Ev, bool)> {
+        self.control(Control::Key(code.to_string()), pressed)
+    }
+
+    pub fn button(&mut self, pad: u32, button: u32, pressed: bool) -> Vec<(Ev, bool)> {
+        self.control(Control::Button(pad, button), pressed)
+    }
+
+    /// An analog axis moved to `value` (-1.0 - 1.0): it's a press of the
+    /// direction where it's over the dead zone.
+    pub fn axis(&mut self, pad: u32, axis: u32, value: f32) -> Vec<(Ev, bool)> {
+        let dead_zone = self.profile.dead_zone;
+        self.set_active(Control::Axis(pad, axis, true), value > dead_zone);
+        self.set_active(Control::Axis(pad, axis, false), value < -dead_zone);
+        self.changes()
+    }
+
+    /// Touch `id` started or moved to `(x, y)` (fractions of the screen size).
+    pub fn touch(&mut self, id: u32, x: f32, y: f32) -> Vec<(Ev, bool)> {
+        self.touches.insert(id, (x, y));
+        self.changes()
+    }
+
+    pub fn touch_end(&mut self, id: u32) -> Vec<(Ev, bool)> {
+        self.touches.remove(&id);
+        self.changes()
+    }
+
+    /// Release everything (e.g. when the window loses the focus).
+    pub fn release_all(&mut self) -> Vec<(Ev, bool)> {
+        self.active.clear();
+        self.touches.clear();
+        self.changes()
+    }
+
+    /// Mask of the events pressed by the mapped inputs.
+    pub fn pressed(&self) -> u32 {
+        self.pressed
+    }
+
+    fn control(&mut self, control: Control, pressed: bool) -> Vec<(Ev, bool)> {
+        self.set_active(control, pressed);
+        self.changes()
+    }
+
+    fn set_active(&mut self, control: Control, active: bool) {
+        if active {
+            self.active.insert(control);
+        } else {
+            self.active.remove(&control);
+        }
+    }
+
+    fn is_active(&self, source: &Source) -> bool {
+        match *source {
+            Source::Control(ref c) => self.active.contains(c),
+            Source::Touch(ref zone) => self.touches.values().any(|&(x, y)| zone.contains(x, y)),
+        }
+    }
+
+    fn changes(&mut self) -> Vec<(Ev, bool)> {
+        let pressed = self.profile.bindings.iter()
+            .filter(|&&(_, ref s)| self.is_active(s))
+            .fold(0, |mask, &(ev, _)| mask | ev.mask());
+        let changed = pressed ^ self.pressed;
+        self.pressed = pressed;
+        Ev::ALL.iter()
+            .filter(|ev| changed & ev.mask() != 0)
+            .map(|&ev| (ev, pressed & ev.mask() != 0))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rstest::{rstest, rstest_parametrize};
+
+    fn mapper() -> Mapper {
+        Mapper::default()
+    }
+
+    #[test]
+    fn default_profile_should_round_trip() {
+        let profile = Profile::default();
+
+        assert_eq!(profile, Profile::parse(&profile.to_string()).unwrap());
+    }
+
+    #[rstest_parametrize(
+    text, expected,
+    case("fire key:Space", Unwrap("InputError::Action(1, \"fire\".to_string())")),
+    case("\np1shoot key:", Unwrap("InputError::Source(2, \"key:\".to_string())")),
+    case("p1shoot axis:0:1", Unwrap("InputError::Source(1, \"axis:0:1\".to_string())")),
+    case("p1shoot touch:0,0,0,1", Unwrap("InputError::Source(1, \"touch:0,0,0,1\".to_string())")),
+    case("deadzone 1.5", Unwrap("InputError::DeadZone(1)")),
+    )]
+    fn invalid_profile(text: &str, expected: InputError) {
+        assert_eq!(Err(expected), Profile::parse(text));
+    }
+
+    #[rstest]
+    fn keys_should_press_and_release(mut mapper: Mapper) {
+        assert_eq!(vec![(Ev::P1Left, true)], mapper.key("ArrowLeft", true));
+        assert_eq!(Vec::<(Ev, bool)>::new(), mapper.key("KeyZ", true));
+        assert_eq!(vec![(Ev::P1Left, false)], mapper.key("ArrowLeft", false));
+    }
+
+    #[rstest]
+    fn action_should_stay_pressed_till_all_bindings_are_released(mut mapper: Mapper) {
+        mapper.key("Space", true);
+        assert_eq!(Vec::<(Ev, bool)>::new(), mapper.button(0, 0, true));
+        assert_eq!(Vec::<(Ev, bool)>::new(), mapper.key("Space", false));
+
+        assert_eq!(vec![(Ev::P1Shoot, false)], mapper.button(0, 0, false));
+    }
+
+    #[rstest]
+    fn axis_should_honor_dead_zone(mut mapper: Mapper) {
+        assert_eq!(Vec::<(Ev, bool)>::new(), mapper.axis(0, 0, -0.2));
+        assert_eq!(vec![(Ev::P1Left, true)], mapper.axis(0, 0, -0.6));
+        assert_eq!(vec![(Ev::P1Left, false), (Ev::P1Right, true)], mapper.axis(0, 0, 0.9));
+        assert_eq!(vec![(Ev::P1Right, false)], mapper.axis(0, 0, 0.0));
+    }
+
+    #[rstest]
+    fn touches_should_press_their_zones(mut mapper: Mapper) {
+        assert_eq!(Vec::<(Ev, bool)>::new(), mapper.touch(1, 0.1, 0.2));
+        assert_eq!(vec![(Ev::P1Left, true)], mapper.touch(1, 0.1, 0.7));
+        mapper.touch(2, 0.5, 0.7);
+        assert_eq!(Ev::P1Left.mask() | Ev::P1Shoot.mask(), mapper.pressed());
+
+        assert_eq!(vec![(Ev::P1Left, false)], mapper.touch_end(1));
+    }
+
+    #[test]
+    fn profile_changes_should_release_unbound_actions() {
+        let mut mapper = Mapper::new(Profile::parse("p1shoot key:KeyX key:Space").unwrap());
+        mapper.key("KeyX", true);
+
+        let mut profile = mapper.profile().clone();
+        profile.unbind(&Source::parse("key:KeyX").unwrap());
+
+        assert_eq!(vec![(Ev::P1Shoot, false)], mapper.set_profile(profile));
+    }
+}
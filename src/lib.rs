@@ -13,6 +13,23 @@ extern crate log;
 
 mod utils;
 mod si;
+pub mod trace;
+pub mod profile;
+pub mod cheat;
+pub mod patch;
+pub mod dip;
+pub mod render;
+pub mod snapshot;
+pub mod netplay;
+pub mod spectate;
+pub mod pacing;
+pub mod movie;
+pub mod ram_map;
+pub mod selftest;
+pub mod coin;
+pub mod hiscore;
+pub mod input;
+pub mod assist;
 
 use std::rc::Rc;
 use std::io::Write;
@@ -20,40 +37,83 @@ use cfg_if::cfg_if;
 use wasm_bindgen::prelude::*;
 
 use rs8080::{
-    cpu::{Cpu as Cpu8080, IrqCmd, CpuError},
-    hook::NoneHook,
+    cpu::{Cpu as Cpu8080, IrqCmd},
 };
 
-use si::{memory::{VRAM_SIZE, ROM_SIZE, SIMmu}, io::{IO, Ev}};
+use si::{memory::{ROM_SIZE, SIMmu, Ram, VRam}, io::{IO, DIP_SWITCHES}};
+pub use si::io::Ev;
+use trace::{Tracer, Crash, Registers};
+use profile::{Profiler, ProfileHook, Half};
+use cheat::{Cheats, Search, Cmp};
+use dip::DipSettings;
+use render::{Renderer, scale::{Filter, Upscaler}, crt::{Crt, CrtSettings}, phosphor::Phosphor};
+use snapshot::Snapshot;
+use pacing::{Pacer, FRAME_MS};
+use coin::CoinMech;
+use hiscore::{Keeper, MemoryStorage, Storage};
+use input::{Mapper, Profile};
+use assist::{Assist, Macro};
+use movie::Movie;
 
 const W: u32 = 256;
 const H: u32 = 224;
 
-type Cpu = Cpu8080<SIMmu, Rc<IO>, Rc<IO>, NoneHook>;
+type Cpu = Cpu8080<SIMmu, Rc<IO>, Rc<IO>, Rc<ProfileHook>>;
 
 #[wasm_bindgen]
 pub struct SpaceInvaders {
     cpu: Cpu,
     io: Rc<IO>,
+    ram: Ram,
+    vram: VRam,
+    tracer: Rc<Tracer>,
+    profile_hook: Rc<ProfileHook>,
+    cheats: Cheats,
+    search: Option<Search>,
     clocks: u64,
     frames: u64,
+    pacer: Pacer,
+    renderer: Renderer,
+    upscaler: Option<Upscaler>,
+    crt: Option<Crt>,
+    phosphor: Option<Phosphor>,
+    /// Frame of the last `render()`.
+    rendered: u64,
+    coin_mech: CoinMech,
+    hiscore: Option<Keeper>,
+    /// The storage shared with the front end by `enable_hiscore()`.
+    hiscore_memory: Option<MemoryStorage>,
+    /// The last frame restored the saved high score.
+    hiscore_restored: bool,
+    mapper: Mapper,
+    assist: Assist,
+    /// The movie in recording, if any.
+    movie: Option<Movie>,
 }
 
 #[wasm_bindgen]
 pub struct Game {
     width: u32,
     height: u32,
-    vram: [u8; VRAM_SIZE],
+    rom: [u8; ROM_SIZE],
+    patches: Vec<String>,
+    dips: DipSettings,
+    cocktail: bool,
 }
 
 #[wasm_bindgen]
 impl Game {
     pub fn new() -> Self {
-        Self { width: W, height: H, vram: [0; VRAM_SIZE] }
-    }
-
-    pub fn vram(&self) -> *const u8 {
-        self.vram.as_ptr()
+        let mut rom = [0; ROM_SIZE];
+        load_rom(&mut rom);
+        Self {
+            width: W,
+            height: H,
+            rom,
+            patches: Vec::new(),
+            dips: DipSettings::new(DIP_SWITCHES),
+            cocktail: false,
+        }
     }
 
     pub fn width(&self) -> u32 {
@@ -64,24 +124,141 @@ impl Game {
         self.height
     }
 
+    /// Rendered screen width: the monitor is rotated, so it's `height()`.
+    pub fn screen_width(&self) -> u32 {
+        render::WIDTH as u32
+    }
+
+    pub fn screen_height(&self) -> u32 {
+        render::HEIGHT as u32
+    }
+
+    /// Check the rom chips: return a line for every chip (see `selftest`).
+    pub fn self_test(&self) -> String {
+        self.self_test_report().to_string()
+    }
+
+    /// Select the cocktail table cabinet for next `space_invaders()` machines.
+    pub fn set_cocktail(&mut self, cocktail: bool) {
+        self.cocktail = cocktail;
+    }
+
+    pub fn cocktail(&self) -> bool {
+        self.cocktail
+    }
+
     pub fn space_invaders(&mut self) -> SpaceInvaders {
-        let mut rom = [0; ROM_SIZE];
-        load_rom(&mut rom);
-        let mmu = SIMmu::new(rom.into(), self.vram.as_mut_ptr().into());
+        let tracer = Rc::new(Tracer::default());
+        let mmu = SIMmu::new(self.rom.into())
+            .with_tracer(tracer.clone());
+        let ram = mmu.ram();
+        let vram = mmu.vram();
         let si_io = IO::default()
-            .change_lives(3)
-            .coin_info_set(true)
-            .lower_bonus_life(true);
+            .dips(&self.dips)
+            .cocktail(self.cocktail);
 
         let io = Rc::new(si_io);
 
-        let cpu = Cpu::new(mmu, io.clone(), io.clone(), Default::default());
+        let profile_hook = Rc::new(ProfileHook::default());
+
+        let cpu = Cpu::new(mmu, io.clone(), io.clone(), profile_hook.clone());
+
+        SpaceInvaders {
+            cpu, io, ram, vram, tracer,
+            profile_hook,
+            cheats: Default::default(),
+            search: None,
+            clocks: 0,
+            frames: 1,
+            pacer: Default::default(),
+            renderer: Default::default(),
+            upscaler: None,
+            crt: None,
+            phosphor: None,
+            rendered: 1,
+            coin_mech: Default::default(),
+            hiscore: None,
+            hiscore_memory: None,
+            hiscore_restored: false,
+            mapper: Default::default(),
+            assist: Default::default(),
+            movie: None,
+        }
+    }
+
+    /// Apply an IPS or BPS patch to the rom used by the next `space_invaders()`
+    /// machines. Patches are applied in sequence: BPS ones are checked against
+    /// the rom they are applied to, IPS ones just when applied to the base rom
+    /// set (they carry no checksum).
+    pub fn apply_patch(&mut self, name: &str, data: &[u8]) -> Result<(), JsValue> {
+        let result = match self.patches.is_empty() {
+            true => patch::apply(&mut self.rom, data),
+            false => patch::apply_chained(&mut self.rom, data),
+        };
+        result.map_err(|e| JsValue::from_str(&format!("{}: {}", name, e)))?;
+        info!("Applied patch {}", name);
+        self.patches.push(name.to_string());
+        Ok(())
+    }
 
-        SpaceInvaders { cpu, io, clocks: 0, frames: 1 }
+    /// Names of the applied patches, comma separated.
+    pub fn patches(&self) -> String {
+        self.patches.join(", ")
+    }
+
+    pub fn dip_count(&self) -> usize {
+        self.dips.switches().len()
+    }
+
+    pub fn dip_name(&self, switch: usize) -> Result<String, JsValue> {
+        self.dips.switch(switch)
+            .map(|s| s.name.to_string())
+            .map_err(|e| JsValue::from_str(&e.to_string()))
+    }
+
+    pub fn dip_value_count(&self, switch: usize) -> Result<usize, JsValue> {
+        self.dips.switch(switch)
+            .map(|s| s.values.len())
+            .map_err(|e| JsValue::from_str(&e.to_string()))
+    }
+
+    pub fn dip_label(&self, switch: usize, value: usize) -> Result<String, JsValue> {
+        self.dips.switch(switch)
+            .map_err(|e| JsValue::from_str(&e.to_string()))?
+            .values.get(value)
+            .map(|v| v.label.to_string())
+            .ok_or_else(|| JsValue::from_str(&format!("Unknown value {} for dip switch {}", value, switch)))
+    }
+
+    pub fn dip_default(&self, switch: usize) -> Result<usize, JsValue> {
+        self.dips.switch(switch)
+            .map(|s| s.default)
+            .map_err(|e| JsValue::from_str(&e.to_string()))
+    }
+
+    pub fn dip_get(&self, switch: usize) -> Result<usize, JsValue> {
+        self.dips.get(switch)
+            .map_err(|e| JsValue::from_str(&e.to_string()))
+    }
+
+    /// Change a dip switch: it will be used by the next `space_invaders()`
+    /// machines.
+    pub fn dip_set(&mut self, switch: usize, value: usize) -> Result<(), JsValue> {
+        self.dips.set(switch, value)
+            .map_err(|e| JsValue::from_str(&e.to_string()))
     }
 
     pub fn name(&self) -> String {
-        format!("Space Invaders")
+        match self.patches.is_empty() {
+            true => format!("Space Invaders"),
+            false => format!("Space Invaders [{}]", self.patches()),
+        }
+    }
+}
+
+impl Game {
+    pub fn self_test_report(&self) -> selftest::Report {
+        selftest::run(&self.rom)
     }
 }
 
@@ -92,16 +269,218 @@ const CLOCKS_PER_FRAME: u64 = CLOCKS_PER_HALF_FRAME * 2;
 #[wasm_bindgen]
 impl SpaceInvaders {
     pub fn next_frame(&mut self) {
-        let done_frame = self.frames * CLOCKS_PER_FRAME;
-        let next_half = done_frame + CLOCKS_PER_HALF_FRAME;
+        // The cpu sees the inputs through the assists (that are recorded in
+        // the movie) and the coin line filtered by the coin mech
+        let inputs = self.io.inputs();
+        let assisted = self.assist.step(inputs);
+        self.run_frame(inputs, assisted);
+    }
 
-        self.run_till(done_frame).unwrap();
-        self.cpu.irq(IrqCmd::Irq1).unwrap();
+    /// Credits in the machine (0 - 99).
+    pub fn credits(&self) -> u32 {
+        self.ram.get(ram_map::CREDITS)
+            .and_then(|c| ram_map::bcd(&[c]))
+            .unwrap_or(0)
+    }
 
-        self.run_till(next_half).unwrap();
-        self.cpu.irq(IrqCmd::Irq2).unwrap();
+    /// Frames the coin switch must stay closed to accept a coin.
+    pub fn set_coin_min_pulse(&mut self, frames: u32) {
+        self.coin_mech.set_min_pulse(frames);
+    }
 
-        self.frames += 1;
+    /// Reject all coins.
+    pub fn set_coin_lockout(&mut self, lockout: bool) {
+        self.coin_mech.set_lockout(lockout);
+    }
+
+    /// Free play: keep at least `credits` credits, 0 disables it.
+    pub fn set_free_play(&mut self, credits: u32) {
+        self.coin_mech.set_free_play(match credits {
+            0 => None,
+            c => Some(c),
+        });
+    }
+
+    /// The mechanical coin counter.
+    pub fn coin_counter(&self) -> f64 {
+        self.coin_mech.counter() as f64
+    }
+
+    pub fn set_coin_counter(&mut self, counter: f64) {
+        self.coin_mech.set_counter(counter.max(0.0) as u64);
+    }
+
+    pub fn coins_inserted(&self) -> u32 {
+        self.coin_mech.bookkeeping().coins as u32
+    }
+
+    pub fn coins_rejected(&self) -> u32 {
+        self.coin_mech.bookkeeping().rejected as u32
+    }
+
+    pub fn credits_used(&self) -> u32 {
+        self.coin_mech.bookkeeping().credits_used as u32
+    }
+
+    pub fn games_played(&self) -> u32 {
+        self.coin_mech.bookkeeping().games as u32
+    }
+
+    /// The high score in ram.
+    pub fn hi_score(&self) -> u32 {
+        let score = [self.ram.get(ram_map::HI_SCORE).unwrap_or(0),
+            self.ram.get(ram_map::HI_SCORE + 1).unwrap_or(0)];
+        ram_map::bcd(&score).unwrap_or(0)
+    }
+
+    /// Keep the high score in memory, starting from `saved` (see `hiscore`
+    /// for the format): the front end takes the new ones by
+    /// `take_hiscore()`.
+    pub fn enable_hiscore(&mut self, saved: Option<String>) {
+        let storage = MemoryStorage::new(saved);
+        self.hiscore_memory = Some(storage.clone());
+        self.hiscore = Some(Keeper::new(storage));
+    }
+
+    /// The high score saved since the last call, if any.
+    pub fn take_hiscore(&mut self) -> Option<String> {
+        self.hiscore_memory.as_ref().and_then(|s| s.take_changed())
+    }
+
+    /// Run the frames that fit in `elapsed_ms` milliseconds of real time
+    /// (scaled by speed) and return how many frames were run. What is left
+    /// is accounted in the next call.
+    pub fn run_for(&mut self, elapsed_ms: f64) -> u32 {
+        let frames = self.pacer.frames(elapsed_ms);
+        for _ in 0..frames {
+            self.next_frame();
+        }
+        frames
+    }
+
+    /// Speed multiplier used by `run_for()`: clamped between 0.25 and 8.
+    pub fn set_speed(&mut self, speed: f64) {
+        self.pacer.set_speed(speed);
+    }
+
+    pub fn speed(&self) -> f64 {
+        self.pacer.speed()
+    }
+
+    pub fn pause(&mut self, paused: bool) {
+        self.pacer.set_paused(paused);
+    }
+
+    pub fn paused(&self) -> bool {
+        self.pacer.paused()
+    }
+
+    /// While turbo is on `run_for()` runs at the max speed.
+    pub fn turbo(&mut self, turbo: bool) {
+        self.pacer.set_turbo(turbo);
+    }
+
+    /// The raw video ram: it's owned by the machine and stays at the same
+    /// address till the machine is freed.
+    pub fn vram(&self) -> *const u8 {
+        self.vram.as_ptr()
+    }
+
+    /// Render the video ram areas changed since the last call in the RGBA
+    /// framebuffer returned by `screen()`. Return the updated rectangles as
+    /// `[x, y, width, height, ...]`.
+    pub fn render(&mut self) -> Vec<u32> {
+        let flipped = self.io.flipped();
+        let rects = match self.phosphor {
+            Some(ref phosphor) => {
+                self.renderer.render_levels(phosphor.levels(), flipped);
+                vec![render::Rect::screen()]
+            }
+            None => self.renderer.render_dirty(&*self.vram.data(), flipped, &*self.vram.dirty()),
+        };
+        self.vram.clear_dirty();
+        let elapsed = self.frames.saturating_sub(self.rendered) as f32 * FRAME_MS as f32;
+        self.rendered = self.frames;
+        if let Some(ref mut upscaler) = self.upscaler {
+            if !rects.is_empty() {
+                upscaler.scale(self.renderer.rgba(), render::WIDTH, render::HEIGHT);
+            }
+        }
+        // The phosphors fade also when nothing changes
+        if let Some(ref mut crt) = self.crt {
+            let (rgba, factor) = match self.upscaler {
+                Some(ref upscaler) => (upscaler.rgba(), upscaler.factor()),
+                None => (self.renderer.rgba(), 1),
+            };
+            crt.process(rgba, render::WIDTH * factor, render::HEIGHT * factor, factor, elapsed);
+        }
+        rects.into_iter()
+            .flat_map(|r| vec![r.x as u32, r.y as u32, r.width as u32, r.height as u32])
+            .collect()
+    }
+
+    pub fn screen(&self) -> *const u8 {
+        self.renderer.rgba().as_ptr()
+    }
+
+    /// Upscale every rendered frame with `filter` (`nearest`, `scale2x`,
+    /// `scale3x` or `xbr`) by `factor`; `none` disables it.
+    pub fn set_upscaler(&mut self, filter: &str, factor: usize) -> Result<(), JsValue> {
+        if filter == "none" {
+            self.upscaler = None;
+            return Ok(());
+        }
+        let upscaler = Filter::parse(filter)
+            .and_then(|f| Upscaler::new(f, factor))
+            .map_err(|e| JsValue::from_str(&e.to_string()))?;
+        self.upscaler = Some(upscaler);
+        self.renderer = Default::default();
+        Ok(())
+    }
+
+    /// Blend the frames with phosphor persistence: levels halve every
+    /// `half_life` milliseconds of emulated time, 0 disables it.
+    pub fn set_phosphor(&mut self, half_life: f32) {
+        if half_life.is_nan() || half_life <= 0.0 {
+            self.phosphor = None;
+            return;
+        }
+        match self.phosphor {
+            Some(ref mut phosphor) => phosphor.set_half_life(half_life),
+            None => self.phosphor = Some(Phosphor::screen(half_life)),
+        }
+    }
+
+    /// Apply the CRT filter after the upscaler: values are clamped in their
+    /// ranges (see `CrtSettings`).
+    pub fn set_crt(&mut self, scanlines: f32, bloom: f32, persistence: f32, curvature: f32, moon: bool) {
+        let settings = CrtSettings { scanlines, bloom, persistence, curvature, moon };
+        match self.crt {
+            Some(ref mut crt) => crt.set_settings(settings),
+            None => self.crt = Some(Crt::new(settings)),
+        }
+    }
+
+    pub fn crt_off(&mut self) {
+        self.crt = None;
+    }
+
+    /// Post processed framebuffer: CRT, upscaler or `screen()`, the first one
+    /// that is enabled.
+    pub fn scaled(&self) -> *const u8 {
+        match (&self.crt, &self.upscaler) {
+            (&Some(ref crt), _) => crt.rgba().as_ptr(),
+            (_, &Some(ref upscaler)) => upscaler.rgba().as_ptr(),
+            _ => self.screen(),
+        }
+    }
+
+    pub fn post_processed(&self) -> bool {
+        self.crt.is_some() || self.upscaler.is_some()
+    }
+
+    pub fn scale_factor(&self) -> usize {
+        self.upscaler.as_ref().map(|u| u.factor()).unwrap_or(1)
     }
 
     pub fn coin(&self, pressed: bool) {
@@ -123,13 +502,394 @@ impl SpaceInvaders {
     pub fn shoot(&self, pressed: bool) {
         self.io.ui_event(Ev::P1Shoot, pressed);
     }
+
+    /// Press or release any input: both players controls, coin and tilt.
+    pub fn set_input(&self, ev: Ev, pressed: bool) {
+        self.io.ui_event(ev, pressed);
+    }
+
+    /// A key (`KeyboardEvent.code`) changed: return if it's bound to some
+    /// input (see `input`).
+    pub fn key(&mut self, code: &str, pressed: bool) -> bool {
+        let changes = self.mapper.key(code, pressed);
+        self.map_inputs(changes);
+        self.mapper.is_bound(code)
+    }
+
+    pub fn gamepad_button(&mut self, pad: u32, button: u32, pressed: bool) {
+        let changes = self.mapper.button(pad, button, pressed);
+        self.map_inputs(changes);
+    }
+
+    /// Gamepad `axis` moved to `value` (-1.0 - 1.0).
+    pub fn gamepad_axis(&mut self, pad: u32, axis: u32, value: f32) {
+        let changes = self.mapper.axis(pad, axis, value);
+        self.map_inputs(changes);
+    }
+
+    /// Touch `id` started or moved to `(x, y)`, in fractions of the screen
+    /// size.
+    pub fn touch(&mut self, id: u32, x: f32, y: f32) {
+        let changes = self.mapper.touch(id, x, y);
+        self.map_inputs(changes);
+    }
+
+    pub fn touch_end(&mut self, id: u32) {
+        let changes = self.mapper.touch_end(id);
+        self.map_inputs(changes);
+    }
+
+    /// Release all keys, buttons and touches (e.g. on focus lost).
+    pub fn release_all(&mut self) {
+        let changes = self.mapper.release_all();
+        self.map_inputs(changes);
+    }
+
+    /// The input bindings in the `input` text format.
+    pub fn bindings(&self) -> String {
+        self.mapper.profile().to_string()
+    }
+
+    pub fn set_bindings(&mut self, bindings: &str) -> Result<(), JsValue> {
+        let profile = Profile::parse(bindings)
+            .map_err(|e| JsValue::from_str(&e.to_string()))?;
+        let changes = self.mapper.set_profile(profile);
+        self.map_inputs(changes);
+        Ok(())
+    }
+
+    /// Autofire `ev` while held: pressed for `period` frames and released for
+    /// as many. 0 disables it.
+    pub fn set_autofire(&mut self, ev: Ev, period: u32) {
+        self.assist.set_autofire(ev, period);
+    }
+
+    /// Record the inputs in macro `name` till `stop_macro()`.
+    pub fn record_macro(&mut self, name: &str) {
+        self.assist.record(name);
+    }
+
+    /// Stop recording and return the name of the recorded macro.
+    pub fn stop_macro(&mut self) -> Option<String> {
+        self.assist.stop_recording()
+    }
+
+    /// Replay macro `name` over the inputs: `false` if there is no such macro.
+    pub fn play_macro(&mut self, name: &str) -> bool {
+        self.assist.play(name)
+    }
+
+    /// Macro `name` in the `assist` text format.
+    pub fn macro_text(&self, name: &str) -> Option<String> {
+        self.assist.macros().get(name).map(|m| m.to_string())
+    }
+
+    pub fn set_macro(&mut self, name: &str, text: &str) -> Result<(), JsValue> {
+        let m = Macro::parse(text).map_err(|e| JsValue::from_str(&e.to_string()))?;
+        self.assist.set_macro(name, m);
+        Ok(())
+    }
+
+    /// Record the inputs the machine sees (autofire and macros included) in
+    /// a movie till `stop_movie()`. Start from power up to replay the same
+    /// run.
+    pub fn record_movie(&mut self) {
+        self.movie = Some(Default::default());
+    }
+
+    /// Stop recording and return the movie text.
+    pub fn stop_movie(&mut self) -> Option<String> {
+        self.movie.take().map(|m| m.to_string())
+    }
+
+    /// Set the state of all inputs at once: bit `ev as u32` of `mask` is set if
+    /// `ev` is pressed. Useful to apply a recorded or remote frame input.
+    pub fn set_inputs(&self, mask: u32) {
+        self.io.set_inputs(mask);
+    }
+
+    /// Should the screen be rendered upside down (cocktail cabinet during
+    /// player 2's turn)?
+    pub fn flipped(&self) -> bool {
+        self.io.flipped()
+    }
+
+    /// The pressed inputs as a mask (see `set_inputs()`).
+    pub fn inputs(&self) -> u32 {
+        self.io.inputs()
+    }
+
+    /// Enable or disable the execution tracer: when enabled the last
+    /// `trace::CRASH_HISTORY` instructions are kept and attached to the panic
+    /// message if the cpu fails.
+    pub fn trace(&self, enabled: bool) {
+        self.tracer.enable(enabled);
+    }
+
+    pub fn trace_capacity(&self, capacity: usize) {
+        self.tracer.set_capacity(capacity);
+    }
+
+    pub fn trace_dump(&self) -> String {
+        self.tracer.dump()
+    }
+
+    /// Start (or stop and drop) a profiling session.
+    pub fn profile(&mut self, enabled: bool) {
+        self.profile_hook.set_profiler(if enabled { Some(Profiler::default()) } else { None });
+    }
+
+    pub fn profile_flat(&self) -> String {
+        self.profile_hook.profiler().as_ref().map(|p| p.flat()).unwrap_or_default()
+    }
+
+    pub fn profile_folded(&self) -> String {
+        self.profile_hook.profiler().as_ref().map(|p| p.folded()).unwrap_or_default()
+    }
+
+    /// Take a ram snapshot and start a new search from all ram addresses.
+    pub fn cheat_search_start(&mut self) {
+        self.search = Some(Search::start(&self.ram));
+    }
+
+    /// Filter the current search by `cmp` (one of `equal`, `changed`, `greater`,
+    /// `less` or `value`: `value` is used just by the last one) and return
+    /// how many candidates are left.
+    pub fn cheat_search(&mut self, cmp: &str, value: u8) -> Result<usize, JsValue> {
+        let cmp = match cmp {
+            "equal" => Cmp::Equal,
+            "changed" => Cmp::Changed,
+            "greater" => Cmp::Greater,
+            "less" => Cmp::Less,
+            "value" => Cmp::Value(value),
+            _ => return Err(JsValue::from_str(&format!("Unknown comparison '{}'", cmp)))
+        };
+        let ram = &self.ram;
+        let search = self.search.get_or_insert_with(|| Search::start(ram));
+        search.filter(ram, cmp);
+        Ok(search.candidates().len())
+    }
+
+    pub fn cheat_candidates(&self) -> Vec<u16> {
+        self.search.as_ref().map(|s| s.candidates().to_vec()).unwrap_or_default()
+    }
+
+    pub fn cheat_freeze(&mut self, address: u16, value: u8) -> Result<(), JsValue> {
+        self.cheats.freeze(&self.ram, address, value)
+            .map_err(|e| JsValue::from_str(&e.to_string()))
+    }
+
+    pub fn cheat_unfreeze(&mut self, address: u16) {
+        self.cheats.unfreeze(address)
+    }
+
+    pub fn cheat_enable(&mut self, desc: &str, enabled: bool) -> bool {
+        self.cheats.enable(desc, enabled)
+    }
+
+    /// Load cheats from a MAME cheat XML file and return how many
+    /// cheats were loaded (all disabled).
+    pub fn cheats_load(&mut self, xml: &str) -> Result<usize, JsValue> {
+        self.cheats.load(xml)
+            .map_err(|e| JsValue::from_str(&e.to_string()))
+    }
+
+    pub fn cheats_save(&self) -> String {
+        self.cheats.save()
+    }
 }
 
 impl SpaceInvaders {
-    fn run_till(&mut self, clocks: u64) -> Result<(), CpuError> {
+    /// Half frame exposure of the phosphors, when enabled.
+    fn expose_phosphor(&mut self) {
+        if let Some(ref mut phosphor) = self.phosphor {
+            phosphor.expose_vram(&*self.vram.data(), FRAME_MS as f32 / 2.0);
+        }
+    }
+
+    /// The cpu registers with the interrupt enable and halt flags.
+    pub fn registers(&self) -> Registers {
+        Registers {
+            inte: self.cpu.interrupt_enabled(),
+            halted: self.cpu.is_stopped(),
+            ..Registers::from(self.cpu.state())
+        }
+    }
+
+    fn run_till(&mut self, clocks: u64) -> Result<(), Crash> {
         while self.clocks < clocks {
-            self.clocks += self.cpu.run()? as u64;
+            if self.tracer.enabled() {
+                self.tracer.begin(self.registers(), self.clocks);
+            }
+            let tracer = &self.tracer;
+            let periods = self.cpu.run().map_err(|error| {
+                tracer.end();
+                Crash { error, trace: tracer.history() }
+            })? as u64;
+            self.tracer.end();
+            self.profile_hook.account(periods);
+            self.clocks += periods;
+        }
+        Ok(())
+    }
+
+    pub fn tracer(&self) -> &Tracer {
+        &self.tracer
+    }
+
+    /// Borrow the video ram (as read by `render::lit()`).
+    pub fn video_ram(&self) -> ::std::cell::Ref<[u8]> {
+        ::std::cell::Ref::map(self.vram.data(), |d| &d[..])
+    }
+
+    pub fn coin_mech(&self) -> &CoinMech {
+        &self.coin_mech
+    }
+
+    pub fn coin_mech_mut(&mut self) -> &mut CoinMech {
+        &mut self.coin_mech
+    }
+
+    pub fn mapper(&self) -> &Mapper {
+        &self.mapper
+    }
+
+    pub fn assist(&self) -> &Assist {
+        &self.assist
+    }
+
+    pub fn assist_mut(&mut self) -> &mut Assist {
+        &mut self.assist
+    }
+
+    /// Run a frame with `inputs` as the cpu inputs, bypassing autofire and
+    /// macros: the way to replay inputs recorded in a movie, that already
+    /// went through them.
+    pub fn replay_frame(&mut self, inputs: u32) {
+        let user = self.io.inputs();
+        self.run_frame(user, inputs);
+    }
+
+    /// Run a frame where the cpu sees the `assisted` inputs, then restore
+    /// the user `inputs`.
+    fn run_frame(&mut self, inputs: u32, assisted: u32) {
+        let done_frame = self.frames * CLOCKS_PER_FRAME;
+        let next_half = done_frame + CLOCKS_PER_HALF_FRAME;
+
+        self.cheats.apply(&self.ram);
+        if let Some(ref mut movie) = self.movie {
+            movie.record(self.frames - 1, assisted);
         }
+        let coin = self.coin_mech.step(assisted & Ev::Coin.mask() != 0);
+        self.io.set_inputs(match coin {
+            true => assisted | Ev::Coin.mask(),
+            false => assisted & !Ev::Coin.mask(),
+        });
+
+        self.run_till(done_frame).unwrap();
+        self.expose_phosphor();
+        self.cpu.irq(IrqCmd::Irq1).unwrap();
+        self.profile_hook.irq(Half::Irq1);
+
+        self.run_till(next_half).unwrap();
+        self.expose_phosphor();
+        self.cpu.irq(IrqCmd::Irq2).unwrap();
+        self.profile_hook.irq(Half::Irq2);
+
+        self.frames += 1;
+        self.io.set_inputs(inputs);
+        let playing = self.ram.get(ram_map::GAME_MODE).unwrap_or(0) != 0;
+        self.coin_mech.observe(playing, self.credits());
+        self.hiscore_restored = match self.hiscore {
+            Some(ref mut keeper) => keeper.frame(self.frames - 1, &self.ram),
+            None => false,
+        };
+    }
+
+    fn map_inputs(&self, changes: Vec<(Ev, bool)>) {
+        for (ev, pressed) in changes {
+            self.io.ui_event(ev, pressed);
+        }
+    }
+
+    /// Restore the high score from `storage` and save it there when beaten.
+    pub fn set_hiscore_storage<S: Storage + 'static>(&mut self, storage: S) {
+        self.hiscore_memory = None;
+        self.hiscore = Some(Keeper::new(storage));
+    }
+
+    /// Did the last frame restore the saved high score? It's a ram change
+    /// that the inputs don't reproduce (see `hiscore`).
+    pub fn hiscore_restored(&self) -> bool {
+        self.hiscore_restored
+    }
+
+    /// Stop keeping the high score: netplay peers must run just on the
+    /// shared inputs.
+    pub fn disable_hiscore(&mut self) {
+        self.hiscore = None;
+        self.hiscore_memory = None;
+    }
+
+    /// Read a ram byte: `None` if `address` is not in ram.
+    pub fn peek(&self, address: u16) -> Option<u8> {
+        self.ram.get(address)
+    }
+
+    /// FNV-1a hash of the video ram: cheap to compare and to store.
+    pub fn vram_hash(&self) -> u64 {
+        self.vram.data().iter()
+            .fold(0xcbf2_9ce4_8422_2325, |h, &b| (h ^ b as u64).wrapping_mul(0x0100_0000_01b3))
+    }
+
+    /// How many frames are been executed.
+    pub fn frame(&self) -> u64 {
+        self.frames - 1
+    }
+
+    pub fn save_state(&self) -> Snapshot {
+        Snapshot {
+            regs: self.registers(),
+            ram: self.ram.snapshot(),
+            vram: self.vram.snapshot(),
+            io: self.io.state(),
+            coin: self.coin_mech.state(),
+            assist: self.assist.state(),
+            clocks: self.clocks,
+            frames: self.frames,
+        }
+    }
+
+    pub fn load_state(&mut self, snapshot: &Snapshot) {
+        snapshot.regs.apply(self.cpu.state_mut());
+        match snapshot.regs.inte {
+            true => self.cpu.enable_interrupt(),
+            false => self.cpu.disable_interrupt(),
+        }
+        self.cpu.set_stopped(snapshot.regs.halted);
+        self.ram.load(&snapshot.ram);
+        self.vram.load(&snapshot.vram);
+        self.io.restore(&snapshot.io);
+        self.coin_mech.restore(&snapshot.coin);
+        self.assist.restore(&snapshot.assist);
+        self.clocks = snapshot.clocks;
+        self.frames = snapshot.frames;
+        self.hiscore_restored = false;
+        let frame = self.frame();
+        if let Some(ref mut keeper) = self.hiscore {
+            keeper.loaded(frame, &self.ram);
+        }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl SpaceInvaders {
+    /// Stream every executed instruction to `path` (see `trace` module for the
+    /// format) and enable tracing.
+    pub fn trace_to_file<P: AsRef<std::path::Path>>(&self, path: P) -> std::io::Result<()> {
+        let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+        self.tracer.stream_to(file);
+        self.tracer.enable(true);
         Ok(())
     }
 }
@@ -205,6 +965,344 @@ mod test {
             si.next_frame();
         }
     }
+
+    #[test]
+    fn run_for_should_follow_emulated_time() {
+        let mut game = Game::new();
+        let mut si = game.space_invaders();
+
+        let run: u32 = (0..60).map(|_| si.run_for(1000.0 / 60.0)).sum();
+        si.pause(true);
+        si.run_for(1000.0);
+
+        assert_eq!(59, run);
+        assert_eq!(59, si.frame());
+    }
+
+    #[test]
+    fn should_trace_executed_instructions() {
+        let mut game = Game::new();
+        let mut si = game.space_invaders();
+        si.trace(true);
+
+        si.next_frame();
+
+        let history = si.tracer().history();
+        assert_eq!(trace::CRASH_HISTORY, history.len());
+        assert!(history.iter().all(|e| e.opcode.is_some()));
+        assert!(history.windows(2).all(|w| w[0].clocks < w[1].clocks));
+    }
+
+    #[test]
+    fn profiler_should_account_all_clocks() {
+        let mut game = Game::new();
+        let mut si = game.space_invaders();
+        si.profile(true);
+
+        for _i in 0..10 {
+            si.next_frame();
+        }
+
+        let profiler = si.profile_hook.profiler();
+        let profiler = profiler.as_ref().unwrap();
+        assert!(profiler.total() >= 10 * CLOCKS_PER_FRAME);
+        assert!(profiler.half_total(Half::Irq1) >= 9 * CLOCKS_PER_HALF_FRAME);
+        assert!(si.profile_folded().lines().count() > 1);
+    }
+
+    #[test]
+    fn frozen_ram_should_survive_frames() {
+        let mut game = Game::new();
+        let mut si = game.space_invaders();
+        for _i in 0..100 {
+            si.next_frame();
+        }
+        si.cheats_load(include_str!("../resources/invaders.xml")).unwrap();
+        assert!(si.cheat_enable("Infinite Lives P1", true));
+
+        si.ram.set(0x21FF, 0x01);
+        si.next_frame();
+
+        assert_eq!(Some(0x03), si.ram.get(0x21FF));
+    }
+
+    #[test]
+    fn load_state_should_replay_the_same_frames() {
+        let mut game = Game::new();
+        let mut si = game.space_invaders();
+        for _i in 0..100 {
+            si.next_frame();
+        }
+        let snapshot = si.save_state();
+        si.set_inputs(Ev::Coin.mask());
+        for _i in 0..50 {
+            si.next_frame();
+        }
+        let expected = si.save_state();
+        let coins = si.coins_inserted();
+        assert_eq!(1, coins);
+
+        si.load_state(&snapshot);
+        assert_eq!(snapshot, si.save_state());
+        assert_eq!(0, si.coins_inserted());
+        si.set_inputs(Ev::Coin.mask());
+        for _i in 0..50 {
+            si.next_frame();
+        }
+
+        assert_eq!(expected, si.save_state());
+        assert_eq!(coins, si.coins_inserted());
+    }
+
+    #[test]
+    fn load_state_should_restore_interrupt_flag() {
+        let mut game = Game::new();
+        let mut si = game.space_invaders();
+        run(&mut si, 100, 0);
+        // Irq2 has just been taken: we are in the isr with interrupts disabled
+        let di = si.save_state();
+        assert!(!di.regs.inte);
+        let mut ei = di.clone();
+        ei.regs.inte = true;
+
+        let mut fresh = game.space_invaders();
+        fresh.load_state(&ei);
+        assert!(fresh.registers().inte);
+        fresh.load_state(&di);
+        assert_eq!(di, fresh.save_state());
+
+        run(&mut si, 10, 0);
+        run(&mut fresh, 10, 0);
+        assert_eq!(si.save_state(), fresh.save_state());
+    }
+
+    #[test]
+    fn patch_should_change_rom_and_name() {
+        let mut game = Game::new();
+        assert_eq!(patch::BASE_CRC32, patch::crc32(&game.rom));
+
+        game.apply_patch("rst7.ips", b"PATCH\x00\x00\x00\x00\x01\xFFEOF").unwrap();
+
+        assert_eq!(0xFF, game.rom[0]);
+        assert_eq!("Space Invaders [rst7.ips]", game.name());
+    }
+
+    #[test]
+    fn ips_patches_should_chain() {
+        let mut game = Game::new();
+
+        game.apply_patch("rst7.ips", b"PATCH\x00\x00\x00\x00\x01\xFFEOF").unwrap();
+        game.apply_patch("second.ips", b"PATCH\x00\x00\x01\x00\x01\xFFEOF").unwrap();
+
+        assert_eq!(&[0xFF, 0xFF], &game.rom[..2]);
+    }
+
+    #[test]
+    fn should_accept_player_2_and_tilt_inputs() {
+        let mut game = Game::new();
+        let si = game.space_invaders();
+
+        si.set_input(Ev::P2Left, true);
+        si.set_input(Ev::Tilt, true);
+        assert_eq!(Ev::P2Left.mask() | Ev::Tilt.mask(), si.inputs());
+
+        si.set_inputs(Ev::P2Shoot.mask());
+        assert_eq!(Ev::P2Shoot.mask(), si.inputs());
+    }
+
+    #[test]
+    fn machines_should_outlive_the_game() {
+        let mut si = Game::new().space_invaders();
+        let mut other = Game::new().space_invaders();
+
+        for _ in 0..200 {
+            si.next_frame();
+        }
+        other.next_frame();
+
+        assert_ne!(si.vram.snapshot(), other.vram.snapshot());
+    }
+
+    #[test]
+    fn upscaler_should_scale_rendered_frames() {
+        let mut game = Game::new();
+        let mut si = game.space_invaders();
+        for _ in 0..100 {
+            si.next_frame();
+        }
+
+        assert!(si.set_upscaler("scale2x", 3).is_err());
+        si.set_upscaler("scale2x", 2).unwrap();
+        si.render();
+
+        assert_eq!(2, si.scale_factor());
+        assert_eq!(render::WIDTH * render::HEIGHT * 16, si.upscaler.as_ref().unwrap().rgba().len());
+    }
+
+    #[test]
+    fn crt_should_process_every_render() {
+        let mut game = Game::new();
+        let mut si = game.space_invaders();
+        si.set_upscaler("nearest", 2).unwrap();
+        si.set_crt(0.5, 0.0, 0.5, 0.0, false);
+
+        si.render();
+        si.render();
+
+        assert_eq!(render::WIDTH * render::HEIGHT * 16, si.crt.as_ref().unwrap().rgba().len());
+    }
+
+    #[test]
+    fn phosphor_should_be_deterministic() {
+        let run = || {
+            let mut game = Game::new();
+            let mut si = game.space_invaders();
+            si.set_phosphor(16.0);
+            for _ in 0..300 {
+                si.next_frame();
+            }
+            si.render();
+            si.renderer.rgba().to_vec()
+        };
+
+        assert_eq!(run(), run());
+    }
+
+    fn run(si: &mut SpaceInvaders, frames: usize, inputs: u32) {
+        si.set_inputs(inputs);
+        for _ in 0..frames {
+            si.next_frame();
+        }
+    }
+
+    #[test]
+    fn coin_mech_should_keep_bookkeeping() {
+        let mut game = Game::new();
+        let mut si = game.space_invaders();
+        run(&mut si, 100, 0);
+
+        run(&mut si, 1, Ev::Coin.mask());
+        assert_eq!(Ev::Coin.mask(), si.inputs());
+        run(&mut si, 30, 0);
+        assert_eq!(0, si.credits());
+        run(&mut si, 5, Ev::Coin.mask());
+        run(&mut si, 30, 0);
+        run(&mut si, 5, Ev::P1Start.mask());
+        run(&mut si, 60, 0);
+
+        assert_eq!(1, si.coins_inserted());
+        assert_eq!(1, si.games_played());
+        assert_eq!(1, si.credits_used());
+        assert_eq!(0, si.credits());
+    }
+
+    #[test]
+    fn coin_lockout_should_reject_coins() {
+        let mut game = Game::new();
+        let mut si = game.space_invaders();
+        si.set_coin_lockout(true);
+        run(&mut si, 100, 0);
+
+        run(&mut si, 5, Ev::Coin.mask());
+        run(&mut si, 30, 0);
+
+        assert_eq!(0, si.credits());
+        assert_eq!(1, si.coins_rejected());
+        assert_eq!(0.0, si.coin_counter());
+    }
+
+    #[test]
+    fn free_play_should_start_games_without_coins() {
+        let mut game = Game::new();
+        let mut si = game.space_invaders();
+        si.set_free_play(1);
+        run(&mut si, 200, 0);
+        assert_eq!(1, si.credits());
+
+        run(&mut si, 5, Ev::P1Start.mask());
+        run(&mut si, 120, 0);
+
+        assert_eq!(1, si.games_played());
+        assert_eq!(1, si.credits());
+        assert_eq!(0, si.coins_inserted());
+    }
+
+    #[test]
+    fn hiscore_should_be_restored_after_boot() {
+        let mut game = Game::new();
+        let mut si = game.space_invaders();
+        si.enable_hiscore(Some(hiscore::encode(1230)));
+
+        run(&mut si, hiscore::BOOT_FRAMES as usize + 1, 0);
+
+        assert_eq!(1230, si.hi_score());
+        assert_eq!(None, si.take_hiscore());
+    }
+
+    #[test]
+    fn mapped_inputs_should_press_events() {
+        let mut game = Game::new();
+        let mut si = game.space_invaders();
+        si.set_bindings("p1shoot key:KeyX axis:0:1+").unwrap();
+
+        assert!(si.key("KeyX", true));
+        si.gamepad_axis(0, 1, 1.0);
+        assert!(si.key("KeyX", false));
+        assert!(!si.key("Space", true));
+        assert_eq!(Ev::P1Shoot.mask(), si.inputs());
+
+        si.release_all();
+        assert_eq!(0, si.inputs());
+    }
+
+    #[test]
+    fn movie_should_record_autofire_and_macros() {
+        let mut game = Game::new();
+        let mut si = game.space_invaders();
+        si.set_free_play(1);
+        si.set_autofire(Ev::P1Shoot, 3);
+        si.set_macro("start", "0 p1start down\n5 p1start up").unwrap();
+        si.record_movie();
+        run(&mut si, 200, 0);
+        si.play_macro("start");
+        run(&mut si, 300, Ev::P1Shoot.mask());
+        let movie = movie::Movie::parse(&si.stop_movie().unwrap()).unwrap();
+
+        // The movie inputs already went through the assists
+        let mut replay = game.space_invaders();
+        replay.set_free_play(1);
+        replay.set_autofire(Ev::P1Shoot, 3);
+        while replay.frame() < si.frame() {
+            let inputs = movie.inputs(replay.frame());
+            replay.replay_frame(inputs);
+        }
+
+        assert_eq!(Ev::P1Shoot.mask(), movie.inputs(230) & Ev::P1Shoot.mask());
+        assert_eq!(0, movie.inputs(233) & Ev::P1Shoot.mask());
+        assert_eq!(1, si.games_played());
+        assert_eq!(si.vram_hash(), replay.vram_hash());
+    }
+
+    #[test]
+    fn cocktail_should_configure_new_machines() {
+        let mut game = Game::new();
+        game.set_cocktail(true);
+
+        let si = game.space_invaders();
+
+        assert!(si.io.is_cocktail());
+        assert!(!si.flipped());
+    }
+
+    #[test]
+    fn dips_should_configure_new_machines() {
+        let mut game = Game::new();
+        game.dip_set(0, 2).unwrap();
+
+        let si = game.space_invaders();
+
+        assert_eq!(5, si.io.lives());
+    }
 }
 
 
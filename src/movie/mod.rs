@@ -0,0 +1,221 @@
+//! Input movies.
+//!
+//! A movie is the list of the input changes of a run, so the same run can be
+//! replayed on a new machine. The text format has a line for every change:
+//! the frame number followed by the inputs pressed from that frame on
+//! (names as `Ev::name()`, nothing for all released). `check` lines mark
+//! the frames that a test should look at; `#` starts a comment.
+//!
+//! ```text
+//! # Insert a coin and start
+//! 100 coin
+//! 105
+//! 150 p1start
+//! 155
+//! check 300
+//! ```
+
+use std::fmt;
+
+use Ev;
+use SpaceInvaders;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MovieError {
+    Frame(usize),
+    Input(usize, String),
+    /// Frames must be in order.
+    Order(usize),
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MovieError::Frame(line) => write!(f, "Line {}: invalid frame number", line),
+            MovieError::Input(line, ref name) => write!(f, "Line {}: unknown input '{}'", line, name),
+            MovieError::Order(line) => write!(f, "Line {}: frame before the previous one", line),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Movie {
+    changes: Vec<(u64, u32)>,
+    checks: Vec<u64>,
+}
+
+impl Movie {
+    pub fn parse(text: &str) -> Result<Self, MovieError> {
+        let mut movie = Movie::default();
+        for (n, line) in text.lines().enumerate().map(|(n, l)| (n + 1, l)) {
+            let line = line.split('#').next().unwrap_or("").trim();
+            let mut words = line.split_whitespace();
+            let first = match words.next() {
+                Some(w) => w,
+                None => continue,
+            };
+            if first == "check" {
+                let frame = words.next().and_then(|w| w.parse().ok())
+                    .ok_or(MovieError::Frame(n))?;
+                movie.checks.push(frame);
+                continue;
+            }
+            let frame = first.parse().map_err(|_| MovieError::Frame(n))?;
+            if movie.changes.last().map(|&(f, _)| f > frame).unwrap_or(false) {
+                return Err(MovieError::Order(n));
+            }
+            let inputs = words
+                .map(|w| Ev::from_name(w).map(Ev::mask).ok_or_else(|| MovieError::Input(n, w.to_string())))
+                .collect::<Result<Vec<_>, _>>()?
+                .into_iter()
+                .fold(0, |acc, m| acc | m);
+            movie.changes.push((frame, inputs));
+        }
+        movie.checks.sort();
+        Ok(movie)
+    }
+
+    /// Record the inputs of `frame`: just the changes are kept. Frames must
+    /// be recorded in order.
+    pub fn record(&mut self, frame: u64, inputs: u32) {
+        if self.inputs(frame) == inputs {
+            return;
+        }
+        match self.changes.last_mut() {
+            Some(last) if last.0 == frame => last.1 = inputs,
+            _ => self.changes.push((frame, inputs)),
+        }
+    }
+
+    pub fn add_check(&mut self, frame: u64) {
+        if let Err(pos) = self.checks.binary_search(&frame) {
+            self.checks.insert(pos, frame);
+        }
+    }
+
+    /// The inputs pressed at `frame`.
+    pub fn inputs(&self, frame: u64) -> u32 {
+        match self.changes.binary_search_by_key(&frame, |&(f, _)| f) {
+            Ok(pos) => self.changes[pos].1,
+            Err(0) => 0,
+            Err(pos) => self.changes[pos - 1].1,
+        }
+    }
+
+    pub fn checks(&self) -> &[u64] {
+        &self.checks
+    }
+
+    /// The frame after the last change or check.
+    pub fn len(&self) -> u64 {
+        let last_change = self.changes.last().map(|&(f, _)| f + 1).unwrap_or(0);
+        let last_check = self.checks.last().cloned().unwrap_or(0);
+        last_change.max(last_check)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty() && self.checks.is_empty()
+    }
+
+    /// Play the movie on `si` till `len()`: `check` is called before the
+    /// checked frames are run (and at the end for the last one). The recorded
+    /// inputs bypass the `si` autofire and macros (see `replay_frame()`).
+    pub fn play<F: FnMut(&SpaceInvaders)>(&self, si: &mut SpaceInvaders, mut check: F) {
+        let mut checks = self.checks.iter().peekable();
+        loop {
+            let frame = si.frame();
+            while checks.peek().map(|&&c| c <= frame).unwrap_or(false) {
+                if *checks.next().unwrap() == frame {
+                    check(si);
+                }
+            }
+            if frame >= self.len() {
+                break;
+            }
+            si.replay_frame(self.inputs(frame));
+        }
+    }
+}
+
+impl fmt::Display for Movie {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for &(frame, inputs) in self.changes.iter() {
+            write!(f, "{}", frame)?;
+            for ev in Ev::ALL.iter().filter(|ev| inputs & ev.mask() != 0) {
+                write!(f, " {}", ev.name())?;
+            }
+            writeln!(f)?;
+        }
+        for check in self.checks.iter() {
+            writeln!(f, "check {}", check)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use Game;
+
+    const MOVIE: &str = "\
+# Comment
+100 coin
+105
+150 p1start # start
+155
+200 p1left p1shoot
+check 300
+check 30
+";
+
+    #[test]
+    fn parse() {
+        let movie = Movie::parse(MOVIE).unwrap();
+
+        assert_eq!(0, movie.inputs(99));
+        assert_eq!(Ev::Coin.mask(), movie.inputs(104));
+        assert_eq!(0, movie.inputs(105));
+        assert_eq!(Ev::P1Left.mask() | Ev::P1Shoot.mask(), movie.inputs(1000));
+        assert_eq!(&[30, 300], movie.checks());
+        assert_eq!(300, movie.len());
+    }
+
+    #[test]
+    fn invalid_movies() {
+        assert_eq!(Err(MovieError::Frame(1)), Movie::parse("ten coin"));
+        assert_eq!(Err(MovieError::Input(2, "p3start".to_string())), Movie::parse("1\n2 p3start"));
+        assert_eq!(Err(MovieError::Order(2)), Movie::parse("10\n5 coin"));
+    }
+
+    #[test]
+    fn record_should_keep_just_changes() {
+        let mut movie = Movie::default();
+
+        for frame in 0..10 {
+            movie.record(frame, if frame >= 3 && frame < 6 { Ev::Coin.mask() } else { 0 });
+        }
+
+        assert_eq!("3 coin\n6\n", movie.to_string());
+    }
+
+    #[test]
+    fn format_should_round_trip() {
+        let movie = Movie::parse(MOVIE).unwrap();
+
+        assert_eq!(Ok(movie.clone()), Movie::parse(&movie.to_string()));
+    }
+
+    #[test]
+    fn play_should_call_checks() {
+        let movie = Movie::parse(MOVIE).unwrap();
+        let mut game = Game::new();
+        let mut si = game.space_invaders();
+        let mut checked = Vec::new();
+
+        movie.play(&mut si, |si| checked.push(si.frame()));
+
+        assert_eq!(vec![30, 300], checked);
+        assert_eq!(300, si.frame());
+    }
+}
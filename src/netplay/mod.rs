@@ -0,0 +1,546 @@
+//! Rollback netplay.
+//!
+//! Both peers run the same machine and own a subset of the inputs (see
+//! `Side::mask()`). Every frame a peer sends its inputs and goes on using a
+//! prediction for the remote ones (the last remote input received); when the
+//! real remote inputs arrive and differ from the prediction the machine is
+//! restored to the snapshot of the first wrong frame and the frames are
+//! simulated again.
+//!
+//! Packets repeat the last `RESEND` local inputs, so an unreliable transport
+//! like UDP can drop some of them.
+//!
+//! The peers' high score keepers would restore different high scores: they
+//! are disabled by the session.
+//!
+//! The local input goes through the local assists (autofire and macros, see
+//! `assist`) before it's sent, and the frames run the exchanged inputs as
+//! they are (`SpaceInvaders::replay_frame()`): a peer can use its assists
+//! without the other one.
+
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::rc::Rc;
+
+use si::io::Ev;
+use snapshot::Snapshot;
+use SpaceInvaders;
+
+/// How many local inputs are sent in every packet.
+pub const RESEND: usize = 8;
+/// Default for how many frames a peer can run ahead of the confirmed remote
+/// inputs.
+pub const MAX_ROLLBACK: u64 = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Side {
+    P1,
+    P2,
+}
+
+impl Side {
+    /// The inputs owned by this side: player 1 take care of coins and tilt too.
+    pub fn mask(self) -> u32 {
+        match self {
+            Side::P1 => [Ev::Coin, Ev::Tilt, Ev::P1Start, Ev::P1Shoot, Ev::P1Left, Ev::P1Right]
+                .iter().fold(0, |m, ev| m | ev.mask()),
+            Side::P2 => [Ev::P2Start, Ev::P2Shoot, Ev::P2Left, Ev::P2Right]
+                .iter().fold(0, |m, ev| m | ev.mask()),
+        }
+    }
+
+    pub fn other(self) -> Self {
+        match self {
+            Side::P1 => Side::P2,
+            Side::P2 => Side::P1,
+        }
+    }
+}
+
+/// Inputs for the frames `frame..frame + inputs.len()`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Packet {
+    pub frame: u64,
+    pub inputs: Vec<u32>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum NetError {
+    Malformed,
+    Transport(String),
+}
+
+impl fmt::Display for NetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            NetError::Malformed => write!(f, "Malformed packet"),
+            NetError::Transport(ref msg) => write!(f, "Transport error: {}", msg),
+        }
+    }
+}
+
+impl Packet {
+    /// Wire format: frame (u64 LE), inputs count (u8) and inputs (u32 LE).
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(9 + 4 * self.inputs.len());
+        out.extend((0..8).map(|i| (self.frame >> (8 * i)) as u8));
+        out.push(self.inputs.len() as u8);
+        for input in self.inputs.iter() {
+            out.extend((0..4).map(|i| (input >> (8 * i)) as u8));
+        }
+        out
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, NetError> {
+        if data.len() < 9 || data.len() != 9 + 4 * data[8] as usize {
+            return Err(NetError::Malformed);
+        }
+        let le = |bytes: &[u8]| bytes.iter().rev().fold(0u64, |acc, &b| (acc << 8) | b as u64);
+        Ok(Packet {
+            frame: le(&data[..8]),
+            inputs: data[9..].chunks(4).map(|c| le(c) as u32).collect(),
+        })
+    }
+}
+
+pub trait Transport {
+    fn send(&mut self, packet: &Packet) -> Result<(), NetError>;
+    /// Return the next received packet, if any: never block.
+    fn recv(&mut self) -> Result<Option<Packet>, NetError>;
+}
+
+type Queue = Rc<RefCell<VecDeque<Vec<u8>>>>;
+
+/// In process transport: `Loopback::pair()` returns two connected ends.
+/// Packets are encoded like any other transport.
+pub struct Loopback {
+    tx: Queue,
+    rx: Queue,
+}
+
+impl Loopback {
+    pub fn pair() -> (Loopback, Loopback) {
+        let a = Queue::default();
+        let b = Queue::default();
+        (Loopback { tx: a.clone(), rx: b.clone() }, Loopback { tx: b, rx: a })
+    }
+
+    /// Packets sent but not received yet by the other end.
+    pub fn in_flight(&self) -> usize {
+        self.tx.borrow().len()
+    }
+}
+
+impl Transport for Loopback {
+    fn send(&mut self, packet: &Packet) -> Result<(), NetError> {
+        self.tx.borrow_mut().push_back(packet.encode());
+        Ok(())
+    }
+
+    fn recv(&mut self) -> Result<Option<Packet>, NetError> {
+        match self.rx.borrow_mut().pop_front() {
+            Some(data) => Packet::decode(&data).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub use self::udp::Udp;
+
+#[cfg(not(target_arch = "wasm32"))]
+mod udp {
+    use std::io::ErrorKind;
+    use std::net::{UdpSocket, ToSocketAddrs};
+
+    use super::*;
+
+    pub struct Udp {
+        socket: UdpSocket,
+    }
+
+    fn transport(e: ::std::io::Error) -> NetError {
+        NetError::Transport(e.to_string())
+    }
+
+    impl Udp {
+        /// Bind `local` and send packets just to `peer`.
+        pub fn connect<A: ToSocketAddrs, B: ToSocketAddrs>(local: A, peer: B) -> Result<Self, NetError> {
+            let socket = UdpSocket::bind(local).map_err(transport)?;
+            socket.connect(peer).map_err(transport)?;
+            socket.set_nonblocking(true).map_err(transport)?;
+            Ok(Udp { socket })
+        }
+    }
+
+    impl Transport for Udp {
+        fn send(&mut self, packet: &Packet) -> Result<(), NetError> {
+            match self.socket.send(&packet.encode()) {
+                Ok(_) => Ok(()),
+                // Peer not listening yet: it's like a lost packet.
+                Err(ref e) if e.kind() == ErrorKind::ConnectionRefused => Ok(()),
+                Err(e) => Err(transport(e)),
+            }
+        }
+
+        fn recv(&mut self) -> Result<Option<Packet>, NetError> {
+            let mut buf = [0; 512];
+            match self.socket.recv(&mut buf) {
+                Ok(n) => Packet::decode(&buf[..n]).map(Some),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock
+                    || e.kind() == ErrorKind::ConnectionRefused => Ok(None),
+                Err(e) => Err(transport(e)),
+            }
+        }
+    }
+}
+
+pub struct Session<T: Transport> {
+    transport: T,
+    side: Side,
+    max_rollback: u64,
+    /// Next frame to simulate.
+    frame: u64,
+    local: HashMap<u64, u32>,
+    remote: HashMap<u64, u32>,
+    /// Remote inputs used to simulate the frames not confirmed yet.
+    predicted: HashMap<u64, u32>,
+    /// First frame whose remote input is not received yet.
+    confirmed: u64,
+    snapshots: VecDeque<(u64, Snapshot)>,
+    rollbacks: u64,
+}
+
+impl<T: Transport> Session<T> {
+    pub fn new(transport: T, side: Side) -> Self {
+        Session {
+            transport,
+            side,
+            max_rollback: MAX_ROLLBACK,
+            frame: 0,
+            local: Default::default(),
+            remote: Default::default(),
+            predicted: Default::default(),
+            confirmed: 0,
+            snapshots: Default::default(),
+            rollbacks: 0,
+        }
+    }
+
+    pub fn max_rollback(self, max_rollback: u64) -> Self {
+        Session { max_rollback, ..self }
+    }
+
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    /// All frames before this one are simulated with the real inputs of
+    /// both peers.
+    pub fn confirmed(&self) -> u64 {
+        self.confirmed.min(self.frame)
+    }
+
+    pub fn rollbacks(&self) -> u64 {
+        self.rollbacks
+    }
+
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    /// Receive remote inputs and fix the mispredicted frames without
+    /// simulating a new one.
+    pub fn poll(&mut self, si: &mut SpaceInvaders) -> Result<(), NetError> {
+        if let Some(frame) = self.receive()? {
+            self.rollback(si, frame);
+        }
+        Ok(())
+    }
+
+    /// Receive remote inputs, fix the mispredicted frames and, if we are not
+    /// too far ahead of the remote peer, simulate a new frame with `input`.
+    /// Return `false` if the frame was not simulated: call it again later.
+    pub fn advance(&mut self, si: &mut SpaceInvaders, input: u32) -> Result<bool, NetError> {
+        self.poll(si)?;
+        if self.frame >= self.confirmed + self.max_rollback {
+            self.send()?;
+            return Ok(false);
+        }
+        let frame = self.frame;
+        let input = si.assist_mut().step(input);
+        self.local.insert(frame, input & self.side.mask());
+        self.send()?;
+        self.simulate(si, frame);
+        self.frame += 1;
+        self.prune();
+        Ok(true)
+    }
+
+    fn receive(&mut self) -> Result<Option<u64>, NetError> {
+        let mut first_wrong: Option<u64> = None;
+        while let Some(packet) = self.transport.recv()? {
+            let mask = self.side.other().mask();
+            for (frame, &input) in (packet.frame..).zip(packet.inputs.iter()) {
+                if frame < self.confirmed {
+                    continue;
+                }
+                let input = input & mask;
+                self.remote.insert(frame, input);
+                if self.predicted.get(&frame).map(|&p| p != input).unwrap_or(false) {
+                    first_wrong = Some(first_wrong.map(|f| f.min(frame)).unwrap_or(frame));
+                }
+            }
+        }
+        while self.remote.contains_key(&self.confirmed) {
+            self.confirmed += 1;
+        }
+        Ok(first_wrong)
+    }
+
+    fn send(&mut self) -> Result<(), NetError> {
+        let start = self.frame.saturating_sub(RESEND as u64 - 1);
+        let inputs = (start..=self.frame)
+            .filter_map(|f| self.local.get(&f).cloned())
+            .collect::<Vec<_>>();
+        if inputs.is_empty() {
+            return Ok(());
+        }
+        let first = (start..=self.frame).find(|f| self.local.contains_key(f)).unwrap();
+        self.transport.send(&Packet { frame: first, inputs })
+    }
+
+    fn remote_input(&self, frame: u64) -> u32 {
+        if let Some(&input) = self.remote.get(&frame) {
+            return input;
+        }
+        // Predict: the last input that we know.
+        let oldest = self.confirmed.saturating_sub(RESEND as u64);
+        (oldest..frame).rev()
+            .filter_map(|f| self.remote.get(&f).cloned())
+            .next()
+            .unwrap_or(0)
+    }
+
+    fn simulate(&mut self, si: &mut SpaceInvaders, frame: u64) {
+        let remote = self.remote_input(frame);
+        self.predicted.insert(frame, remote);
+        self.snapshots.push_back((frame, si.save_state()));
+        si.disable_hiscore();
+        let inputs = self.local[&frame] | remote;
+        si.set_inputs(inputs);
+        si.replay_frame(inputs);
+    }
+
+    fn rollback(&mut self, si: &mut SpaceInvaders, frame: u64) {
+        let position = match self.snapshots.iter().position(|&(f, _)| f == frame) {
+            Some(position) => position,
+            None => {
+                warn!("No snapshot for frame {}: cannot rollback", frame);
+                return;
+            }
+        };
+        debug!("Rollback from frame {} to {}", self.frame, frame);
+        self.rollbacks += 1;
+        // The assists already produced the local inputs of the frames to
+        // simulate again: keep their current state.
+        let assist = si.assist().state();
+        si.load_state(&self.snapshots[position].1);
+        si.assist_mut().restore(&assist);
+        self.snapshots.truncate(position);
+        for f in frame..self.frame {
+            self.simulate(si, f);
+        }
+    }
+
+    /// Forget everything about the confirmed frames but the last snapshot.
+    fn prune(&mut self) {
+        let confirmed = self.confirmed();
+        while self.snapshots.front().map(|&(f, _)| f < confirmed).unwrap_or(false) {
+            self.snapshots.pop_front();
+        }
+        let keep = |f: &u64, _: &mut u32| *f + RESEND as u64 >= confirmed;
+        self.local.retain(keep);
+        self.remote.retain(keep);
+        self.predicted.retain(keep);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use Game;
+    use hiscore;
+
+    #[test]
+    fn packet_round_trip() {
+        let packet = Packet { frame: 0x0102_0304_0506, inputs: vec![0x01, 0x3C0, 0xFFFF_FFFF] };
+
+        assert_eq!(Ok(packet.clone()), Packet::decode(&packet.encode()));
+    }
+
+    #[test]
+    fn malformed_packet() {
+        let mut data = Packet { frame: 12, inputs: vec![1, 2] }.encode();
+        data.pop();
+
+        assert_eq!(Err(NetError::Malformed), Packet::decode(&data));
+    }
+
+    #[test]
+    fn sides_should_own_disjoint_inputs() {
+        assert_eq!(0, Side::P1.mask() & Side::P2.mask());
+        assert_eq!(0x3FF, Side::P1.mask() | Side::P2.mask());
+    }
+
+    #[test]
+    fn loopback_should_connect_the_two_ends() {
+        let (mut a, mut b) = Loopback::pair();
+        let packet = Packet { frame: 3, inputs: vec![7] };
+
+        a.send(&packet).unwrap();
+
+        assert_eq!(Ok(Some(packet)), b.recv());
+        assert_eq!(Ok(None), a.recv());
+    }
+
+    fn p1_input(frame: u64) -> u32 {
+        match frame {
+            10..=14 => Ev::Coin.mask(),
+            30..=34 => Ev::P1Start.mask(),
+            f if f > 60 && (f / 7) % 2 == 0 => Ev::P1Left.mask() | Ev::P1Shoot.mask(),
+            f if f > 60 => Ev::P1Right.mask(),
+            _ => 0,
+        }
+    }
+
+    fn p2_input(frame: u64) -> u32 {
+        match (frame / 5) % 3 {
+            0 => Ev::P2Left.mask(),
+            1 => Ev::P2Shoot.mask(),
+            _ => 0,
+        }
+    }
+
+    #[test]
+    fn peers_should_converge_to_the_same_state() {
+        let (a, b) = Loopback::pair();
+        let mut game_a = Game::new();
+        let mut game_b = Game::new();
+        let mut si_a = game_a.space_invaders();
+        let mut si_b = game_b.space_invaders();
+        let mut peer_a = Session::new(a, Side::P1);
+        let mut peer_b = Session::new(b, Side::P2);
+
+        // Peer b is always some frames late: peer a mispredict its inputs
+        // and should rollback.
+        while peer_a.frame() < 200 {
+            for _ in 0..3 {
+                let f = peer_a.frame();
+                peer_a.advance(&mut si_a, p1_input(f)).unwrap();
+            }
+            for _ in 0..2 {
+                let f = peer_b.frame();
+                peer_b.advance(&mut si_b, p2_input(f)).unwrap();
+            }
+        }
+        while peer_b.frame() < peer_a.frame() {
+            let f = peer_b.frame();
+            peer_b.advance(&mut si_b, p2_input(f)).unwrap();
+        }
+        peer_a.poll(&mut si_a).unwrap();
+
+        assert!(peer_a.rollbacks() > 0);
+        assert_eq!(peer_a.frame(), peer_b.frame());
+        assert_eq!(si_a.save_state(), si_b.save_state());
+    }
+
+    #[test]
+    fn autofire_on_a_peer_should_not_desync() {
+        let (a, b) = Loopback::pair();
+        let mut game_a = Game::new();
+        let mut game_b = Game::new();
+        let mut si_a = game_a.space_invaders();
+        let mut si_b = game_b.space_invaders();
+        si_a.set_autofire(Ev::P1Shoot, 2);
+        let mut peer_a = Session::new(a, Side::P1);
+        let mut peer_b = Session::new(b, Side::P2);
+
+        while peer_a.frame() < 300 {
+            for _ in 0..3 {
+                let f = peer_a.frame();
+                peer_a.advance(&mut si_a, p1_input(f)).unwrap();
+            }
+            for _ in 0..2 {
+                let f = peer_b.frame();
+                peer_b.advance(&mut si_b, p2_input(f)).unwrap();
+            }
+        }
+        while peer_b.frame() < peer_a.frame() {
+            let f = peer_b.frame();
+            peer_b.advance(&mut si_b, p2_input(f)).unwrap();
+        }
+        peer_a.poll(&mut si_a).unwrap();
+
+        assert!(peer_a.rollbacks() > 0);
+        assert_eq!(peer_a.frame(), peer_b.frame());
+        let (mut state_a, state_b) = (si_a.save_state(), si_b.save_state());
+        // Just peer a has the autofire on
+        state_a.assist = state_b.assist.clone();
+        assert_eq!(state_a, state_b);
+    }
+
+    #[test]
+    fn peers_should_not_restore_their_hiscore() {
+        let (a, b) = Loopback::pair();
+        let mut game_a = Game::new();
+        let mut game_b = Game::new();
+        let mut si_a = game_a.space_invaders();
+        let mut si_b = game_b.space_invaders();
+        si_a.enable_hiscore(Some(hiscore::encode(1230)));
+        let mut peer_a = Session::new(a, Side::P1);
+        let mut peer_b = Session::new(b, Side::P2);
+
+        while peer_a.frame() <= hiscore::BOOT_FRAMES + 1 {
+            peer_a.advance(&mut si_a, 0).unwrap();
+            peer_b.advance(&mut si_b, 0).unwrap();
+        }
+
+        assert_eq!(0, si_a.hi_score());
+        assert_eq!(si_a.save_state(), si_b.save_state());
+    }
+
+    #[test]
+    fn should_wait_for_a_late_peer() {
+        let (a, _b) = Loopback::pair();
+        let mut game = Game::new();
+        let mut si = game.space_invaders();
+        let mut peer = Session::new(a, Side::P1).max_rollback(4);
+
+        let advanced = (0..10).filter(|_| peer.advance(&mut si, 0).unwrap()).count();
+
+        assert_eq!(4, advanced);
+        assert_eq!(4, peer.frame());
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    #[test]
+    fn udp_on_localhost() {
+        let a_addr = "127.0.0.1:38761";
+        let b_addr = "127.0.0.1:38762";
+        let mut a = Udp::connect(a_addr, b_addr).unwrap();
+        let mut b = Udp::connect(b_addr, a_addr).unwrap();
+        let packet = Packet { frame: 42, inputs: vec![1, 2, 3] };
+
+        a.send(&packet).unwrap();
+
+        let received = (0..1000)
+            .filter_map(|_| {
+                ::std::thread::sleep(::std::time::Duration::from_millis(1));
+                b.recv().unwrap()
+            })
+            .next();
+        assert_eq!(Some(packet), received);
+    }
+}
@@ -0,0 +1,159 @@
+//! Real time frame pacing.
+//!
+//! Convert the elapsed wall clock time in how many frames the machine should
+//! run. The fraction of frame that is left is kept for the next call, so the
+//! emulated time never drift from the real one (scaled by the speed).
+
+/// Monitor refresh rate.
+pub const FRAME_RATE: f64 = 59.94;
+pub const FRAME_MS: f64 = 1000.0 / FRAME_RATE;
+
+pub const MIN_SPEED: f64 = 0.25;
+pub const MAX_SPEED: f64 = 8.0;
+/// Speed used in turbo mode.
+pub const TURBO_SPEED: f64 = MAX_SPEED;
+
+/// Longer elapsed times are clamped to this: if the browser stop calling us
+/// (hidden tab) we don't want to run minutes of emulation in a single call.
+pub const MAX_ELAPSED_MS: f64 = 250.0;
+
+pub struct Pacer {
+    remainder: f64,
+    speed: f64,
+    paused: bool,
+    turbo: bool,
+}
+
+impl Default for Pacer {
+    fn default() -> Self {
+        Pacer { remainder: 0.0, speed: 1.0, paused: false, turbo: false }
+    }
+}
+
+impl Pacer {
+    /// How many frames should be run for `elapsed_ms` milliseconds of real time.
+    pub fn frames(&mut self, elapsed_ms: f64) -> u32 {
+        if self.paused || elapsed_ms.is_nan() || elapsed_ms <= 0.0 {
+            return 0;
+        }
+        self.remainder += elapsed_ms.min(MAX_ELAPSED_MS) * self.current_speed();
+        let frames = (self.remainder / FRAME_MS).floor();
+        self.remainder -= frames * FRAME_MS;
+        frames as u32
+    }
+
+    pub fn current_speed(&self) -> f64 {
+        match self.turbo {
+            true => TURBO_SPEED,
+            false => self.speed,
+        }
+    }
+
+    pub fn speed(&self) -> f64 {
+        self.speed
+    }
+
+    /// Change speed multiplier: clamped in `MIN_SPEED..=MAX_SPEED`.
+    pub fn set_speed(&mut self, speed: f64) {
+        self.speed = match speed.is_nan() {
+            true => 1.0,
+            false => speed.max(MIN_SPEED).min(MAX_SPEED),
+        };
+    }
+
+    pub fn paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn set_paused(&mut self, paused: bool) {
+        if paused == self.paused {
+            return;
+        }
+        self.paused = paused;
+        // Resume from the beginning of a frame
+        self.remainder = 0.0;
+    }
+
+    pub fn turbo(&self) -> bool {
+        self.turbo
+    }
+
+    pub fn set_turbo(&mut self, turbo: bool) {
+        self.turbo = turbo;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rstest::{rstest, rstest_parametrize};
+
+    fn pacer() -> Pacer {
+        Pacer::default()
+    }
+
+    #[rstest]
+    fn should_not_drift(mut pacer: Pacer) {
+        // A minute of 60Hz timer
+        let frames: u32 = (0..3600).map(|_| pacer.frames(1000.0 / 60.0)).sum();
+
+        assert_eq!(3596, frames);
+    }
+
+    #[rstest]
+    fn should_keep_remainder(mut pacer: Pacer) {
+        assert_eq!(0, pacer.frames(FRAME_MS / 2.0));
+        assert_eq!(1, pacer.frames(FRAME_MS / 2.0 + 0.001));
+        assert_eq!(2, pacer.frames(FRAME_MS * 2.0));
+    }
+
+    #[rstest_parametrize(
+    speed, expected,
+    case(0.5, 0.5),
+    case(0.1, 0.25),
+    case(12.0, 8.0),
+    case(Unwrap("::std::f64::NAN"), 1.0),
+    )]
+    fn clamp_speed(mut pacer: Pacer, speed: f64, expected: f64) {
+        pacer.set_speed(speed);
+
+        assert_eq!(expected, pacer.speed());
+    }
+
+    #[rstest]
+    fn speed_should_scale_frames(mut pacer: Pacer) {
+        pacer.set_speed(4.0);
+
+        assert_eq!(4, pacer.frames(FRAME_MS + 0.001));
+    }
+
+    #[rstest]
+    fn paused_should_not_run(mut pacer: Pacer) {
+        pacer.set_paused(true);
+
+        assert_eq!(0, pacer.frames(100.0));
+    }
+
+    #[rstest]
+    fn unchanged_pause_should_keep_remainder(mut pacer: Pacer) {
+        assert_eq!(0, pacer.frames(FRAME_MS / 2.0));
+        pacer.set_paused(false);
+
+        assert_eq!(1, pacer.frames(FRAME_MS / 2.0 + 0.001));
+    }
+
+    #[rstest]
+    fn turbo_should_use_max_speed(mut pacer: Pacer) {
+        pacer.set_turbo(true);
+
+        assert_eq!(8, pacer.frames(FRAME_MS + 0.001));
+
+        pacer.set_turbo(false);
+        assert_eq!(1, pacer.frames(FRAME_MS));
+    }
+
+    #[rstest]
+    fn should_clamp_long_elapsed_time(mut pacer: Pacer) {
+        assert_eq!((MAX_ELAPSED_MS / FRAME_MS) as u32, pacer.frames(10_000.0));
+    }
+}
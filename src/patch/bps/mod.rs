@@ -0,0 +1,252 @@
+use super::{Result, PatchError, crc32};
+
+pub const MAGIC: &[u8] = b"BPS1";
+const FOOTER_SIZE: usize = 12;
+
+const SOURCE_READ: usize = 0;
+const TARGET_READ: usize = 1;
+const SOURCE_COPY: usize = 2;
+const TARGET_COPY: usize = 3;
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn byte(&mut self) -> Result<u8> {
+        let b = *self.data.get(self.pos).ok_or(PatchError::Truncated)?;
+        self.pos += 1;
+        Ok(b)
+    }
+
+    fn number(&mut self) -> Result<usize> {
+        let mut data: usize = 0;
+        let mut shift: usize = 1;
+        loop {
+            let x = self.byte()? as usize;
+            data = (x & 0x7f).checked_mul(shift)
+                .and_then(|d| d.checked_add(data))
+                .ok_or(PatchError::Number)?;
+            if x & 0x80 != 0 {
+                return Ok(data);
+            }
+            shift = shift.checked_mul(0x80).ok_or(PatchError::Number)?;
+            data = data.checked_add(shift).ok_or(PatchError::Number)?;
+        }
+    }
+
+    fn relative(&mut self) -> Result<isize> {
+        let data = self.number()?;
+        let offset = (data >> 1) as isize;
+        Ok(if data & 1 == 1 { -offset } else { offset })
+    }
+}
+
+fn le32(data: &[u8]) -> u32 {
+    data.iter().rev().fold(0, |acc, &b| (acc << 8) | b as u32)
+}
+
+fn checked(expected: u32, actual: u32, err: fn(u32, u32) -> PatchError) -> Result<()> {
+    match expected == actual {
+        true => Ok(()),
+        false => Err(err(expected, actual))
+    }
+}
+
+fn moved(base: usize, offset: isize, limit: usize) -> Result<usize> {
+    let pos = base as isize + offset;
+    match pos >= 0 && (pos as usize) < limit {
+        true => Ok(pos as usize),
+        false => Err(PatchError::OutOfRom(pos.max(0) as usize))
+    }
+}
+
+pub fn apply(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>> {
+    if patch.len() < MAGIC.len() + FOOTER_SIZE {
+        return Err(PatchError::Truncated);
+    }
+    let actions_end = patch.len() - FOOTER_SIZE;
+    let footer = &patch[actions_end..];
+    checked(le32(&footer[8..]), crc32(&patch[..patch.len() - 4]),
+            |expected, actual| PatchError::PatchChecksum { expected, actual })?;
+    checked(le32(&footer[..4]), crc32(rom),
+            |expected, actual| PatchError::SourceChecksum { expected, actual })?;
+
+    let mut reader = Reader { data: &patch[..actions_end], pos: MAGIC.len() };
+    let source_size = reader.number()?;
+    if source_size != rom.len() {
+        return Err(PatchError::SourceSize(source_size));
+    }
+    let target_size = reader.number()?;
+    if target_size != rom.len() {
+        return Err(PatchError::TargetSize(target_size));
+    }
+    let metadata_size = reader.number()?;
+    reader.pos = reader.pos.checked_add(metadata_size)
+        .filter(|&pos| pos <= actions_end)
+        .ok_or(PatchError::Truncated)?;
+
+    let mut target = Vec::with_capacity(target_size);
+    let mut source_relative = 0;
+    let mut target_relative = 0;
+    while reader.pos < actions_end {
+        let data = reader.number()?;
+        let length = (data >> 2) + 1;
+        if target.len() + length > target_size {
+            return Err(PatchError::OutOfRom(target.len() + length - 1));
+        }
+        match data & 3 {
+            SOURCE_READ => {
+                let start = target.len();
+                target.extend_from_slice(&rom[start..start + length]);
+            }
+            TARGET_READ => {
+                for _ in 0..length {
+                    let b = reader.byte()?;
+                    target.push(b);
+                }
+            }
+            SOURCE_COPY => {
+                source_relative = moved(source_relative, reader.relative()?, rom.len())?;
+                if source_relative + length > rom.len() {
+                    return Err(PatchError::OutOfRom(source_relative + length - 1));
+                }
+                target.extend_from_slice(&rom[source_relative..source_relative + length]);
+                source_relative += length;
+            }
+            TARGET_COPY => {
+                target_relative = moved(target_relative, reader.relative()?, target.len())?;
+                // Can overlap the bytes that we are writing: copy one by one.
+                for _ in 0..length {
+                    let b = target[target_relative];
+                    target.push(b);
+                    target_relative += 1;
+                }
+            }
+            _ => unreachable!()
+        }
+    }
+    if target.len() != target_size {
+        return Err(PatchError::Truncated);
+    }
+    checked(le32(&footer[4..8]), crc32(&target),
+            |expected, actual| PatchError::TargetChecksum { expected, actual })?;
+    Ok(target)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn number(mut data: usize) -> Vec<u8> {
+        let mut out = Vec::new();
+        loop {
+            let x = (data & 0x7f) as u8;
+            data >>= 7;
+            if data == 0 {
+                out.push(0x80 | x);
+                return out;
+            }
+            out.push(x);
+            data -= 1;
+        }
+    }
+
+    fn le(v: u32) -> Vec<u8> {
+        (0..4).map(|i| (v >> (8 * i)) as u8).collect()
+    }
+
+    fn action(command: usize, length: usize) -> Vec<u8> {
+        number(((length - 1) << 2) | command)
+    }
+
+    fn build(source: &[u8], target: &[u8], actions: Vec<Vec<u8>>) -> Vec<u8> {
+        build_with_metadata(source, target, 0, actions)
+    }
+
+    fn build_with_metadata(source: &[u8], target: &[u8], metadata_size: usize,
+                           actions: Vec<Vec<u8>>) -> Vec<u8> {
+        let mut patch = MAGIC.to_vec();
+        patch.extend(number(source.len()));
+        patch.extend(number(target.len()));
+        patch.extend(number(metadata_size));
+        for a in actions {
+            patch.extend(a);
+        }
+        patch.extend(le(crc32(source)));
+        patch.extend(le(crc32(target)));
+        let crc = crc32(&patch);
+        patch.extend(le(crc));
+        patch
+    }
+
+    #[test]
+    fn number_round_trip() {
+        for &n in [0, 1, 127, 128, 300, 0x2000, 0x12345].iter() {
+            let mut reader = Reader { data: &number(n), pos: 0 };
+            assert_eq!(Ok(n), reader.number());
+        }
+    }
+
+    #[test]
+    fn number_overflow() {
+        let mut reader = Reader { data: &[0x7f; 16], pos: 0 };
+
+        assert_eq!(Err(PatchError::Number), reader.number());
+    }
+
+    #[test]
+    fn should_apply_all_commands() {
+        let source = [1, 2, 3, 4, 5, 6, 7, 8];
+        let target = [1, 2, 9, 9, 9, 9, 3, 4];
+        let mut target_read = action(TARGET_READ, 1);
+        target_read.push(9);
+        let mut target_copy = action(TARGET_COPY, 3);
+        target_copy.extend(number(2 << 1));
+        let mut source_copy = action(SOURCE_COPY, 2);
+        source_copy.extend(number(2 << 1));
+        let patch = build(&source, &target, vec![
+            action(SOURCE_READ, 2),
+            target_read,
+            target_copy,
+            source_copy,
+        ]);
+
+        assert_eq!(Ok(target.to_vec()), apply(&source, &patch));
+    }
+
+    #[test]
+    fn metadata_past_the_end() {
+        let source = [1, 2, 3, 4];
+
+        for &size in [1000, ::std::usize::MAX - 2].iter() {
+            let patch = build_with_metadata(&source, &source, size, vec![action(SOURCE_READ, 4)]);
+            assert_eq!(Err(PatchError::Truncated), apply(&source, &patch));
+        }
+    }
+
+    #[test]
+    fn should_reject_wrong_source() {
+        let source = [1, 2, 3, 4];
+        let patch = build(&source, &source, vec![action(SOURCE_READ, 4)]);
+
+        match apply(&[0, 0, 0, 0], &patch) {
+            Err(PatchError::SourceChecksum { .. }) => {}
+            r => panic!("Unexpected {:?}", r)
+        }
+    }
+
+    #[test]
+    fn should_reject_corrupted_patch() {
+        let source = [1, 2, 3, 4];
+        let mut patch = build(&source, &source, vec![action(SOURCE_READ, 4)]);
+        let last = patch.len() - 1;
+        patch[last] ^= 0xff;
+
+        match apply(&source, &patch) {
+            Err(PatchError::PatchChecksum { .. }) => {}
+            r => panic!("Unexpected {:?}", r)
+        }
+    }
+}
@@ -0,0 +1,79 @@
+use super::{Result, PatchError};
+
+pub const MAGIC: &[u8] = b"PATCH";
+const EOF: &[u8] = b"EOF";
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.pos + n > self.data.len() {
+            return Err(PatchError::Truncated);
+        }
+        let chunk = &self.data[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(chunk)
+    }
+
+    fn number(&mut self, n: usize) -> Result<usize> {
+        Ok(self.take(n)?.iter().fold(0, |acc, &b| (acc << 8) | b as usize))
+    }
+}
+
+pub fn apply(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>> {
+    let mut out = rom.to_vec();
+    let mut reader = Reader { data: patch, pos: MAGIC.len() };
+    loop {
+        if reader.take(EOF.len())? == EOF {
+            break;
+        }
+        reader.pos -= EOF.len();
+        let offset = reader.number(3)?;
+        let size = reader.number(2)?;
+        let (len, chunk) = match size {
+            0 => {
+                let len = reader.number(2)?;
+                let value = reader.take(1)?[0];
+                (len, vec![value; len])
+            }
+            _ => (size, reader.take(size)?.to_vec())
+        };
+        if offset + len > out.len() {
+            return Err(PatchError::OutOfRom(offset + len - 1));
+        }
+        out[offset..offset + len].copy_from_slice(&chunk);
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn should_apply_records() {
+        let rom = [0; 8];
+        let patch = b"PATCH\x00\x00\x01\x00\x02\xAA\xBB\x00\x00\x05\x00\x00\x00\x03\xCCEOF";
+
+        assert_eq!(Ok(vec![0x00, 0xAA, 0xBB, 0x00, 0x00, 0xCC, 0xCC, 0xCC]), apply(&rom, patch));
+    }
+
+    #[test]
+    fn should_not_write_out_of_rom() {
+        let rom = [0; 8];
+        let patch = b"PATCH\x00\x00\x07\x00\x02\xAA\xBBEOF";
+
+        assert_eq!(Err(PatchError::OutOfRom(8)), apply(&rom, patch));
+    }
+
+    #[test]
+    fn truncated() {
+        let rom = [0; 8];
+        let patch = b"PATCH\x00\x00\x01\x00\x02\xAA";
+
+        assert_eq!(Err(PatchError::Truncated), apply(&rom, patch));
+    }
+}
@@ -0,0 +1,147 @@
+//! Rom patches in IPS and BPS formats.
+//!
+//! Patches are applied to the base rom set (`invaders.h`, `.g`, `.f` and `.e`
+//! concatenated) and are validated against its CRC32: BPS patches carry the
+//! source checksum, for IPS ones we can just check that we are starting from
+//! the right base set. IPS patches chained after other patches
+//! (`apply_chained()`) cannot be checked at all.
+
+mod ips;
+mod bps;
+
+use std::fmt;
+
+/// CRC32 of the unpatched rom set.
+pub const BASE_CRC32: u32 = 0xb64c_a815;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Ips,
+    Bps,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PatchError {
+    UnknownFormat,
+    Truncated,
+    /// A BPS number that doesn't fit in `usize`.
+    Number,
+    OutOfRom(usize),
+    SourceSize(usize),
+    TargetSize(usize),
+    SourceChecksum { expected: u32, actual: u32 },
+    TargetChecksum { expected: u32, actual: u32 },
+    PatchChecksum { expected: u32, actual: u32 },
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PatchError::UnknownFormat => write!(f, "Unknown patch format"),
+            PatchError::Truncated => write!(f, "Truncated patch"),
+            PatchError::Number => write!(f, "Invalid number in patch"),
+            PatchError::OutOfRom(offset) => write!(f, "Patch write out of rom at {:06x}", offset),
+            PatchError::SourceSize(size) => write!(f, "Patch is for a rom of {} bytes", size),
+            PatchError::TargetSize(size) => write!(f, "Patch produce a rom of {} bytes", size),
+            PatchError::SourceChecksum { expected, actual } =>
+                write!(f, "Wrong base rom: crc32 {:08x} instead of {:08x}", actual, expected),
+            PatchError::TargetChecksum { expected, actual } =>
+                write!(f, "Wrong patched rom: crc32 {:08x} instead of {:08x}", actual, expected),
+            PatchError::PatchChecksum { expected, actual } =>
+                write!(f, "Corrupted patch: crc32 {:08x} instead of {:08x}", actual, expected),
+        }
+    }
+}
+
+pub type Result<T> = ::std::result::Result<T, PatchError>;
+
+pub fn format(patch: &[u8]) -> Option<Format> {
+    if patch.starts_with(ips::MAGIC) {
+        Some(Format::Ips)
+    } else if patch.starts_with(bps::MAGIC) {
+        Some(Format::Bps)
+    } else {
+        None
+    }
+}
+
+/// Apply `patch` to the base rom set `rom` in place. `rom` is untouched if
+/// the patch cannot be applied.
+pub fn apply(rom: &mut [u8], patch: &[u8]) -> Result<Format> {
+    apply_with(rom, patch, true)
+}
+
+/// Apply `patch` to a `rom` that was already patched: IPS patches are not
+/// checked against the base rom set.
+pub fn apply_chained(rom: &mut [u8], patch: &[u8]) -> Result<Format> {
+    apply_with(rom, patch, false)
+}
+
+fn apply_with(rom: &mut [u8], patch: &[u8], base: bool) -> Result<Format> {
+    let format = format(patch).ok_or(PatchError::UnknownFormat)?;
+    let patched = match format {
+        Format::Ips => {
+            if base {
+                check_base(rom)?;
+            }
+            ips::apply(rom, patch)?
+        }
+        Format::Bps => bps::apply(rom, patch)?,
+    };
+    rom.copy_from_slice(&patched);
+    Ok(format)
+}
+
+fn check_base(rom: &[u8]) -> Result<()> {
+    let actual = crc32(rom);
+    match actual == BASE_CRC32 {
+        true => Ok(()),
+        false => Err(PatchError::SourceChecksum { expected: BASE_CRC32, actual })
+    }
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0, |crc, &b| {
+        (0..8).fold(crc ^ b as u32, |crc, _|
+            match crc & 1 {
+                1 => (crc >> 1) ^ 0xEDB8_8320,
+                _ => crc >> 1,
+            },
+        )
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(0xCBF4_3926, crc32(b"123456789"));
+    }
+
+    #[test]
+    fn unknown_format() {
+        let mut rom = [0; 16];
+
+        assert_eq!(Err(PatchError::UnknownFormat), apply(&mut rom, b"NOPATCH"));
+    }
+
+    #[test]
+    fn ips_should_validate_base_rom() {
+        let mut rom = [0; 16];
+
+        match apply(&mut rom, b"PATCHEOF") {
+            Err(PatchError::SourceChecksum { .. }) => {}
+            r => panic!("Unexpected {:?}", r)
+        }
+    }
+
+    #[test]
+    fn chained_ips_should_skip_base_check() {
+        let mut rom = [0; 16];
+
+        assert_eq!(Ok(Format::Ips), apply_chained(&mut rom, b"PATCH\x00\x00\x02\x00\x01\xAAEOF"));
+        assert_eq!(0xAA, rom[2]);
+    }
+}
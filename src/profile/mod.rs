@@ -0,0 +1,356 @@
+//! Cycle profiler.
+//!
+//! `ProfileHook` is the cpu hook (see the `Cpu` alias): it looks at every
+//! instruction before it's executed and `run_till()` accounts it the clocks
+//! it took. Clocks are accounted both to the instruction address (flat
+//! profile) and to the stack of routines that are running (folded stacks).
+//!
+//! Routines are identified by their entry address and tracked by looking at
+//! how the stack pointer changes between two instructions: when the pc jumps
+//! and 2 bytes are pushed it's a call (`CALL`, `Cxx`, `RST` or an interrupt),
+//! when it jumps and 2 bytes are popped it's a return.
+//!
+//! The accounting is split by half frame: `Half::Irq1` is the time from
+//! `Irq1` to `Irq2` and `Half::Irq2` the one from `Irq2` to the next `Irq1`.
+
+use std::cell::{Ref, RefCell};
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use rs8080::{Address, cpu::{Instruction, Periods, State}, hook::CpuHook};
+
+const RESET: Address = 0x0000;
+/// The root of the interned stacks: the code run from reset.
+const ROOT: usize = 0;
+
+/// The interrupt that started a half frame.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Half {
+    Irq1 = 0,
+    Irq2 = 1,
+}
+
+impl Half {
+    pub fn name(self) -> &'static str {
+        match self {
+            Half::Irq1 => "irq1",
+            Half::Irq2 => "irq2",
+        }
+    }
+}
+
+const HALVES: [Half; 2] = [Half::Irq1, Half::Irq2];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Frame {
+    /// Interned stack of routines up to this one.
+    stack: usize,
+    sp: Address,
+}
+
+pub struct Profiler {
+    frames: Vec<Frame>,
+    /// Pc and sp of the last instruction.
+    last: Option<(Address, Address)>,
+    /// The instruction that waits for its clocks.
+    pending: Option<Address>,
+    /// Interned stacks: parent stack and routine.
+    stacks: Vec<(usize, Address)>,
+    children: HashMap<(usize, Address), usize>,
+    folded: Vec<[u64; 2]>,
+    by_pc: HashMap<Address, u64>,
+    by_routine: HashMap<Address, [u64; 2]>,
+    half: Half,
+    halves: [u64; 2],
+    /// Frames started (`Irq1` taken).
+    frame_count: u64,
+    total: u64,
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Profiler {
+            frames: vec![Frame { stack: ROOT, sp: 0xFFFF }],
+            last: None,
+            pending: None,
+            stacks: vec![(ROOT, RESET)],
+            children: Default::default(),
+            folded: vec![[0; 2]],
+            by_pc: Default::default(),
+            by_routine: Default::default(),
+            // The machine starts before the first Irq1
+            half: Half::Irq2,
+            halves: [0; 2],
+            frame_count: 0,
+            total: 0,
+        }
+    }
+}
+
+impl Profiler {
+    /// The cpu is going to execute the instruction at `pc` with stack
+    /// pointer `sp`.
+    pub fn instruction(&mut self, pc: Address, sp: Address) {
+        if let Some((last_pc, last_sp)) = self.last {
+            let jumped = pc != last_pc.wrapping_add(1);
+            if jumped && sp == last_sp.wrapping_sub(2) {
+                self.enter(pc, sp);
+            } else if jumped && sp == last_sp.wrapping_add(2) {
+                self.leave(sp);
+            }
+        }
+        self.last = Some((pc, sp));
+        self.pending = Some(pc);
+    }
+
+    /// The last instruction took `clocks`.
+    pub fn account(&mut self, clocks: u64) {
+        let pc = match self.pending.take() {
+            Some(pc) => pc,
+            None => return,
+        };
+        let half = self.half as usize;
+        let stack = self.stack();
+        *self.by_pc.entry(pc).or_insert(0) += clocks;
+        self.by_routine.entry(self.current()).or_insert([0; 2])[half] += clocks;
+        self.folded[stack][half] += clocks;
+        self.halves[half] += clocks;
+        self.total += clocks;
+    }
+
+    /// The cpu took an interrupt: a new half frame starts.
+    pub fn irq(&mut self, half: Half) {
+        self.half = half;
+        if half == Half::Irq1 {
+            self.frame_count += 1;
+        }
+    }
+
+    fn stack(&self) -> usize {
+        self.frames.last().map(|f| f.stack).unwrap_or(ROOT)
+    }
+
+    fn intern(&mut self, parent: usize, routine: Address) -> usize {
+        if let Some(&stack) = self.children.get(&(parent, routine)) {
+            return stack;
+        }
+        let stack = self.stacks.len();
+        self.stacks.push((parent, routine));
+        self.folded.push([0; 2]);
+        self.children.insert((parent, routine), stack);
+        stack
+    }
+
+    fn enter(&mut self, routine: Address, sp: Address) {
+        let parent = self.stack();
+        let stack = self.intern(parent, routine);
+        self.frames.push(Frame { stack, sp });
+    }
+
+    fn leave(&mut self, sp: Address) {
+        // The ROM can drop frames by resetting the stack pointer: we remove
+        // all frames that are above the new stack pointer.
+        while self.frames.len() > 1 && self.frames.last().map(|f| f.sp < sp).unwrap_or(false) {
+            self.frames.pop();
+        }
+    }
+
+    pub fn current(&self) -> Address {
+        self.stacks[self.stack()].1
+    }
+
+    pub fn depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// Clocks spent in the `half` frames.
+    pub fn half_total(&self, half: Half) -> u64 {
+        self.halves[half as usize]
+    }
+
+    pub fn clear(&mut self) {
+        *self = Default::default();
+    }
+
+    /// Flat profile: the clocks of each half frame (total and average per
+    /// frame), then one line for each routine sorted by spent clocks
+    /// (`routine clocks percent irq1 irq2`) followed by one for each address
+    /// (`pc clocks percent`).
+    pub fn flat(&self) -> String {
+        let mut out = String::new();
+        let total = self.total.max(1) as f64;
+        writeln!(out, "# half clocks clocks/frame %").unwrap();
+        for &half in HALVES.iter() {
+            let clocks = self.halves[half as usize];
+            writeln!(out, "{} {} {} {:.2}", half.name(), clocks, clocks / self.frame_count.max(1),
+                     clocks as f64 * 100.0 / total).unwrap();
+        }
+        writeln!(out, "# routine clocks % irq1 irq2").unwrap();
+        let mut routines = self.by_routine.iter()
+            .map(|(&routine, halves)| (routine, halves[0] + halves[1], halves))
+            .collect::<Vec<_>>();
+        routines.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        for (routine, clocks, halves) in routines {
+            writeln!(out, "{:04x} {} {:.2} {} {}", routine, clocks, clocks as f64 * 100.0 / total,
+                     halves[0], halves[1]).unwrap();
+        }
+        writeln!(out, "# pc clocks %").unwrap();
+        let mut pcs = self.by_pc.iter().collect::<Vec<_>>();
+        pcs.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        for (pc, clocks) in pcs {
+            writeln!(out, "{:04x} {} {:.2}", pc, clocks, *clocks as f64 * 100.0 / total).unwrap();
+        }
+        out
+    }
+
+    fn stack_name(&self, mut stack: usize) -> String {
+        let mut routines = vec![format!("{:04x}", self.stacks[stack].1)];
+        while stack != ROOT {
+            stack = self.stacks[stack].0;
+            routines.push(format!("{:04x}", self.stacks[stack].1));
+        }
+        routines.reverse();
+        routines.join(";")
+    }
+
+    /// Folded stacks rooted at the half frame (`irq1;0000;0ab1;1a5f clocks`),
+    /// the input format of `flamegraph.pl` and compatible tools.
+    pub fn folded(&self) -> String {
+        let mut entries = Vec::new();
+        for (stack, clocks) in self.folded.iter().enumerate() {
+            let name = self.stack_name(stack);
+            for &half in HALVES.iter().filter(|&&h| clocks[h as usize] > 0) {
+                entries.push((format!("{};{}", half.name(), name), clocks[half as usize]));
+            }
+        }
+        entries.sort();
+        let mut out = String::new();
+        for (stack, clocks) in entries {
+            writeln!(out, "{} {}", stack, clocks).unwrap();
+        }
+        out
+    }
+}
+
+/// The cpu hook: feed the profiler, when there is one, with every
+/// instruction.
+#[derive(Default)]
+pub struct ProfileHook {
+    profiler: RefCell<Option<Profiler>>,
+}
+
+impl ProfileHook {
+    pub fn profiler(&self) -> Ref<Option<Profiler>> {
+        self.profiler.borrow()
+    }
+
+    pub fn set_profiler(&self, profiler: Option<Profiler>) {
+        *self.profiler.borrow_mut() = profiler;
+    }
+
+    pub fn account(&self, clocks: u64) {
+        if let Some(ref mut profiler) = *self.profiler.borrow_mut() {
+            profiler.account(clocks);
+        }
+    }
+
+    pub fn irq(&self, half: Half) {
+        if let Some(ref mut profiler) = *self.profiler.borrow_mut() {
+            profiler.irq(half);
+        }
+    }
+}
+
+impl CpuHook for ProfileHook {
+    fn handle(&self, state: &State, _instruction: &Instruction) -> Option<Periods> {
+        if let Some(ref mut profiler) = *self.profiler.borrow_mut() {
+            profiler.instruction(state.pc.val, state.sp.val);
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn profiler() -> Profiler {
+        Profiler::default()
+    }
+
+    fn step(p: &mut Profiler, pc: Address, sp: Address, clocks: u64) {
+        p.instruction(pc, sp);
+        p.account(clocks);
+    }
+
+    #[test]
+    fn should_track_call_and_ret() {
+        let mut p = profiler();
+
+        step(&mut p, 0x0100, 0x2400, 4);
+        // CALL 0x0200
+        step(&mut p, 0x0101, 0x2400, 17);
+        step(&mut p, 0x0200, 0x23fe, 7);
+        assert_eq!(0x0200, p.current());
+        // RET
+        step(&mut p, 0x0201, 0x23fe, 10);
+        step(&mut p, 0x0104, 0x2400, 4);
+
+        assert_eq!(RESET, p.current());
+        assert_eq!(42, p.total());
+        assert_eq!(Some(&[0, 17]), p.by_routine.get(&0x0200));
+    }
+
+    #[test]
+    fn push_and_pop_are_not_calls() {
+        let mut p = profiler();
+
+        step(&mut p, 0x0100, 0x2400, 11);
+        step(&mut p, 0x0101, 0x23fe, 10);
+        step(&mut p, 0x0102, 0x2400, 4);
+
+        assert_eq!(1, p.depth());
+    }
+
+    #[test]
+    fn should_detect_interrupts() {
+        let mut p = profiler();
+
+        step(&mut p, 0x0100, 0x2400, 4);
+        p.irq(Half::Irq1);
+        step(&mut p, 0x0008, 0x23fe, 11);
+
+        assert_eq!(0x0008, p.current());
+        assert_eq!(4, p.half_total(Half::Irq2));
+        assert_eq!(11, p.half_total(Half::Irq1));
+    }
+
+    #[test]
+    fn folded_stacks_should_be_split_by_half_frame() {
+        let mut p = profiler();
+
+        step(&mut p, 0x0100, 0x2400, 17);
+        step(&mut p, 0x0200, 0x23fe, 7);
+        p.irq(Half::Irq1);
+        step(&mut p, 0x0201, 0x23fe, 5);
+
+        assert_eq!("irq1;0000;0200 5\nirq2;0000 17\nirq2;0000;0200 7\n", p.folded());
+    }
+
+    #[test]
+    fn stacks_should_be_interned() {
+        let mut p = profiler();
+
+        for _ in 0..3 {
+            step(&mut p, 0x0100, 0x2400, 17);
+            step(&mut p, 0x0200, 0x23fe, 10);
+        }
+
+        assert_eq!(2, p.stacks.len());
+        assert_eq!("irq2;0000 51\nirq2;0000;0200 30\n", p.folded());
+    }
+}
@@ -0,0 +1,118 @@
+//! Where the ROM keeps the game state in ram.
+//!
+//! Scores and credits are BCD: scores are two bytes, least significant
+//! first (`0x20F8 = 0x50, 0x20F9 = 0x12` is 1250).
+
+use rs8080::{Address, Byte};
+
+use SpaceInvaders;
+
+pub const CREDITS: Address = 0x20EB;
+/// 1 while a game is running, 0 in attract mode.
+pub const GAME_MODE: Address = 0x20EF;
+pub const HI_SCORE: Address = 0x20F4;
+pub const P1_SCORE: Address = 0x20F8;
+pub const P2_SCORE: Address = 0x20FC;
+/// Waves cleared by the player.
+pub const P1_RACK: Address = 0x21FE;
+pub const P2_RACK: Address = 0x22FE;
+pub const P1_SHIPS: Address = 0x21FF;
+pub const P2_SHIPS: Address = 0x22FF;
+
+/// The most ships a player can have: 6 from dip switches plus the bonus.
+pub const MAX_SHIPS: Byte = 7;
+
+pub fn is_bcd(b: Byte) -> bool {
+    b & 0x0F <= 9 && b >> 4 <= 9
+}
+
+/// Decode little endian BCD `bytes`: `None` if they are not valid BCD.
+pub fn bcd(bytes: &[Byte]) -> Option<u32> {
+    bytes.iter().rev().try_fold(0, |acc, &b| match is_bcd(b) {
+        true => Some(acc * 100 + (b >> 4) as u32 * 10 + (b & 0x0F) as u32),
+        false => None,
+    })
+}
+
+/// Encode `value` (mod 10^(2 * len)) in `len` little endian BCD bytes.
+pub fn to_bcd(value: u32, len: usize) -> Vec<Byte> {
+    (0..len)
+        .scan(value, |v, _| {
+            let b = ((*v % 100 / 10) << 4 | *v % 10) as Byte;
+            *v /= 100;
+            Some(b)
+        })
+        .collect()
+}
+
+/// The game variables read from a running machine.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GameState {
+    pub playing: bool,
+    pub credits: u32,
+    pub hi_score: u32,
+    pub scores: [u32; 2],
+    pub ships: [Byte; 2],
+    pub racks: [Byte; 2],
+}
+
+impl GameState {
+    /// Read the state and check it's consistent: return the broken invariant
+    /// as error.
+    pub fn read(si: &SpaceInvaders) -> Result<Self, String> {
+        let peek = |address: Address| si.peek(address).unwrap_or(0);
+        let bcd_at = |address: Address, len: usize, what: &str| {
+            let bytes = (0..len as Address).map(|i| peek(address + i)).collect::<Vec<_>>();
+            bcd(&bytes).ok_or_else(|| format!("{} is not BCD: {:02x?}", what, bytes))
+        };
+        let state = GameState {
+            playing: peek(GAME_MODE) != 0,
+            credits: bcd_at(CREDITS, 1, "credits")?,
+            hi_score: bcd_at(HI_SCORE, 2, "hi score")?,
+            scores: [bcd_at(P1_SCORE, 2, "P1 score")?, bcd_at(P2_SCORE, 2, "P2 score")?],
+            ships: [peek(P1_SHIPS), peek(P2_SHIPS)],
+            racks: [peek(P1_RACK), peek(P2_RACK)],
+        };
+        if let Some(ships) = state.ships.iter().find(|&&s| s > MAX_SHIPS) {
+            return Err(format!("{} ships", ships));
+        }
+        Ok(state)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rstest::rstest_parametrize;
+
+    #[rstest_parametrize(
+    bytes, expected,
+    case(Unwrap("&[0x50, 0x12]"), Unwrap("Some(1250)")),
+    case(Unwrap("&[0x99]"), Unwrap("Some(99)")),
+    case(Unwrap("&[0x0A]"), Unwrap("None")),
+    case(Unwrap("&[0x00, 0xA0]"), Unwrap("None")),
+    )]
+    fn decode_bcd(bytes: &[Byte], expected: Option<u32>) {
+        assert_eq!(expected, bcd(bytes));
+    }
+
+    #[test]
+    fn encode_bcd() {
+        assert_eq!(vec![0x50, 0x12], to_bcd(1250, 2));
+        assert_eq!(vec![0x45], to_bcd(12345, 1));
+    }
+
+    #[test]
+    fn boot_state_should_be_valid() {
+        let mut game = ::Game::new();
+        let mut si = game.space_invaders();
+        for _ in 0..200 {
+            si.next_frame();
+        }
+
+        let state = GameState::read(&si).unwrap();
+
+        assert!(!state.playing);
+        assert_eq!(0, state.credits);
+    }
+}
@@ -0,0 +1,317 @@
+//! Software CRT simulation.
+//!
+//! A post process for the RGBA framebuffer (plain or upscaled) that runs on
+//! the cpu. Stages, in order:
+//!
+//! * persistence: the phosphors don't turn off at once (see `Phosphor`)
+//! * moon backdrop: the original cabinet reflects a painted moon on the glass
+//!   in front of the monitor, so it shows just where the screen is dark
+//! * bloom: bright pixels leak on their neighbors
+//! * scanlines: the last row of every source line is darkened
+//! * barrel curvature: the image is bent like the tube glass
+//!
+//! Every stage can be disabled by setting it to 0 (`moon` to `false`).
+
+use rs8080::Byte;
+
+use super::phosphor::Phosphor;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CrtSettings {
+    /// How much the scanlines are darkened: 0.0 - 1.0.
+    pub scanlines: f32,
+    /// Bloom strength: 0.0 - 1.0.
+    pub bloom: f32,
+    /// Phosphor half life in milliseconds: 0.0 - 100.0.
+    pub persistence: f32,
+    /// Barrel distortion: 0.0 (flat) - 0.5.
+    pub curvature: f32,
+    pub moon: bool,
+}
+
+impl Default for CrtSettings {
+    fn default() -> Self {
+        CrtSettings {
+            scanlines: 0.4,
+            bloom: 0.3,
+            persistence: 8.0,
+            curvature: 0.08,
+            moon: false,
+        }
+    }
+}
+
+impl CrtSettings {
+    fn clamped(self) -> Self {
+        let clamp = |v: f32, max: f32| if v.is_nan() { 0.0 } else { v.max(0.0).min(max) };
+        CrtSettings {
+            scanlines: clamp(self.scanlines, 1.0),
+            bloom: clamp(self.bloom, 1.0),
+            persistence: clamp(self.persistence, 100.0),
+            curvature: clamp(self.curvature, 0.5),
+            moon: self.moon,
+        }
+    }
+}
+
+const CHANNELS: usize = 3;
+
+/// The moon painted on the backdrop at normalized screen coordinates.
+fn moon(u: f32, v: f32) -> [f32; CHANNELS] {
+    const CENTER: (f32, f32) = (0.62, 0.38);
+    const RADIUS: f32 = 0.22;
+    const CRATERS: [(f32, f32, f32); 3] = [(0.55, 0.33, 0.05), (0.68, 0.45, 0.035), (0.66, 0.30, 0.025)];
+    let sky = [8.0, 10.0, 30.0];
+    let d = ((u - CENTER.0).powi(2) + (v - CENTER.1).powi(2)).sqrt();
+    if d > RADIUS {
+        return sky;
+    }
+    // Lit from the left
+    let mut light = 0.55 + 0.45 * ((CENTER.0 - u) / RADIUS).max(-1.0);
+    if CRATERS.iter().any(|&(cx, cy, r)| (u - cx).powi(2) + (v - cy).powi(2) < r * r) {
+        light *= 0.75;
+    }
+    [110.0 * light, 105.0 * light, 95.0 * light]
+}
+
+pub struct Crt {
+    settings: CrtSettings,
+    width: usize,
+    height: usize,
+    /// Phosphors brightness (persistence stage output).
+    glow: Phosphor,
+    work: Vec<f32>,
+    rgba: Vec<Byte>,
+}
+
+impl Crt {
+    pub fn new(settings: CrtSettings) -> Self {
+        Crt {
+            settings: settings.clamped(),
+            width: 0,
+            height: 0,
+            glow: Phosphor::new(0, settings.persistence),
+            work: Vec::new(),
+            rgba: Vec::new(),
+        }
+    }
+
+    pub fn settings(&self) -> CrtSettings {
+        self.settings
+    }
+
+    pub fn set_settings(&mut self, settings: CrtSettings) {
+        self.settings = settings.clamped();
+        self.glow.set_half_life(self.settings.persistence);
+    }
+
+    /// Process a `width`x`height` `rgba` frame where every source line is
+    /// `line_height` rows tall (the upscale factor); `elapsed` is the
+    /// emulated time since the last frame in milliseconds.
+    pub fn process(&mut self, rgba: &[Byte], width: usize, height: usize, line_height: usize,
+                   elapsed: f32) -> &[Byte] {
+        if self.width != width || self.height != height {
+            self.width = width;
+            self.height = height;
+            self.glow = Phosphor::new(width * height * CHANNELS, self.settings.persistence);
+            self.rgba = vec![0; width * height * 4];
+        }
+        let input = rgba.iter().enumerate()
+            .filter(|&(i, _)| i % 4 < CHANNELS)
+            .map(|(_, &v)| v as f32);
+        self.glow.expose(input, elapsed);
+        self.work = self.glow.levels().to_vec();
+        if self.settings.moon {
+            self.backdrop();
+        }
+        if self.settings.bloom > 0.0 {
+            self.bloom();
+        }
+        if self.settings.scanlines > 0.0 {
+            self.scanlines(line_height.max(1));
+        }
+        self.output();
+        &self.rgba
+    }
+
+    pub fn rgba(&self) -> &[Byte] {
+        &self.rgba
+    }
+
+    fn backdrop(&mut self) {
+        let (w, h) = (self.width as f32, self.height as f32);
+        for (i, pixel) in self.work.chunks_mut(CHANNELS).enumerate() {
+            let (x, y) = (i % self.width, i / self.width);
+            let backdrop = moon(x as f32 / w, y as f32 / h);
+            for c in 0..CHANNELS {
+                pixel[c] = pixel[c].max(backdrop[c]);
+            }
+        }
+    }
+
+    fn bloom(&mut self) {
+        let (w, h) = (self.width, self.height);
+        let strength = self.settings.bloom;
+        let src = self.work.clone();
+        for y in 0..h {
+            for x in 0..w {
+                let mut sum = [0.0; CHANNELS];
+                for ny in y.saturating_sub(1)..(y + 2).min(h) {
+                    for nx in x.saturating_sub(1)..(x + 2).min(w) {
+                        let pos = (ny * w + nx) * CHANNELS;
+                        for c in 0..CHANNELS {
+                            sum[c] += src[pos + c];
+                        }
+                    }
+                }
+                let pos = (y * w + x) * CHANNELS;
+                for c in 0..CHANNELS {
+                    self.work[pos + c] += sum[c] / 9.0 * strength;
+                }
+            }
+        }
+    }
+
+    fn scanlines(&mut self, line_height: usize) {
+        let dark = 1.0 - self.settings.scanlines;
+        let row = self.width * CHANNELS;
+        for (y, line) in self.work.chunks_mut(row).enumerate() {
+            // With 1 row for line we darken every other row
+            let scanline = match line_height {
+                1 => y % 2 == 1,
+                n => y % n == n - 1,
+            };
+            if scanline {
+                for v in line.iter_mut() {
+                    *v *= dark;
+                }
+            }
+        }
+    }
+
+    /// Write `work` in `rgba` bending it by curvature.
+    fn output(&mut self) {
+        let (w, h) = (self.width, self.height);
+        let k = self.settings.curvature;
+        for y in 0..h {
+            for x in 0..w {
+                let dst = (y * w + x) * 4;
+                let src = match k > 0.0 {
+                    true => barrel(x, y, w, h, k),
+                    false => Some((x, y)),
+                };
+                let color = match src {
+                    Some((sx, sy)) => {
+                        let pos = (sy * w + sx) * CHANNELS;
+                        [self.work[pos], self.work[pos + 1], self.work[pos + 2]]
+                    }
+                    None => [0.0; CHANNELS],
+                };
+                for c in 0..CHANNELS {
+                    self.rgba[dst + c] = color[c].max(0.0).min(255.0) as Byte;
+                }
+                self.rgba[dst + 3] = 0xFF;
+            }
+        }
+    }
+}
+
+/// Source pixel of the output (`x`, `y`) with barrel distortion `k`, `None`
+/// if it falls out of the tube.
+fn barrel(x: usize, y: usize, w: usize, h: usize, k: f32) -> Option<(usize, usize)> {
+    let u = (x as f32 + 0.5) / w as f32 * 2.0 - 1.0;
+    let v = (y as f32 + 0.5) / h as f32 * 2.0 - 1.0;
+    let r2 = u * u + v * v;
+    let (su, sv) = (u * (1.0 + k * r2), v * (1.0 + k * r2));
+    if su.abs() > 1.0 || sv.abs() > 1.0 {
+        return None;
+    }
+    let sx = ((su + 1.0) / 2.0 * w as f32) as usize;
+    let sy = ((sv + 1.0) / 2.0 * h as f32) as usize;
+    Some((sx.min(w - 1), sy.min(h - 1)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn flat() -> CrtSettings {
+        CrtSettings { scanlines: 0.0, bloom: 0.0, persistence: 0.0, curvature: 0.0, moon: false }
+    }
+
+    fn frame(w: usize, h: usize, lit: &[(usize, usize)]) -> Vec<Byte> {
+        let mut rgba = vec![0; w * h * 4];
+        for &(x, y) in lit {
+            let pos = (y * w + x) * 4;
+            rgba[pos..pos + 3].copy_from_slice(&[255, 255, 255]);
+        }
+        rgba
+    }
+
+    fn red(rgba: &[Byte], w: usize, x: usize, y: usize) -> Byte {
+        rgba[(y * w + x) * 4]
+    }
+
+    #[test]
+    fn all_disabled_should_copy_the_frame() {
+        let input = frame(4, 4, &[(1, 2)]);
+        let mut input_opaque = input.clone();
+        input_opaque.chunks_mut(4).for_each(|p| p[3] = 0xFF);
+
+        assert_eq!(input_opaque.as_slice(), Crt::new(flat()).process(&input, 4, 4, 1, 16.0));
+    }
+
+    #[test]
+    fn persistence_should_keep_decayed_pixels() {
+        let mut crt = Crt::new(CrtSettings { persistence: 10.0, ..flat() });
+
+        crt.process(&frame(4, 4, &[(1, 1)]), 4, 4, 1, 10.0);
+        let out = crt.process(&frame(4, 4, &[]), 4, 4, 1, 10.0).to_vec();
+
+        assert_eq!(127, red(&out, 4, 1, 1));
+    }
+
+    #[test]
+    fn scanlines_should_darken_last_row_of_every_line() {
+        let lit = (0..4).flat_map(|y| (0..4).map(move |x| (x, y))).collect::<Vec<_>>();
+        let mut crt = Crt::new(CrtSettings { scanlines: 0.5, ..flat() });
+
+        let out = crt.process(&frame(4, 4, &lit), 4, 4, 2, 16.0).to_vec();
+
+        assert_eq!(vec![255, 127, 255, 127], (0..4).map(|y| red(&out, 4, 0, y)).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn bloom_should_leak_on_neighbors() {
+        let mut crt = Crt::new(CrtSettings { bloom: 0.9, ..flat() });
+
+        let out = crt.process(&frame(4, 4, &[(1, 1)]), 4, 4, 1, 16.0).to_vec();
+
+        assert_eq!(25, red(&out, 4, 2, 2));
+        assert_eq!(0, red(&out, 4, 3, 3));
+    }
+
+    #[test]
+    fn curvature_should_blank_corners() {
+        let lit = (0..8).flat_map(|y| (0..8).map(move |x| (x, y))).collect::<Vec<_>>();
+        let mut crt = Crt::new(CrtSettings { curvature: 0.3, ..flat() });
+
+        let out = crt.process(&frame(8, 8, &lit), 8, 8, 1, 16.0).to_vec();
+
+        assert_eq!(0, red(&out, 8, 0, 0));
+        assert_eq!(255, red(&out, 8, 4, 4));
+    }
+
+    #[test]
+    fn moon_should_show_on_dark_areas_only() {
+        let mut crt = Crt::new(CrtSettings { moon: true, ..flat() });
+        // Moon center
+        let (w, h) = (100, 100);
+
+        let out = crt.process(&frame(w, h, &[(62, 38)]), w, h, 1, 16.0).to_vec();
+
+        assert_eq!(255, red(&out, w, 62, 38));
+        assert!(red(&out, w, 63, 38) > 0);
+        assert_eq!(8, red(&out, w, 5, 95));
+    }
+}
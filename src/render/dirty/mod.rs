@@ -0,0 +1,160 @@
+//! Video ram dirty tracking.
+//!
+//! Every video ram row (32 bytes) is a screen column, so the map keeps a
+//! 32 bits mask for each of them: bit `n` is set when byte `n` of the row was
+//! written.
+
+use super::{WIDTH, HEIGHT};
+
+pub const ROW_BYTES: usize = HEIGHT / 8;
+pub const ROWS: usize = WIDTH;
+
+/// A screen area: `x`, `y` is the top left corner.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Rect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Rect {
+    pub fn screen() -> Self {
+        Rect { x: 0, y: 0, width: WIDTH, height: HEIGHT }
+    }
+
+    /// The same area on the screen rotated by 180°.
+    pub fn flipped(&self) -> Self {
+        Rect {
+            x: WIDTH - self.x - self.width,
+            y: HEIGHT - self.y - self.height,
+            ..*self
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct DirtyMap {
+    rows: [u32; ROWS],
+}
+
+impl Default for DirtyMap {
+    fn default() -> Self {
+        DirtyMap { rows: [0; ROWS] }
+    }
+}
+
+impl DirtyMap {
+    /// Mark the byte at video ram `offset`.
+    #[inline]
+    pub fn mark(&mut self, offset: usize) {
+        self.rows[offset / ROW_BYTES] |= 1 << (offset % ROW_BYTES);
+    }
+
+    pub fn mark_all(&mut self) {
+        self.rows = [!0; ROWS];
+    }
+
+    pub fn clear(&mut self) {
+        self.rows = [0; ROWS];
+    }
+
+    pub fn is_dirty(&self, offset: usize) -> bool {
+        self.rows[offset / ROW_BYTES] & (1 << (offset % ROW_BYTES)) != 0
+    }
+
+    pub fn is_clean(&self) -> bool {
+        self.rows.iter().all(|&r| r == 0)
+    }
+
+    /// The dirty bytes masks, one for each video ram row.
+    pub fn rows(&self) -> &[u32] {
+        &self.rows
+    }
+
+    /// Dirty screen areas (not flipped): adjacent dirty columns are merged in
+    /// the same rectangle.
+    pub fn rects(&self) -> Vec<Rect> {
+        let mut rects: Vec<Rect> = Vec::new();
+        let mut open = false;
+        for (x, &mask) in self.rows.iter().enumerate() {
+            if mask == 0 {
+                open = false;
+                continue;
+            }
+            // Bytes are bottom to top
+            let top = HEIGHT - 8 * (ROW_BYTES - mask.leading_zeros() as usize);
+            let bottom = HEIGHT - 8 * mask.trailing_zeros() as usize;
+            if open {
+                if let Some(r) = rects.last_mut() {
+                    let end = (r.y + r.height).max(bottom);
+                    r.y = r.y.min(top);
+                    r.height = end - r.y;
+                    r.width += 1;
+                    continue;
+                }
+            }
+            rects.push(Rect { x, y: top, width: 1, height: bottom - top });
+            open = true;
+        }
+        rects
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn should_mark_bytes() {
+        let mut map = DirtyMap::default();
+
+        map.mark(33);
+
+        assert!(map.is_dirty(33));
+        assert!(!map.is_dirty(32));
+        assert_eq!(0x02, map.rows()[1]);
+    }
+
+    #[test]
+    fn first_byte_is_the_bottom_of_first_column() {
+        let mut map = DirtyMap::default();
+
+        map.mark(0);
+
+        assert_eq!(vec![Rect { x: 0, y: HEIGHT - 8, width: 1, height: 8 }], map.rects());
+    }
+
+    #[test]
+    fn adjacent_columns_should_be_merged() {
+        let mut map = DirtyMap::default();
+
+        map.mark(10 * ROW_BYTES + 31);
+        map.mark(11 * ROW_BYTES + 30);
+        map.mark(13 * ROW_BYTES);
+
+        assert_eq!(vec![
+            Rect { x: 10, y: 0, width: 2, height: 16 },
+            Rect { x: 13, y: HEIGHT - 8, width: 1, height: 8 },
+        ], map.rects());
+    }
+
+    #[test]
+    fn clear_and_mark_all() {
+        let mut map = DirtyMap::default();
+
+        map.mark_all();
+        assert_eq!(vec![Rect::screen()], map.rects());
+
+        map.clear();
+        assert!(map.is_clean());
+        assert!(map.rects().is_empty());
+    }
+
+    #[test]
+    fn flip_rect() {
+        let r = Rect { x: 0, y: HEIGHT - 8, width: 1, height: 8 };
+
+        assert_eq!(Rect { x: WIDTH - 1, y: 0, width: 1, height: 8 }, r.flipped());
+    }
+}
@@ -0,0 +1,217 @@
+//! Render the video ram to an RGBA framebuffer.
+//!
+//! The monitor is mounted rotated by 90° counterclockwise: video ram rows
+//! (32 bytes, 256 pixels, least significant bit first) are the screen columns
+//! from bottom to top. The colors come from the gel overlay stuck on the
+//! original monitor.
+
+use rs8080::Byte;
+
+pub mod dirty;
+pub mod scale;
+pub mod crt;
+pub mod phosphor;
+pub mod png;
+
+pub use self::dirty::{DirtyMap, Rect};
+
+/// Screen size (rotated).
+pub const WIDTH: usize = 224;
+pub const HEIGHT: usize = 256;
+
+pub type Rgb = [Byte; 3];
+
+pub const BLACK: Rgb = [0, 0, 0];
+pub const WHITE: Rgb = [255, 255, 255];
+pub const RED: Rgb = [255, 0, 0];
+pub const GREEN: Rgb = [0, 255, 0];
+
+/// The overlay color at screen coordinate (`x`, `y`).
+pub fn overlay(x: usize, y: usize) -> Rgb {
+    match y {
+        0..=31 => WHITE,
+        32..=63 => RED,
+        64..=183 => WHITE,
+        184..=239 => GREEN,
+        _ if x > 16 && x <= 134 => GREEN,
+        _ => WHITE,
+    }
+}
+
+/// Is the pixel at screen coordinate (`x`, `y`) lit?
+pub fn lit(vram: &[Byte], x: usize, y: usize) -> bool {
+    let idx = x * HEIGHT + (HEIGHT - 1 - y);
+    vram[idx >> 3] & (0x01 << (idx & 0x07)) != 0
+}
+
+pub struct Renderer {
+    rgba: Vec<Byte>,
+    /// Orientation of the last render, `None` if nothing was rendered yet.
+    flipped: Option<bool>,
+}
+
+impl Default for Renderer {
+    fn default() -> Self {
+        Renderer { rgba: vec![0; WIDTH * HEIGHT * 4], flipped: None }
+    }
+}
+
+impl Renderer {
+    /// Render `vram`: if `flipped` the screen is rotated by 180° (cocktail
+    /// cabinet in player 2's turn).
+    pub fn render(&mut self, vram: &[Byte], flipped: bool) {
+        self.render_rect(vram, flipped, Rect::screen());
+        self.flipped = Some(flipped);
+    }
+
+    /// Render just the `dirty` areas and return the updated framebuffer
+    /// rectangles. The whole screen is rendered the first time and when the
+    /// orientation changes.
+    pub fn render_dirty(&mut self, vram: &[Byte], flipped: bool, dirty: &DirtyMap) -> Vec<Rect> {
+        if self.flipped != Some(flipped) {
+            self.render(vram, flipped);
+            return vec![Rect::screen()];
+        }
+        let rects = dirty.rects().into_iter()
+            .map(|r| match flipped {
+                true => r.flipped(),
+                false => r,
+            })
+            .collect::<Vec<_>>();
+        for &r in rects.iter() {
+            self.render_rect(vram, flipped, r);
+        }
+        rects
+    }
+
+    /// Render phosphor `levels` (one for every not flipped screen pixel):
+    /// the overlay color is scaled by the level.
+    pub fn render_levels(&mut self, levels: &[f32], flipped: bool) {
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                let (sx, sy) = match flipped {
+                    true => (WIDTH - 1 - x, HEIGHT - 1 - y),
+                    false => (x, y),
+                };
+                let level = levels[sy * WIDTH + sx].max(0.0).min(1.0);
+                let c = overlay(sx, sy);
+                let pos = (y * WIDTH + x) * 4;
+                for i in 0..3 {
+                    self.rgba[pos + i] = (c[i] as f32 * level) as Byte;
+                }
+                self.rgba[pos + 3] = 0xFF;
+            }
+        }
+        // The next render_dirty() must redraw everything
+        self.flipped = None;
+    }
+
+    fn render_rect(&mut self, vram: &[Byte], flipped: bool, rect: Rect) {
+        for y in rect.y..rect.y + rect.height {
+            for x in rect.x..rect.x + rect.width {
+                let (sx, sy) = match flipped {
+                    true => (WIDTH - 1 - x, HEIGHT - 1 - y),
+                    false => (x, y),
+                };
+                let c = match lit(vram, sx, sy) {
+                    true => overlay(sx, sy),
+                    false => BLACK,
+                };
+                let pos = (y * WIDTH + x) * 4;
+                self.rgba[pos..pos + 3].copy_from_slice(&c);
+                self.rgba[pos + 3] = 0xFF;
+            }
+        }
+    }
+
+    pub fn rgba(&self) -> &[Byte] {
+        &self.rgba
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use si::memory::VRAM_SIZE;
+    use super::dirty::ROW_BYTES;
+    use rstest::rstest_parametrize;
+
+    fn pixel(renderer: &Renderer, x: usize, y: usize) -> Rgb {
+        let pos = (y * WIDTH + x) * 4;
+        [renderer.rgba[pos], renderer.rgba[pos + 1], renderer.rgba[pos + 2]]
+    }
+
+    #[rstest_parametrize(
+    x, y, expected,
+    case(0, 0, Unwrap("WHITE")),
+    case(100, 40, Unwrap("RED")),
+    case(100, 200, Unwrap("GREEN")),
+    case(20, 250, Unwrap("GREEN")),
+    case(200, 250, Unwrap("WHITE")),
+    )]
+    fn overlay_colors(x: usize, y: usize, expected: Rgb) {
+        assert_eq!(expected, overlay(x, y));
+    }
+
+    #[test]
+    fn first_vram_bit_is_bottom_left() {
+        let mut vram = [0; VRAM_SIZE];
+        vram[0] = 0x01;
+        let mut renderer = Renderer::default();
+
+        renderer.render(&vram, false);
+
+        assert_eq!(WHITE, pixel(&renderer, 0, HEIGHT - 1));
+        assert_eq!(BLACK, pixel(&renderer, 0, 0));
+    }
+
+    #[test]
+    fn flipped_should_rotate_by_180() {
+        let mut vram = [0; VRAM_SIZE];
+        vram[0] = 0x01;
+        let mut renderer = Renderer::default();
+
+        renderer.render(&vram, true);
+
+        assert_eq!(WHITE, pixel(&renderer, WIDTH - 1, 0));
+        assert_eq!(BLACK, pixel(&renderer, 0, HEIGHT - 1));
+    }
+
+    #[test]
+    fn render_dirty_should_update_just_dirty_areas() {
+        let mut vram = [0; VRAM_SIZE];
+        let mut renderer = Renderer::default();
+        let mut dirty = DirtyMap::default();
+        assert_eq!(vec![Rect::screen()], renderer.render_dirty(&vram, false, &dirty));
+
+        vram[0] = 0x01;
+        vram[ROW_BYTES] = 0x01;
+        dirty.mark(0);
+        let rects = renderer.render_dirty(&vram, false, &dirty);
+
+        assert_eq!(vec![Rect { x: 0, y: HEIGHT - 8, width: 1, height: 8 }], rects);
+        assert_eq!(WHITE, pixel(&renderer, 0, HEIGHT - 1));
+        assert_eq!(BLACK, pixel(&renderer, 1, HEIGHT - 1));
+    }
+
+    #[test]
+    fn render_levels_should_scale_overlay() {
+        let mut levels = vec![0.0; WIDTH * HEIGHT];
+        levels[40 * WIDTH + 100] = 0.5;
+        let mut renderer = Renderer::default();
+
+        renderer.render_levels(&levels, false);
+
+        assert_eq!([127, 0, 0], pixel(&renderer, 100, 40));
+        assert_eq!(BLACK, pixel(&renderer, 101, 40));
+    }
+
+    #[test]
+    fn render_dirty_should_redraw_all_when_flip_changes() {
+        let vram = [0; VRAM_SIZE];
+        let mut renderer = Renderer::default();
+        renderer.render(&vram, false);
+
+        assert_eq!(vec![Rect::screen()], renderer.render_dirty(&vram, true, &DirtyMap::default()));
+    }
+}
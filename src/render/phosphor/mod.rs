@@ -0,0 +1,132 @@
+//! Phosphor persistence.
+//!
+//! The ROM draws shots and the UFO across the interrupts, so on a sampled
+//! framebuffer they flicker; on the original monitor the phosphors kept
+//! glowing for a while. `Phosphor` keeps a level for every element of a
+//! buffer: every exposure decays the old levels by the emulated time passed
+//! (exponentially, by `half_life`) and keeps the brightest between them and
+//! the new input. Time is the emulated one, so the result is deterministic.
+
+use rs8080::Byte;
+
+use super::{WIDTH, HEIGHT, lit};
+
+pub struct Phosphor {
+    half_life: f32,
+    levels: Vec<f32>,
+}
+
+impl Phosphor {
+    /// `len` levels that halve every `half_life` milliseconds.
+    pub fn new(len: usize, half_life: f32) -> Self {
+        let mut p = Phosphor { half_life: 0.0, levels: vec![0.0; len] };
+        p.set_half_life(half_life);
+        p
+    }
+
+    /// Persistence for the whole screen (a level for every pixel).
+    pub fn screen(half_life: f32) -> Self {
+        Self::new(WIDTH * HEIGHT, half_life)
+    }
+
+    pub fn half_life(&self) -> f32 {
+        self.half_life
+    }
+
+    pub fn set_half_life(&mut self, half_life: f32) {
+        self.half_life = match half_life.is_nan() {
+            true => 0.0,
+            false => half_life.max(0.0),
+        };
+    }
+
+    /// How much of a level survives after `elapsed` milliseconds.
+    pub fn decay(&self, elapsed: f32) -> f32 {
+        match self.half_life > 0.0 {
+            true => 0.5f32.powf(elapsed / self.half_life),
+            false => 0.0,
+        }
+    }
+
+    /// Decay the levels by `elapsed` milliseconds and excite them by `input`.
+    pub fn expose<I: IntoIterator<Item=f32>>(&mut self, input: I, elapsed: f32) {
+        let decay = self.decay(elapsed);
+        for (level, v) in self.levels.iter_mut().zip(input) {
+            *level = v.max(*level * decay);
+        }
+    }
+
+    /// Expose the screen pixels lit in `vram` (not flipped, row by row).
+    pub fn expose_vram(&mut self, vram: &[Byte], elapsed: f32) {
+        let pixels = (0..HEIGHT)
+            .flat_map(|y| (0..WIDTH).map(move |x| (x, y)))
+            .map(|(x, y)| match lit(vram, x, y) {
+                true => 1.0,
+                false => 0.0,
+            });
+        self.expose(pixels, elapsed);
+    }
+
+    pub fn levels(&self) -> &[f32] {
+        &self.levels
+    }
+
+    pub fn clear(&mut self) {
+        for level in self.levels.iter_mut() {
+            *level = 0.0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rstest::rstest_parametrize;
+
+    #[rstest_parametrize(
+    half_life, elapsed, expected,
+    case(10.0, 10.0, 0.5),
+    case(10.0, 20.0, 0.25),
+    case(10.0, 0.0, 1.0),
+    case(0.0, 5.0, 0.0),
+    )]
+    fn decay(half_life: f32, elapsed: f32, expected: f32) {
+        assert_eq!(expected, Phosphor::new(1, half_life).decay(elapsed));
+    }
+
+    #[test]
+    fn should_keep_brightest_between_decayed_and_new() {
+        let mut p = Phosphor::new(3, 10.0);
+
+        p.expose(vec![1.0, 1.0, 0.0], 0.0);
+        p.expose(vec![0.0, 0.8, 0.2], 10.0);
+
+        assert_eq!(&[0.5, 0.8, 0.2], p.levels());
+    }
+
+    #[test]
+    fn split_exposure_should_decay_the_same() {
+        let mut once = Phosphor::new(1, 16.0);
+        let mut twice = Phosphor::new(1, 16.0);
+        once.expose(vec![1.0], 0.0);
+        twice.expose(vec![1.0], 0.0);
+
+        once.expose(vec![0.0], 16.0);
+        twice.expose(vec![0.0], 8.0);
+        twice.expose(vec![0.0], 8.0);
+
+        assert!((once.levels()[0] - twice.levels()[0]).abs() < 1e-6);
+    }
+
+    #[test]
+    fn expose_vram_should_follow_screen_layout() {
+        let mut vram = [0; ::si::memory::VRAM_SIZE];
+        vram[0] = 0x01;
+        let mut p = Phosphor::screen(10.0);
+
+        p.expose_vram(&vram, 0.0);
+
+        assert_eq!(1.0, p.levels()[(HEIGHT - 1) * WIDTH]);
+        assert_eq!(1.0, p.levels().iter().sum::<f32>());
+    }
+}
@@ -0,0 +1,203 @@
+//! Minimal PNG encoder and decoder for RGBA screenshots.
+//!
+//! Images are written as 8 bit RGBA, no filter and zlib *stored* (not
+//! compressed) blocks: the files are big but the code is tiny and doesn't
+//! need any dependency. `decode()` reads just this kind of files.
+
+use std::fmt;
+
+use patch::crc32;
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+const RGBA: u8 = 6;
+const MAX_STORED: usize = 0xFFFF;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PngError {
+    Signature,
+    Truncated,
+    Checksum,
+    /// Valid PNG that doesn't use the format written by `encode()`.
+    Unsupported,
+}
+
+impl fmt::Display for PngError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PngError::Signature => write!(f, "Not a PNG file"),
+            PngError::Truncated => write!(f, "Truncated PNG file"),
+            PngError::Checksum => write!(f, "Corrupted PNG chunk"),
+            PngError::Unsupported => write!(f, "Unsupported PNG format"),
+        }
+    }
+}
+
+fn be32(data: &[u8]) -> u32 {
+    data.iter().take(4).fold(0, |acc, &b| (acc << 8) | b as u32)
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (a, b) = data.iter().fold((1u32, 0u32), |(a, b), &v| {
+        let a = (a + v as u32) % 65521;
+        (a, (b + a) % 65521)
+    });
+    (b << 16) | a
+}
+
+fn chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = out.len();
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    let crc = crc32(&out[start..]);
+    out.extend_from_slice(&crc.to_be_bytes());
+}
+
+/// Encode a `width`x`height` RGBA image.
+pub fn encode(rgba: &[u8], width: usize, height: usize) -> Vec<u8> {
+    let mut raw = Vec::with_capacity((width * 4 + 1) * height);
+    for row in rgba.chunks(width * 4).take(height) {
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+
+    let mut zlib = vec![0x78, 0x01];
+    let blocks = raw.chunks(MAX_STORED).count();
+    for (i, block) in raw.chunks(MAX_STORED).enumerate() {
+        zlib.push((i + 1 == blocks) as u8);
+        let len = block.len() as u16;
+        zlib.extend_from_slice(&len.to_le_bytes());
+        zlib.extend_from_slice(&(!len).to_le_bytes());
+        zlib.extend_from_slice(block);
+    }
+    zlib.extend_from_slice(&adler32(&raw).to_be_bytes());
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    header.extend_from_slice(&[8, RGBA, 0, 0, 0]);
+
+    let mut out = SIGNATURE.to_vec();
+    chunk(&mut out, b"IHDR", &header);
+    chunk(&mut out, b"IDAT", &zlib);
+    chunk(&mut out, b"IEND", &[]);
+    out
+}
+
+/// Decode an image written by `encode()`: return width, height and RGBA data.
+pub fn decode(data: &[u8]) -> Result<(usize, usize, Vec<u8>), PngError> {
+    if !data.starts_with(&SIGNATURE) {
+        return Err(PngError::Signature);
+    }
+    let mut rest = &data[SIGNATURE.len()..];
+    let mut size = None;
+    let mut zlib = Vec::new();
+    while rest.len() >= 12 {
+        let len = be32(rest) as usize;
+        if len.checked_add(12).map(|size| rest.len() < size).unwrap_or(true) {
+            return Err(PngError::Truncated);
+        }
+        if crc32(&rest[4..8 + len]) != be32(&rest[8 + len..]) {
+            return Err(PngError::Checksum);
+        }
+        let body = &rest[8..8 + len];
+        match &rest[4..8] {
+            b"IHDR" if len == 13 => {
+                if body[8..] != [8, RGBA, 0, 0, 0] {
+                    return Err(PngError::Unsupported);
+                }
+                size = Some((be32(body) as usize, be32(&body[4..]) as usize));
+            }
+            b"IDAT" => zlib.extend_from_slice(body),
+            b"IEND" => break,
+            _ => {}
+        }
+        rest = &rest[12 + len..];
+    }
+    let (width, height) = size.ok_or(PngError::Truncated)?;
+
+    let row_size = width.checked_mul(4).and_then(|w| w.checked_add(1))
+        .ok_or(PngError::Unsupported)?;
+    let raw_size = row_size.checked_mul(height).ok_or(PngError::Unsupported)?;
+
+    let raw = inflate_stored(&zlib)?;
+    if raw.len() != raw_size {
+        return Err(PngError::Truncated);
+    }
+    let mut rgba = Vec::with_capacity(width * height * 4);
+    for row in raw.chunks(row_size) {
+        if row[0] != 0 {
+            return Err(PngError::Unsupported);
+        }
+        rgba.extend_from_slice(&row[1..]);
+    }
+    Ok((width, height, rgba))
+}
+
+fn inflate_stored(zlib: &[u8]) -> Result<Vec<u8>, PngError> {
+    if zlib.len() < 6 {
+        return Err(PngError::Truncated);
+    }
+    let mut rest = &zlib[2..];
+    let mut raw = Vec::new();
+    loop {
+        if rest.len() < 5 {
+            return Err(PngError::Truncated);
+        }
+        let (header, len) = (rest[0], rest[1] as usize | (rest[2] as usize) << 8);
+        if header & 0x06 != 0 {
+            return Err(PngError::Unsupported);
+        }
+        if rest.len() < 5 + len {
+            return Err(PngError::Truncated);
+        }
+        raw.extend_from_slice(&rest[5..5 + len]);
+        rest = &rest[5 + len..];
+        if header & 0x01 != 0 {
+            break;
+        }
+    }
+    match rest.len() >= 4 && be32(rest) == adler32(&raw) {
+        true => Ok(raw),
+        false => Err(PngError::Checksum),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let rgba = (0..300 * 100 * 4).map(|i| (i * 7) as u8).collect::<Vec<_>>();
+
+        assert_eq!(Ok((300, 100, rgba.clone())), decode(&encode(&rgba, 300, 100)));
+    }
+
+    #[test]
+    fn adler() {
+        assert_eq!(0x11E6_0398, adler32(b"Wikipedia"));
+    }
+
+    #[test]
+    fn corrupted_file() {
+        let mut png = encode(&[0; 16], 2, 2);
+        png[20] ^= 0xFF;
+
+        assert_eq!(Err(PngError::Checksum), decode(&png));
+        assert_eq!(Err(PngError::Signature), decode(b"GIF89a"));
+    }
+
+    #[test]
+    fn huge_size() {
+        let mut png = encode(&[0; 16], 2, 2);
+        // IHDR width and height
+        for b in png[16..24].iter_mut() {
+            *b = 0xFF;
+        }
+        let crc = crc32(&png[12..29]);
+        png[29..33].copy_from_slice(&crc.to_be_bytes());
+
+        assert_eq!(Err(PngError::Unsupported), decode(&png));
+    }
+}
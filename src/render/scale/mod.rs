@@ -0,0 +1,347 @@
+//! Pixel art upscalers for the RGBA framebuffer.
+//!
+//! * `Nearest`: every pixel become a `factor`x`factor` square (any factor
+//!   from 1 to 8)
+//! * `Scale2x` and `Scale3x`: the AdvanceMAME edge interpolation; 4x and 8x
+//!   apply `Scale2x` again and 9x `Scale3x`
+//! * `Xbr`: a light version of xBR 2x that looks just at the 3x3 neighborhood;
+//!   4x and 8x apply it again
+//!
+//! Neighbors outside the image are the border pixels themselves.
+
+use std::fmt;
+use std::mem;
+
+use rs8080::Byte;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Filter {
+    Nearest,
+    Scale2x,
+    Scale3x,
+    Xbr,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScaleError {
+    /// The filter cannot produce this factor.
+    Factor(Filter, usize),
+    Filter,
+}
+
+impl fmt::Display for ScaleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ScaleError::Factor(filter, factor) => write!(f, "{:?} cannot scale by {}", filter, factor),
+            ScaleError::Filter => write!(f, "Unknown filter"),
+        }
+    }
+}
+
+impl Filter {
+    pub fn parse(name: &str) -> Result<Self, ScaleError> {
+        match name {
+            "nearest" => Ok(Filter::Nearest),
+            "scale2x" => Ok(Filter::Scale2x),
+            "scale3x" => Ok(Filter::Scale3x),
+            "xbr" => Ok(Filter::Xbr),
+            _ => Err(ScaleError::Filter),
+        }
+    }
+
+    /// How many passes are needed to scale by `factor`.
+    fn passes(&self, factor: usize) -> Option<usize> {
+        match (*self, factor) {
+            (Filter::Nearest, 1..=8) => Some(1),
+            (Filter::Scale2x, 2) | (Filter::Xbr, 2) => Some(1),
+            (Filter::Scale2x, 4) | (Filter::Xbr, 4) => Some(2),
+            (Filter::Scale2x, 8) | (Filter::Xbr, 8) => Some(3),
+            (Filter::Scale3x, 3) => Some(1),
+            (Filter::Scale3x, 9) => Some(2),
+            _ => None,
+        }
+    }
+}
+
+type Pixel = u32;
+
+fn pixels(rgba: &[Byte], out: &mut Vec<Pixel>) {
+    out.clear();
+    out.extend(rgba.chunks(4)
+        .map(|c| (c[0] as Pixel) << 24 | (c[1] as Pixel) << 16 | (c[2] as Pixel) << 8 | c[3] as Pixel));
+}
+
+fn channels(p: Pixel) -> [i32; 4] {
+    [(p >> 24) as u8 as i32, (p >> 16) as u8 as i32, (p >> 8) as u8 as i32, p as u8 as i32]
+}
+
+/// Source image with clamped neighbors access.
+struct Image<'a> {
+    data: &'a [Pixel],
+    width: usize,
+    height: usize,
+}
+
+impl<'a> Image<'a> {
+    /// The 3x3 neighborhood of (`x`, `y`) as `[A, B, C, D, E, F, G, H, I]`.
+    fn around(&self, x: usize, y: usize) -> [Pixel; 9] {
+        let xs = [x.saturating_sub(1), x, (x + 1).min(self.width - 1)];
+        let ys = [y.saturating_sub(1), y, (y + 1).min(self.height - 1)];
+        let mut out = [0; 9];
+        for (j, &sy) in ys.iter().enumerate() {
+            for (i, &sx) in xs.iter().enumerate() {
+                out[j * 3 + i] = self.data[sy * self.width + sx];
+            }
+        }
+        out
+    }
+}
+
+fn nearest(src: &Image, factor: usize, out: &mut Vec<Pixel>) {
+    let width = src.width * factor;
+    out.clear();
+    for y in 0..src.height * factor {
+        let row = &src.data[(y / factor) * src.width..(y / factor + 1) * src.width];
+        out.extend((0..width).map(|x| row[x / factor]));
+    }
+}
+
+/// Run `block` for every source pixel: it fills the first `n`x`n` pixels of
+/// its output, row by row, that are copied in `out`.
+fn by_block<F: Fn(&[Pixel; 9], &mut [Pixel; 9])>(src: &Image, n: usize, out: &mut Vec<Pixel>, block: F) {
+    let width = src.width * n;
+    out.clear();
+    out.resize(width * src.height * n, 0);
+    let mut b = [0; 9];
+    for y in 0..src.height {
+        for x in 0..src.width {
+            block(&src.around(x, y), &mut b);
+            for (k, &p) in b[..n * n].iter().enumerate() {
+                out[(y * n + k / n) * width + x * n + k % n] = p;
+            }
+        }
+    }
+}
+
+fn scale2x(src: &Image, out: &mut Vec<Pixel>) {
+    by_block(src, 2, out, |n, out| {
+        let (b, d, e, f, h) = (n[1], n[3], n[4], n[5], n[7]);
+        if b != h && d != f {
+            out[..4].copy_from_slice(&[
+                if d == b { d } else { e },
+                if b == f { f } else { e },
+                if d == h { d } else { e },
+                if h == f { f } else { e },
+            ]);
+        } else {
+            *out = [e; 9];
+        }
+    })
+}
+
+fn scale3x(src: &Image, out: &mut Vec<Pixel>) {
+    by_block(src, 3, out, |n, out| {
+        let (a, b, c, d, e, f, g, h, i) = (n[0], n[1], n[2], n[3], n[4], n[5], n[6], n[7], n[8]);
+        if b != h && d != f {
+            *out = [
+                if d == b { d } else { e },
+                if (d == b && e != c) || (b == f && e != a) { b } else { e },
+                if b == f { f } else { e },
+                if (d == b && e != g) || (d == h && e != a) { d } else { e },
+                e,
+                if (b == f && e != i) || (h == f && e != c) { f } else { e },
+                if d == h { d } else { e },
+                if (d == h && e != i) || (h == f && e != g) { h } else { e },
+                if h == f { f } else { e },
+            ];
+        } else {
+            *out = [e; 9];
+        }
+    })
+}
+
+/// Color distance weighted like the luma.
+fn distance(p: Pixel, q: Pixel) -> i32 {
+    let (p, q) = (channels(p), channels(q));
+    3 * (p[0] - q[0]).abs() + 6 * (p[1] - q[1]).abs() + (p[2] - q[2]).abs()
+}
+
+fn blend(p: Pixel, q: Pixel) -> Pixel {
+    let (p, q) = (channels(p), channels(q));
+    (0..4).fold(0, |acc, i| (acc << 8) | ((p[i] + q[i]) / 2) as Pixel)
+}
+
+/// The corner of `e` between its neighbors `f` and `h` (`i` is the diagonal
+/// one): if the edge goes along `f`-`h` the corner gets the color on the
+/// other side. `c` and `g` are the neighbors of `f` and `h` away from the
+/// corner, `d` and `b` the ones opposite to `f` and `h`.
+fn corner(e: Pixel, f: Pixel, h: Pixel, i: Pixel, c: Pixel, g: Pixel, d: Pixel, b: Pixel) -> Pixel {
+    let along_fh = distance(e, c) + distance(e, g) + 4 * distance(h, f);
+    let along_ei = distance(h, d) + distance(f, b) + 4 * distance(e, i);
+    if along_fh < along_ei {
+        let other = if distance(e, f) <= distance(e, h) { f } else { h };
+        blend(e, other)
+    } else {
+        e
+    }
+}
+
+fn xbr(src: &Image, out: &mut Vec<Pixel>) {
+    by_block(src, 2, out, |n, out| {
+        let (a, b, c, d, e, f, g, h, i) = (n[0], n[1], n[2], n[3], n[4], n[5], n[6], n[7], n[8]);
+        out[..4].copy_from_slice(&[
+            corner(e, d, b, a, g, c, f, h),
+            corner(e, f, b, c, i, a, d, h),
+            corner(e, d, h, g, a, i, f, b),
+            corner(e, f, h, i, c, g, d, b),
+        ]);
+    })
+}
+
+/// Scale RGBA frames with a filter and keep the result.
+///
+/// The buffers are kept between frames, so scaling doesn't allocate once
+/// they have grown to the frame size.
+pub struct Upscaler {
+    filter: Filter,
+    factor: usize,
+    rgba: Vec<Byte>,
+    /// The pass input and output.
+    src: Vec<Pixel>,
+    dst: Vec<Pixel>,
+}
+
+impl Upscaler {
+    pub fn new(filter: Filter, factor: usize) -> Result<Self, ScaleError> {
+        filter.passes(factor).ok_or(ScaleError::Factor(filter, factor))?;
+        Ok(Upscaler { filter, factor, rgba: Vec::new(), src: Vec::new(), dst: Vec::new() })
+    }
+
+    pub fn filter(&self) -> Filter {
+        self.filter
+    }
+
+    pub fn factor(&self) -> usize {
+        self.factor
+    }
+
+    /// Scale the `width`x`height` `rgba` image: the result is
+    /// `factor() * width` wide and `factor() * height` tall.
+    pub fn scale(&mut self, rgba: &[Byte], width: usize, height: usize) -> &[Byte] {
+        pixels(rgba, &mut self.src);
+        let (mut w, mut h) = (width, height);
+        let passes = self.filter.passes(self.factor).unwrap_or(1);
+        let step = match self.filter {
+            Filter::Nearest => self.factor,
+            Filter::Scale3x => 3,
+            _ => 2,
+        };
+        for _ in 0..passes {
+            {
+                let src = Image { data: &self.src, width: w, height: h };
+                match self.filter {
+                    Filter::Nearest => nearest(&src, step, &mut self.dst),
+                    Filter::Scale2x => scale2x(&src, &mut self.dst),
+                    Filter::Scale3x => scale3x(&src, &mut self.dst),
+                    Filter::Xbr => xbr(&src, &mut self.dst),
+                }
+            }
+            mem::swap(&mut self.src, &mut self.dst);
+            w *= step;
+            h *= step;
+        }
+        self.rgba.resize(self.src.len() * 4, 0);
+        for (c, &p) in self.rgba.chunks_mut(4).zip(self.src.iter()) {
+            c.copy_from_slice(&[(p >> 24) as Byte, (p >> 16) as Byte, (p >> 8) as Byte, p as Byte]);
+        }
+        &self.rgba
+    }
+
+    pub fn rgba(&self) -> &[Byte] {
+        &self.rgba
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rstest::rstest_parametrize;
+
+    const X: [Byte; 4] = [255, 255, 255, 255];
+    const O: [Byte; 4] = [0, 0, 0, 255];
+
+    fn image(rows: &[&str]) -> Vec<Byte> {
+        rows.iter()
+            .flat_map(|r| r.chars())
+            .flat_map(|c| match c {
+                'X' => X.to_vec(),
+                _ => O.to_vec(),
+            })
+            .collect()
+    }
+
+    fn scale(filter: Filter, factor: usize, rows: &[&str]) -> Vec<Byte> {
+        Upscaler::new(filter, factor).unwrap()
+            .scale(&image(rows), rows[0].len(), rows.len())
+            .to_vec()
+    }
+
+    #[test]
+    fn nearest_should_replicate_pixels() {
+        assert_eq!(image(&["XXOO", "XXOO", "OOXX", "OOXX"]),
+                   scale(Filter::Nearest, 2, &["XO", "OX"]));
+    }
+
+    #[test]
+    fn scale2x_should_smooth_diagonals() {
+        assert_eq!(image(&[
+            "OOOOOOOO",
+            "OOOOOOOO",
+            "OOXXOOOO",
+            "OOXXXOOO",
+            "OOOXXXOO",
+            "OOOOXXOO",
+            "OOOOOOOO",
+            "OOOOOOOO",
+        ]), scale(Filter::Scale2x, 2, &["OOOO", "OXOO", "OOXO", "OOOO"]));
+    }
+
+    #[test]
+    fn scale3x_should_keep_flat_areas() {
+        assert_eq!(image(&["XXXXXX"; 6]), scale(Filter::Scale3x, 3, &["XX", "XX"]));
+    }
+
+    #[test]
+    fn xbr_should_blend_diagonal_corners() {
+        let out = scale(Filter::Xbr, 2, &["XOO", "OXO", "OOX"]);
+
+        // Top right corner of the center pixel is on the edge
+        let pos = (2 * 6 + 3) * 4;
+        assert_eq!([127, 127, 127, 255], out[pos..pos + 4]);
+        // Top left is on the diagonal
+        let pos = (2 * 6 + 2) * 4;
+        assert_eq!(X, out[pos..pos + 4]);
+    }
+
+    #[rstest_parametrize(
+    filter, factor, size,
+    case(Unwrap("Filter::Nearest"), 3, 3),
+    case(Unwrap("Filter::Scale2x"), 4, 4),
+    case(Unwrap("Filter::Scale3x"), 9, 9),
+    case(Unwrap("Filter::Xbr"), 8, 8),
+    )]
+    fn output_size(filter: Filter, factor: usize, size: usize) {
+        assert_eq!(size * size * 4, scale(filter, factor, &["X"]).len());
+    }
+
+    #[rstest_parametrize(
+    filter, factor,
+    case(Unwrap("Filter::Nearest"), 0),
+    case(Unwrap("Filter::Scale2x"), 3),
+    case(Unwrap("Filter::Scale3x"), 2),
+    case(Unwrap("Filter::Xbr"), 3),
+    )]
+    fn unsupported_factor(filter: Filter, factor: usize) {
+        assert_eq!(Err(ScaleError::Factor(filter, factor)), Upscaler::new(filter, factor).map(|_| ()));
+    }
+}
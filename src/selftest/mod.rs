@@ -0,0 +1,118 @@
+//! Installation self test, done by the emulator: every rom chip CRC32 is
+//! compared with the one of the original Midway set (a patched rom fails
+//! here, as expected).
+//!
+//! There is no service mode: the board wires DIP switch 4 to bit 0 of input
+//! port 0, but this rom set never reads port 0, so the emulator doesn't
+//! expose the switch. The ram is not checked either: it's emulated memory
+//! that cannot break.
+//!
+//! ```text
+//! ROM invaders.h 0000-07FF 734f5ad8 OK
+//! ...
+//! ROM invaders.e 1800-1FFF 14e538b0 OK
+//! ```
+
+use std::fmt;
+
+use rs8080::Address;
+use patch::crc32;
+use si::memory::ROM_SIZE;
+
+const CHIP_SIZE: usize = ROM_SIZE / 4;
+
+const CHIPS: [(&str, u32); 4] = [
+    ("invaders.h", 0x734f_5ad8),
+    ("invaders.g", 0x6bfa_ca4a),
+    ("invaders.f", 0x0cce_ad96),
+    ("invaders.e", 0x14e5_38b0),
+];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RomCheck {
+    pub name: &'static str,
+    pub start: Address,
+    pub expected: u32,
+    pub actual: u32,
+}
+
+impl RomCheck {
+    pub fn ok(&self) -> bool {
+        self.expected == self.actual
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Report {
+    pub roms: Vec<RomCheck>,
+}
+
+impl Report {
+    pub fn ok(&self) -> bool {
+        self.roms.iter().all(RomCheck::ok)
+    }
+}
+
+fn status(ok: bool) -> &'static str {
+    match ok {
+        true => "OK",
+        false => "BAD",
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for rom in self.roms.iter() {
+            write!(f, "ROM {} {:04X}-{:04X} {:08x} {}", rom.name, rom.start,
+                   rom.start as usize + CHIP_SIZE - 1, rom.actual, status(rom.ok()))?;
+            match rom.ok() {
+                true => writeln!(f)?,
+                false => writeln!(f, " (expected {:08x})", rom.expected)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn check_rom(rom: &[u8]) -> Vec<RomCheck> {
+    CHIPS.iter().zip(rom.chunks(CHIP_SIZE)).enumerate()
+        .map(|(i, (&(name, expected), data))| RomCheck {
+            name,
+            start: (i * CHIP_SIZE) as Address,
+            expected,
+            actual: crc32(data),
+        })
+        .collect()
+}
+
+/// Check the chips of `rom`.
+pub fn run(rom: &[u8; ROM_SIZE]) -> Report {
+    Report { roms: check_rom(rom) }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use Game;
+
+    #[test]
+    fn original_rom_should_pass() {
+        let report = Game::new().self_test_report();
+
+        assert!(report.ok(), "{}", report);
+        assert_eq!(4, report.roms.len());
+    }
+
+    #[test]
+    fn should_report_bad_chip() {
+        let mut rom = [0; ROM_SIZE];
+        rom[..CHIP_SIZE].copy_from_slice(&Game::new().rom[..CHIP_SIZE]);
+
+        let report = run(&rom);
+
+        assert!(!report.ok());
+        assert!(report.roms[0].ok());
+        assert!(!report.roms[1].ok());
+        assert!(report.to_string().contains("ROM invaders.g 0800-0FFF"));
+    }
+}
@@ -2,13 +2,15 @@
 
 mod shift_register;
 
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 
 use rs8080::{
     io_bus::{InputBus, OutputBus},
     Byte
 };
-use self::shift_register::ShiftRegister;
+pub use self::shift_register::ShiftRegister;
+use dip::{DipSwitch, DipValue, DipSettings};
+use wasm_bindgen::prelude::*;
 
 
 
@@ -38,25 +40,107 @@ const P2RIGHT_BIT: u8 = 0x06;
 const LIVES_MASK: u8 = 0x03;
 const BONUS_LIFE_MASK: u8 = 0x08;
 const COIN_INFO_MASK: u8 = 0x80;
-
-#[derive(Copy, Clone)]
+const FLIP_MASK: u8 = 0x20;
+
+// There is no "Service Mode" switch: DIP4 is wired to port 0 bit 0 (self
+// test request), but this rom never reads port 0 (see `selftest`).
+pub static DIP_SWITCHES: &[DipSwitch] = &[
+    DipSwitch {
+        name: "Lives",
+        port: PORT2,
+        mask: LIVES_MASK,
+        values: &[
+            DipValue { value: 0x00, label: "3" },
+            DipValue { value: 0x01, label: "4" },
+            DipValue { value: 0x02, label: "5" },
+            DipValue { value: 0x03, label: "6" },
+        ],
+        default: 0,
+    },
+    DipSwitch {
+        name: "Bonus Life",
+        port: PORT2,
+        mask: BONUS_LIFE_MASK,
+        values: &[
+            DipValue { value: BONUS_LIFE_MASK, label: "1000" },
+            DipValue { value: 0x00, label: "1500" },
+        ],
+        default: 0,
+    },
+    DipSwitch {
+        name: "Coin Info",
+        port: PORT2,
+        mask: COIN_INFO_MASK,
+        values: &[
+            DipValue { value: 0x00, label: "On" },
+            DipValue { value: COIN_INFO_MASK, label: "Off" },
+        ],
+        default: 0,
+    },
+];
+
+/// Input events: the discriminant is the bit used in inputs masks.
+#[wasm_bindgen]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Ev {
-    Coin,
-    Tilt,
-    P1Start,
-    P1Shoot,
-    P1Left,
-    P1Right,
-    P2Start,
-    P2Shoot,
-    P2Left,
-    P2Right,
+    Coin = 0,
+    Tilt = 1,
+    P1Start = 2,
+    P1Shoot = 3,
+    P1Left = 4,
+    P1Right = 5,
+    P2Start = 6,
+    P2Shoot = 7,
+    P2Left = 8,
+    P2Right = 9,
+}
+
+impl Ev {
+    pub const ALL: [Ev; 10] = [Ev::Coin, Ev::Tilt, Ev::P1Start, Ev::P1Shoot, Ev::P1Left,
+        Ev::P1Right, Ev::P2Start, Ev::P2Shoot, Ev::P2Left, Ev::P2Right];
+
+    pub fn mask(self) -> u32 {
+        0x01 << self as u32
+    }
+
+    /// Lower case name used in text formats (`p1left`).
+    pub fn name(self) -> &'static str {
+        match self {
+            Ev::Coin => "coin",
+            Ev::Tilt => "tilt",
+            Ev::P1Start => "p1start",
+            Ev::P1Shoot => "p1shoot",
+            Ev::P1Left => "p1left",
+            Ev::P1Right => "p1right",
+            Ev::P2Start => "p2start",
+            Ev::P2Shoot => "p2shoot",
+            Ev::P2Left => "p2left",
+            Ev::P2Right => "p2right",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Ev> {
+        Ev::ALL.iter().cloned().find(|ev| ev.name() == name)
+    }
+}
+
+/// The `IO` state that changes while the machine is running.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IoState {
+    pub port1: Byte,
+    pub port2: Byte,
+    pub sr: ShiftRegister,
+    pub inputs: u32,
+    pub flip: bool,
 }
 
 pub struct IO {
     port1: RefCell<u8>,
     port2: RefCell<u8>,
     sr: RefCell<ShiftRegister>,
+    inputs: Cell<u32>,
+    cocktail: bool,
+    flip: Cell<bool>,
 }
 
 impl IO {
@@ -65,30 +149,97 @@ impl IO {
             port1: RefCell::new(port1),
             port2: RefCell::new(port2),
             sr: Default::default(),
+            inputs: Cell::new(0),
+            cocktail: false,
+            flip: Cell::new(false),
         }
     }
 
+    pub fn state(&self) -> IoState {
+        IoState {
+            port1: *self.port1.borrow(),
+            port2: *self.port2.borrow(),
+            sr: *self.sr.borrow(),
+            inputs: self.inputs.get(),
+            flip: self.flip.get(),
+        }
+    }
+
+    pub fn restore(&self, state: &IoState) {
+        *self.port1.borrow_mut() = state.port1;
+        *self.port2.borrow_mut() = state.port2;
+        *self.sr.borrow_mut() = state.sr;
+        self.inputs.set(state.inputs);
+        self.flip.set(state.flip);
+    }
+
+    /// Pressed events as a mask of `Ev::mask()` bits.
+    pub fn inputs(&self) -> u32 {
+        self.inputs.get()
+    }
+
+    /// Press all events in `mask` and release the others.
+    pub fn set_inputs(&self, mask: u32) {
+        self.inputs.set(mask);
+        self.wire();
+    }
+
     pub fn ui_event(&self, ev: Ev, pressed: bool) {
-        let (port, bit, set) = match ev {
-            Ev::Coin => (&self.port1, COIN_BIT, !pressed),
-            Ev::Tilt => (&self.port2, TILT_BIT, pressed),
-            Ev::P1Start => (&self.port1, P1START_BIT, pressed),
-            Ev::P1Shoot => (&self.port1, P1SHOOT_BIT, pressed),
-            Ev::P1Left => (&self.port1, P1LEFT_BIT, pressed),
-            Ev::P1Right => (&self.port1, P1RIGHT_BIT, pressed),
-            Ev::P2Start => (&self.port1, P2START_BIT, pressed),
-            Ev::P2Shoot => (&self.port2, P1SHOOT_BIT, pressed),
-            Ev::P2Left => (&self.port2, P1LEFT_BIT, pressed),
-            Ev::P2Right => (&self.port2, P1RIGHT_BIT, pressed),
-        };
+        let inputs = self.inputs.get();
+        self.inputs.set(if pressed { inputs | ev.mask() } else { inputs & !ev.mask() });
+        self.wire();
+    }
 
-        if set {
-            *port.borrow_mut() |= 0x01 << bit;
-        } else {
-            *port.borrow_mut() &= !(0x01 << bit);
+    fn pressed(&self, ev: Ev) -> bool {
+        self.inputs.get() & ev.mask() != 0
+    }
+
+    /// Player 2 controls: in the upright cabinet both players use the same
+    /// control panel, so player 1 controls are wired to player 2 inputs too.
+    fn p2_pressed(&self, p2: Ev, p1: Ev) -> bool {
+        self.pressed(p2) || (!self.cocktail && self.pressed(p1))
+    }
+
+    fn wire(&self) {
+        let port1 = [
+            (COIN_BIT, !self.pressed(Ev::Coin)),
+            (P2START_BIT, self.pressed(Ev::P2Start)),
+            (P1START_BIT, self.pressed(Ev::P1Start)),
+            (P1SHOOT_BIT, self.pressed(Ev::P1Shoot)),
+            (P1LEFT_BIT, self.pressed(Ev::P1Left)),
+            (P1RIGHT_BIT, self.pressed(Ev::P1Right)),
+        ];
+        let port2 = [
+            (TILT_BIT, self.pressed(Ev::Tilt)),
+            (P2SHOOT_BIT, self.p2_pressed(Ev::P2Shoot, Ev::P1Shoot)),
+            (P2LEFT_BIT, self.p2_pressed(Ev::P2Left, Ev::P1Left)),
+            (P2RIGHT_BIT, self.p2_pressed(Ev::P2Right, Ev::P1Right)),
+        ];
+        for &(port, bits) in [(&self.port1, &port1[..]), (&self.port2, &port2[..])].iter() {
+            let state = bits.iter()
+                .fold(*port.borrow(), |state, &(bit, set)| mask(state, 0x01 << bit, set));
+            *port.borrow_mut() = state;
         }
     }
 
+    /// Cocktail cabinet: players have their own controls and the screen is
+    /// flipped during player 2's turn.
+    pub fn cocktail(self, cocktail: bool) -> Self {
+        let io = IO { cocktail, ..self };
+        io.wire();
+        io
+    }
+
+    pub fn is_cocktail(&self) -> bool {
+        self.cocktail
+    }
+
+    /// Should the screen be flipped? The rom ask it in player 2's turn, but
+    /// just the cocktail cabinet use it.
+    pub fn flipped(&self) -> bool {
+        self.cocktail && self.flip.get()
+    }
+
     pub fn bonus_life(&self) -> u16 {
         match *self.port2.borrow() & BONUS_LIFE_MASK {
             BONUS_LIFE_MASK => 1000,
@@ -135,6 +286,14 @@ impl IO {
         }
     }
 
+    pub fn dips(self, settings: &DipSettings) -> Self {
+        IO {
+            port1: RefCell::new(settings.port(PORT1, *self.port1.borrow())),
+            port2: RefCell::new(settings.port(PORT2, *self.port2.borrow())),
+            ..self
+        }
+    }
+
     pub fn lives(&self) -> u8 {
         match *self.port2.borrow() & LIVES_MASK {
             0 => 3,
@@ -182,7 +341,11 @@ impl OutputBus for IO {
             SR_OFFSET_PORT => {
                 self.sr.borrow_mut().set_offset(data);
             }
-            SOUND_A_PORT | SOUND_B_PORT => debug!("Write to sound port[{}]={:02x}", id, data),
+            SOUND_B_PORT => {
+                self.flip.set(data & FLIP_MASK != 0);
+                debug!("Write to sound port[{}]={:02x}", id, data)
+            }
+            SOUND_A_PORT => debug!("Write to sound port[{}]={:02x}", id, data),
             WATCHDOG_PORT => debug!("Write to watchdog {:02x}", data),
             _ => warn!("Write to unknown port {}={:02x}", id, data)
         }
@@ -198,6 +361,14 @@ mod test {
         IO::default()
     }
 
+    #[test]
+    fn ev_names_round_trip() {
+        for &ev in Ev::ALL.iter() {
+            assert_eq!(Some(ev), Ev::from_name(ev.name()));
+        }
+        assert_eq!(None, Ev::from_name("p3start"));
+    }
+
     #[test]
     fn default_state() {
         let io = IO::default();
@@ -238,6 +409,74 @@ mod test {
         assert!(io.read(port) & mask == expected);
     }
 
+    #[rstest_parametrize(
+    mask, port1, port2,
+    case(0x000, 0x01, 0x00),
+    case(0x001, 0x00, 0x00),
+    case(0x002, 0x01, 0x04),
+    case(0x038, 0x71, 0x70),
+    case(0x3C0, 0x03, 0x70),
+    )]
+    fn set_inputs_by_mask(io: IO, mask: u32, port1: u8, port2: u8) {
+        io.set_inputs(mask);
+
+        assert_eq!(port1, io.read(PORT1));
+        assert_eq!(port2, io.read(PORT2));
+        assert_eq!(mask, io.inputs());
+    }
+
+    #[rstest]
+    fn set_inputs_should_release_the_others(io: IO) {
+        io.ui_event(Ev::P1Left, true);
+        io.ui_event(Ev::P2Shoot, true);
+
+        io.set_inputs(Ev::P1Right.mask());
+
+        assert_eq!(Ev::P1Right.mask(), io.inputs());
+        assert_eq!(0x01 << P1RIGHT_BIT, io.read(PORT1) & !(0x01 << COIN_BIT));
+        assert_eq!(0x01 << P2RIGHT_BIT, io.read(PORT2));
+    }
+
+    #[rstest_parametrize(
+    cocktail, event, port2,
+    case(false, Unwrap("Ev::P1Shoot"), 0x10),
+    case(false, Unwrap("Ev::P2Shoot"), 0x10),
+    case(true, Unwrap("Ev::P1Shoot"), 0x00),
+    case(true, Unwrap("Ev::P2Shoot"), 0x10),
+    case(true, Unwrap("Ev::P2Left"), 0x20),
+    )]
+    fn player2_controls_wiring(io: IO, cocktail: bool, event: Ev, port2: u8) {
+        let io = io.cocktail(cocktail);
+
+        io.ui_event(event, true);
+
+        assert_eq!(port2, io.read(PORT2));
+    }
+
+    #[rstest]
+    fn should_not_forget_dip_switches(io: IO) {
+        let io = io.change_lives(5);
+
+        io.ui_event(Ev::P1Left, true);
+        io.ui_event(Ev::P1Left, false);
+
+        assert_eq!(5, io.lives());
+    }
+
+    #[rstest_parametrize(
+    cocktail, data, flipped,
+    case(true, 0x20, true),
+    case(true, 0xDF, false),
+    case(false, 0x20, false),
+    )]
+    fn flip_screen(io: IO, cocktail: bool, data: u8, flipped: bool) {
+        let io = io.cocktail(cocktail);
+
+        io.send(SOUND_B_PORT, data);
+
+        assert_eq!(flipped, io.flipped());
+    }
+
     #[rstest_parametrize(
     lives, value,
     case(0, 0),
@@ -292,6 +531,48 @@ mod test {
         assert_eq!(false, io.coin_info());
     }
 
+    #[rstest]
+    fn default_dips_should_be_the_classic_setting(io: IO) {
+        let io = io.dips(&DipSettings::new(DIP_SWITCHES));
+
+        assert_eq!(3, io.lives());
+        assert_eq!(1000, io.bonus_life());
+        assert_eq!(true, io.coin_info());
+    }
+
+    #[rstest_parametrize(
+    switch, value, lives, bonus_life, coin_info,
+    case(0, 2, 5, 1000, true),
+    case(0, 3, 6, 1000, true),
+    case(1, 1, 3, 1500, true),
+    case(2, 1, 3, 1000, false),
+    )]
+    fn dips(io: IO, switch: usize, value: usize, lives: u8, bonus_life: u16, coin_info: bool) {
+        let mut settings = DipSettings::new(DIP_SWITCHES);
+        settings.set(switch, value).unwrap();
+
+        let io = io.dips(&settings);
+
+        assert_eq!(lives, io.lives());
+        assert_eq!(bonus_life, io.bonus_life());
+        assert_eq!(coin_info, io.coin_info());
+    }
+
+    #[rstest]
+    fn restore_state(io: IO) {
+        io.send(SR_DATA_PORT, 0xA5);
+        io.ui_event(Ev::P1Left, true);
+        let state = io.state();
+
+        io.send(SR_DATA_PORT, 0x12);
+        io.ui_event(Ev::P1Left, false);
+        io.ui_event(Ev::Coin, true);
+        io.restore(&state);
+
+        assert_eq!(state, io.state());
+        assert_eq!(Ev::P1Left.mask(), io.inputs());
+    }
+
     #[rstest]
     fn should_implement_shift_register(io: IO) {
         assert_eq!(0x00, io.read(PORT3));
@@ -1,4 +1,4 @@
-#[derive(Clone, Copy, Default)]
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
 pub struct ShiftRegister {
     value: u16,
     offset: u8,
@@ -16,6 +16,15 @@ impl ShiftRegister {
     pub fn set_offset(&mut self, offset: u8) {
         self.offset = offset & 0x07;
     }
+
+    /// Register value and offset.
+    pub fn raw(&self) -> (u16, u8) {
+        (self.value, self.offset)
+    }
+
+    pub fn from_raw(value: u16, offset: u8) -> Self {
+        ShiftRegister { value, offset: offset & 0x07 }
+    }
 }
 
 impl From<u16> for ShiftRegister {
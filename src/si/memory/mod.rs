@@ -1,4 +1,5 @@
-use std::ptr;
+use std::rc::Rc;
+use std::cell::RefCell;
 
 use rs8080::{
     Byte, Address,
@@ -7,6 +8,8 @@ use rs8080::{
     },
     mmu::Mmu
 };
+use trace::{Tracer, Access};
+use render::DirtyMap;
 
 
 trait MBank: Mmu {
@@ -75,13 +78,47 @@ impl Mmu for Rom {
     }
 }
 
-struct Ram {
-    data: [Byte; RAM_SIZE],
+/// Work ram. Cloning it give you another handle to the same memory: that's
+/// how `SpaceInvaders` can read and patch ram while the cpu owns the mmu.
+#[derive(Clone)]
+pub struct Ram {
+    data: Rc<RefCell<[Byte; RAM_SIZE]>>,
 }
 
 impl Default for Ram {
     fn default() -> Self {
-        Ram { data: [0; RAM_SIZE] }
+        Ram { data: Rc::new(RefCell::new([0; RAM_SIZE])) }
+    }
+}
+
+impl Ram {
+    /// Read the byte at cpu `address`, `None` if it's not a ram address.
+    pub fn get(&self, address: Address) -> Option<Byte> {
+        match self.contains(address) {
+            true => Some(self.data.borrow()[self.address(address)]),
+            false => None
+        }
+    }
+
+    /// Write the byte at cpu `address`: return false if it's not a ram address.
+    pub fn set(&self, address: Address, val: Byte) -> bool {
+        if !self.contains(address) {
+            return false;
+        }
+        self.data.borrow_mut()[self.address(address)] = val;
+        true
+    }
+
+    pub fn addresses(&self) -> ::std::ops::Range<Address> {
+        (RAM_OFFSET as Address)..((RAM_OFFSET + RAM_SIZE) as Address)
+    }
+
+    pub fn snapshot(&self) -> Vec<Byte> {
+        self.data.borrow().to_vec()
+    }
+
+    pub fn load(&self, data: &[Byte]) {
+        self.data.borrow_mut().copy_from_slice(data);
     }
 }
 
@@ -91,45 +128,71 @@ impl MBank for Ram {
     }
 
     fn size(&self) -> usize {
-        self.data.len()
+        RAM_SIZE
     }
 }
 
 impl Mmu for Ram {
     fn read_byte(&self, address: Address) -> Result<Byte> {
-        Ok(self.data[self.address(address)])
+        Ok(self.data.borrow()[self.address(address)])
     }
 
     fn write_byte(&mut self, address: Address, val: Byte) -> Result<()> {
         let address = self.address(address);
-        self.data[address] = val;
+        self.data.borrow_mut()[address] = val;
         Ok(())
     }
 
     fn dump(&self) -> String {
-        str_memory(&self.data, self.offset(), DUMP_MEMORY_COLUMNS)
+        str_memory(&*self.data.borrow(), self.offset(), DUMP_MEMORY_COLUMNS)
     }
 }
 
+/// Video ram. Like `Ram` it's a shared handle: the machine owns the memory
+/// and the clones give access to it to renderers and snapshots. Every write
+/// is tracked in a dirty map till the next `clear_dirty()`.
+#[derive(Clone)]
 pub struct VRam {
-    ptr: *mut Byte,
+    data: Rc<RefCell<[Byte; VRAM_SIZE]>>,
+    dirty: Rc<RefCell<DirtyMap>>,
 }
 
-impl From<*mut Byte> for VRam {
-    fn from(ptr: *mut Byte) -> Self {
-        Self::new(ptr)
+impl Default for VRam {
+    fn default() -> Self {
+        VRam {
+            data: Rc::new(RefCell::new([0; VRAM_SIZE])),
+            dirty: Default::default(),
+        }
     }
 }
 
 impl VRam {
-    pub fn new(ptr: *mut Byte) -> Self {
-        VRam { ptr }
+    pub fn snapshot(&self) -> Vec<Byte> {
+        self.data.borrow().to_vec()
     }
-}
 
-impl Default for VRam {
-    fn default() -> Self {
-        Self::new(ptr::null_mut())
+    pub fn load(&self, data: &[Byte]) {
+        self.data.borrow_mut().copy_from_slice(data);
+        self.dirty.borrow_mut().mark_all();
+    }
+
+    pub fn dirty(&self) -> ::std::cell::Ref<DirtyMap> {
+        self.dirty.borrow()
+    }
+
+    pub fn clear_dirty(&self) {
+        self.dirty.borrow_mut().clear();
+    }
+
+    /// Borrow the video memory for reading.
+    pub fn data(&self) -> ::std::cell::Ref<[Byte; VRAM_SIZE]> {
+        self.data.borrow()
+    }
+
+    /// Stable address of the video memory: it's valid as long as any handle
+    /// of this memory is alive.
+    pub fn as_ptr(&self) -> *const Byte {
+        self.data.as_ptr() as *const Byte
     }
 }
 
@@ -145,23 +208,18 @@ impl MBank for VRam {
 
 impl Mmu for VRam {
     fn read_byte(&self, address: Address) -> Result<Byte> {
-        let addr = self.address(address);
-        let val = unsafe {
-            *self.ptr.offset(addr as isize)
-        };
-        debug!("Read Vram [0x{:04x}]=0x{:02x}", address, val);
-        Ok(val)
+        Ok(self.data.borrow()[self.address(address)])
     }
 
     fn write_byte(&mut self, address: Address, val: Byte) -> Result<()> {
-        debug!("Write Vram [0x{:04x}]=0x{:02x}", address, val);
         let address = self.address(address);
-        unsafe { *self.ptr.offset(address as isize) = val }
+        self.data.borrow_mut()[address] = val;
+        self.dirty.borrow_mut().mark(address);
         Ok(())
     }
 
     fn dump(&self) -> String {
-        format!("No Data!")
+        str_memory(&*self.data.borrow(), self.offset(), DUMP_MEMORY_COLUMNS)
     }
 }
 
@@ -201,54 +259,50 @@ pub struct SIMmu {
     ram: Ram,
     vram: VRam,
     mirror: Mirror,
+    tracer: Option<Rc<Tracer>>,
 }
 
 impl SIMmu {
     #[allow(dead_code)]
-    pub fn new(rom: Rom, vram: VRam) -> SIMmu {
+    pub fn new(rom: Rom) -> SIMmu {
         SIMmu {
             rom,
-            vram,
             ..Default::default()
         }
     }
 
-    fn should_ignore_it(&self, address: Address) -> bool {
-        return 0x4000 <= address && address < 0x4200
+    pub fn ram(&self) -> Ram {
+        self.ram.clone()
+    }
+
+    pub fn vram(&self) -> VRam {
+        self.vram.clone()
+    }
+
+    pub fn with_tracer(self, tracer: Rc<Tracer>) -> Self {
+        SIMmu {
+            tracer: Some(tracer),
+            ..self
+        }
+    }
+
+    fn trace(&self, access: Access) {
+        if let Some(ref tracer) = self.tracer {
+            tracer.access(access);
+        }
     }
 }
 
 impl Mmu for SIMmu {
     fn read_byte(&self, address: Address) -> Result<Byte> {
-        if self.rom.contains(address) {
-            self.rom.read_byte(address)
-        } else if self.ram.contains(address) {
-            self.ram.read_byte(address)
-        } else if self.vram.contains(address) {
-            self.vram.read_byte(address)
-        } else if self.mirror.contains(address) {
-            self.mirror.read_byte(address)
-        } else {
-            unreachable!()
-        }
+        let val = self.decode_read(address)?;
+        self.trace(Access::Read(address, val));
+        Ok(val)
     }
 
     fn write_byte(&mut self, address: Address, val: Byte) -> Result<()> {
-        if self.should_ignore_it(address) {
-            debug!("Write access to ignore address 0x{:04x} = 0x{:02x}", address, val);
-            return Ok(())
-        }
-        if self.rom.contains(address) {
-            self.rom.write_byte(address, val)
-        } else if self.ram.contains(address) {
-            self.ram.write_byte(address, val)
-        } else if self.vram.contains(address) {
-            self.vram.write_byte(address, val)
-        } else if self.mirror.contains(address) {
-            self.mirror.write_byte(address, val)
-        } else {
-            unreachable!()
-        }
+        self.trace(Access::Write(address, val));
+        self.decode_write(address, val)
     }
 
     fn dump(&self) -> String {
@@ -263,6 +317,31 @@ Mirror:
     }
 }
 
+// Banks are selected by the address high bits: rom 0x0000-0x1FFF, ram
+// 0x2000-0x23FF, video ram 0x2400-0x3FFF and everything else is mirror.
+impl SIMmu {
+    #[inline]
+    fn decode_read(&self, address: Address) -> Result<Byte> {
+        match address >> 10 {
+            0x00..=0x07 => self.rom.read_byte(address),
+            0x08 => self.ram.read_byte(address),
+            0x09..=0x0F => self.vram.read_byte(address),
+            _ => self.mirror.read_byte(address),
+        }
+    }
+
+    #[inline]
+    fn decode_write(&mut self, address: Address, val: Byte) -> Result<()> {
+        match address >> 9 {
+            0x00..=0x0F => self.rom.write_byte(address, val),
+            0x10..=0x11 => self.ram.write_byte(address, val),
+            0x12..=0x1F => self.vram.write_byte(address, val),
+            0x20 => Ok(()),
+            _ => self.mirror.write_byte(address, val),
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -288,6 +367,30 @@ mod test {
 
             assert_eq!(Ok(value), zmem.read_byte(address))
         }
+
+        #[test]
+        fn handle_should_share_memory() {
+            let mut zmem = zmem();
+            let ram = zmem.ram();
+
+            zmem.write_byte(0x20EB, 0x12).unwrap();
+            assert_eq!(Some(0x12), ram.get(0x20EB));
+
+            assert!(ram.set(0x21FF, 0x03));
+            assert_eq!(Ok(0x03), zmem.read_byte(0x21FF));
+        }
+
+        #[rstest_parametrize(
+        address,
+        case(0x1FFF),
+        case(0x2400),
+        )]
+        fn handle_should_ignore_out_of_ram_addresses(zmem: SIMmu, address: Address) {
+            let ram = zmem.ram();
+
+            assert_eq!(None, ram.get(address));
+            assert!(!ram.set(address, 0x00));
+        }
     }
 
     mod rom {
@@ -326,13 +429,36 @@ mod test {
         case(0x2420, 0xA5),
         case(0x2FFF, 0x1A),
         )]
-        fn write_and_read(address: Address, value: Byte) {
-            let mut vram = [0; VRAM_SIZE];
-            let mut mem = SIMmu { vram: VRam::new(vram.as_mut_ptr()), ..Default::default() };
+        fn write_and_read(mut zmem: SIMmu, address: Address, value: Byte) {
+            zmem.write_byte(address, value).unwrap();
+
+            assert_eq!(value, zmem.read_byte(address).unwrap());
+        }
+
+        #[test]
+        fn every_machine_should_own_its_vram() {
+            let mut first = zmem();
+            let second = zmem();
+            let ptr = first.vram().as_ptr();
+
+            first.write_byte(0x2400, 0xFF).unwrap();
+
+            assert_eq!(0xFF, first.vram().data()[0]);
+            assert_eq!(0x00, second.vram().data()[0]);
+            assert_ne!(first.vram().as_ptr(), second.vram().as_ptr());
+            assert_eq!(ptr, first.vram().as_ptr());
+        }
+
+        #[test]
+        fn write_should_mark_dirty() {
+            let mut zmem = zmem();
+            let vram = zmem.vram();
 
-            mem.write_byte(address, value).unwrap();
+            zmem.write_byte(0x2421, 0x10).unwrap();
 
-            assert_eq!(value, mem.read_byte(address).unwrap());
+            assert!(vram.dirty().is_dirty(0x21));
+            vram.clear_dirty();
+            assert!(vram.dirty().is_clean());
         }
     }
 
@@ -0,0 +1,360 @@
+//! Machine save states.
+//!
+//! A `Snapshot` is taken between two frames and contains everything that
+//! `next_frame()` needs to produce the same result: run a frame from a restored
+//! snapshot with the same inputs and you get the same machine state.
+//!
+//! Encoded snapshots are (multi bytes values are little endian):
+//!
+//! | Size   | Content                                  |
+//! |--------|------------------------------------------|
+//! | 4      | `SIST`                                   |
+//! | 1      | Version (4)                              |
+//! | 8      | Registers A, F, B, C, D, E, H, L         |
+//! | 4      | Registers SP and PC                      |
+//! | 1      | INTE (bit 0) and halted (bit 1)          |
+//! | 8      | Clocks                                   |
+//! | 8      | Frames                                   |
+//! | 2      | Input ports 1 and 2                      |
+//! | 3      | Shift register value and offset          |
+//! | 4      | Pressed inputs mask                      |
+//! | 1      | Flip screen (0 or 1)                     |
+//! | 12     | Coin switch frames, credits, free phase  |
+//! | 1      | Coin accepted (bit 0), playing (bit 1)   |
+//! | 8      | Coin counter                             |
+//! | 40     | Coins, rejected, credits used, games and |
+//! |        | free play pulses                         |
+//! | 40     | Autofire periods (`Ev` order)            |
+//! | 40     | Autofire held counters                   |
+//! | 0x0400 | Ram                                      |
+//! | 0x1C00 | Video ram                                |
+//! | 1      | Macro in play (0 or 1)                   |
+//!
+//! and, if there is a macro in play:
+//!
+//! | Size   | Content                                  |
+//! |--------|------------------------------------------|
+//! | 8      | Frames played                            |
+//! | 4      | Next event                               |
+//! | 4      | Pressed inputs mask                      |
+//! | 4      | Events count                             |
+//! | 10     | Every event: frame, `Ev` and pressed     |
+
+use std::fmt;
+
+use rs8080::Byte;
+use assist::{AssistState, Macro, Playing};
+use coin::{Bookkeeping, CoinState};
+use si::io::{Ev, IoState, ShiftRegister};
+use si::memory::{RAM_SIZE, VRAM_SIZE};
+use trace::Registers;
+
+const MAGIC: &[u8] = b"SIST";
+const VERSION: u8 = 4;
+
+const INTE: u8 = 0x01;
+const HALTED: u8 = 0x02;
+
+const ACCEPTED: u8 = 0x01;
+const PLAYING: u8 = 0x02;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Snapshot {
+    pub regs: Registers,
+    pub ram: Vec<Byte>,
+    pub vram: Vec<Byte>,
+    pub io: IoState,
+    pub coin: CoinState,
+    pub assist: AssistState,
+    pub clocks: u64,
+    pub frames: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    Magic,
+    Version(u8),
+    Size(usize),
+    Macro,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DecodeError::Magic => write!(f, "Not a snapshot"),
+            DecodeError::Version(v) => write!(f, "Unknown snapshot version {}", v),
+            DecodeError::Size(size) => write!(f, "Wrong snapshot size {}", size),
+            DecodeError::Macro => write!(f, "Invalid macro in snapshot"),
+        }
+    }
+}
+
+/// The size of a snapshot without a macro in play: a macro adds
+/// `MACRO_SIZE` bytes and `EVENT_SIZE` for every event.
+pub const ENCODED_SIZE: usize = 4 + 1 + 8 + 4 + 1 + 8 + 8 + 2 + 3 + 4 + 1 + 12 + 1 + 8 + 40 + 40 + 40
+    + RAM_SIZE + VRAM_SIZE + 1;
+pub const MACRO_SIZE: usize = 8 + 4 + 4 + 4;
+pub const EVENT_SIZE: usize = 8 + 1 + 1;
+
+fn put(out: &mut Vec<u8>, v: u64, size: usize) {
+    out.extend((0..size).map(|i| (v >> (8 * i)) as u8));
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> &'a [u8] {
+        let (head, tail) = self.data.split_at(n);
+        self.data = tail;
+        head
+    }
+
+    fn remaining(&self) -> usize {
+        self.data.len()
+    }
+
+    fn get(&mut self, size: usize) -> u64 {
+        self.take(size).iter().rev().fold(0, |acc, &b| (acc << 8) | b as u64)
+    }
+}
+
+impl Snapshot {
+    pub fn encode(&self) -> Vec<u8> {
+        let r = &self.regs;
+        let mut out = Vec::with_capacity(ENCODED_SIZE + self.assist.playing.as_ref()
+            .map(|p| MACRO_SIZE + EVENT_SIZE * p.events.events().len())
+            .unwrap_or(0));
+        out.extend_from_slice(MAGIC);
+        out.push(VERSION);
+        out.extend_from_slice(&[r.a, r.flags, r.b, r.c, r.d, r.e, r.h, r.l]);
+        put(&mut out, r.sp as u64, 2);
+        put(&mut out, r.pc as u64, 2);
+        out.push(if r.inte { INTE } else { 0 } | if r.halted { HALTED } else { 0 });
+        put(&mut out, self.clocks, 8);
+        put(&mut out, self.frames, 8);
+        out.push(self.io.port1);
+        out.push(self.io.port2);
+        let (value, offset) = self.io.sr.raw();
+        put(&mut out, value as u64, 2);
+        out.push(offset);
+        put(&mut out, self.io.inputs as u64, 4);
+        out.push(self.io.flip as u8);
+        let c = &self.coin;
+        put(&mut out, c.closed as u64, 4);
+        put(&mut out, c.credits as u64, 4);
+        put(&mut out, c.free_phase as u64, 4);
+        out.push(if c.accepted { ACCEPTED } else { 0 } | if c.playing { PLAYING } else { 0 });
+        put(&mut out, c.counter, 8);
+        let b = &c.bookkeeping;
+        for &v in [b.coins, b.rejected, b.credits_used, b.games, b.free].iter() {
+            put(&mut out, v, 8);
+        }
+        for &v in self.assist.autofire.iter().chain(self.assist.held.iter()) {
+            put(&mut out, v as u64, 4);
+        }
+        out.extend_from_slice(&self.ram);
+        out.extend_from_slice(&self.vram);
+        match self.assist.playing {
+            Some(ref p) => {
+                out.push(1);
+                put(&mut out, p.frame, 8);
+                put(&mut out, p.next as u64, 4);
+                put(&mut out, p.inputs as u64, 4);
+                put(&mut out, p.events.events().len() as u64, 4);
+                for &(frame, ev, pressed) in p.events.events().iter() {
+                    put(&mut out, frame, 8);
+                    out.push(ev as u8);
+                    out.push(pressed as u8);
+                }
+            }
+            None => out.push(0),
+        }
+        out
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, DecodeError> {
+        if !data.starts_with(MAGIC) {
+            return Err(DecodeError::Magic);
+        }
+        if data.len() > MAGIC.len() && data[MAGIC.len()] != VERSION {
+            return Err(DecodeError::Version(data[MAGIC.len()]));
+        }
+        if data.len() < ENCODED_SIZE {
+            return Err(DecodeError::Size(data.len()));
+        }
+        let mut reader = Reader { data: &data[MAGIC.len() + 1..] };
+        let r = reader.take(8);
+        let sp = reader.get(2) as u16;
+        let pc = reader.get(2) as u16;
+        let cpu_flags = reader.get(1) as u8;
+        let regs = Registers {
+            a: r[0], flags: r[1], b: r[2], c: r[3], d: r[4], e: r[5], h: r[6], l: r[7],
+            sp,
+            pc,
+            inte: cpu_flags & INTE != 0,
+            halted: cpu_flags & HALTED != 0,
+        };
+        let clocks = reader.get(8);
+        let frames = reader.get(8);
+        let port1 = reader.get(1) as u8;
+        let port2 = reader.get(1) as u8;
+        let sr = ShiftRegister::from_raw(reader.get(2) as u16, reader.get(1) as u8);
+        let io = IoState {
+            port1,
+            port2,
+            sr,
+            inputs: reader.get(4) as u32,
+            flip: reader.get(1) != 0,
+        };
+        let closed = reader.get(4) as u32;
+        let credits = reader.get(4) as u32;
+        let free_phase = reader.get(4) as u32;
+        let coin_flags = reader.get(1) as u8;
+        let coin = CoinState {
+            closed,
+            accepted: coin_flags & ACCEPTED != 0,
+            credits,
+            playing: coin_flags & PLAYING != 0,
+            free_phase,
+            counter: reader.get(8),
+            bookkeeping: Bookkeeping {
+                coins: reader.get(8),
+                rejected: reader.get(8),
+                credits_used: reader.get(8),
+                games: reader.get(8),
+                free: reader.get(8),
+            },
+        };
+        let mut assist = AssistState::default();
+        for v in assist.autofire.iter_mut().chain(assist.held.iter_mut()) {
+            *v = reader.get(4) as u32;
+        }
+        let ram = reader.take(RAM_SIZE).to_vec();
+        let vram = reader.take(VRAM_SIZE).to_vec();
+        if reader.get(1) != 0 {
+            assist.playing = Some(Self::decode_playing(&mut reader, data.len())?);
+        }
+        if reader.remaining() != 0 {
+            return Err(DecodeError::Size(data.len()));
+        }
+        Ok(Snapshot {
+            regs,
+            clocks,
+            frames,
+            io,
+            coin,
+            assist,
+            ram,
+            vram,
+        })
+    }
+
+    /// The macro in play of a snapshot of `size` bytes.
+    fn decode_playing(reader: &mut Reader, size: usize) -> Result<Playing, DecodeError> {
+        if reader.remaining() < MACRO_SIZE {
+            return Err(DecodeError::Size(size));
+        }
+        let frame = reader.get(8);
+        let next = reader.get(4) as usize;
+        let inputs = reader.get(4) as u32;
+        let count = reader.get(4) as usize;
+        if reader.remaining() < count.saturating_mul(EVENT_SIZE) {
+            return Err(DecodeError::Size(size));
+        }
+        let mut events = Vec::with_capacity(count);
+        for _ in 0..count {
+            let frame = reader.get(8);
+            let ev = match Ev::ALL.get(reader.get(1) as usize) {
+                Some(&ev) => ev,
+                None => return Err(DecodeError::Macro),
+            };
+            events.push((frame, ev, reader.get(1) != 0));
+        }
+        let events = match Macro::from_events(events) {
+            Some(events) if next <= events.events().len() => events,
+            _ => return Err(DecodeError::Macro),
+        };
+        Ok(Playing { frame, next, inputs, events })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn snapshot() -> Snapshot {
+        Snapshot {
+            regs: Registers {
+                a: 1, flags: 2, b: 3, c: 4, d: 5, e: 6, h: 7, l: 8, sp: 0x2400, pc: 0x1a5f,
+                inte: true, halted: false,
+            },
+            ram: (0..RAM_SIZE).map(|i| i as u8).collect(),
+            vram: (0..VRAM_SIZE).map(|i| (i * 7) as u8).collect(),
+            io: IoState {
+                port1: 0x81,
+                port2: 0x0b,
+                sr: ShiftRegister::from_raw(0xA5FF, 3),
+                inputs: 0x3C0,
+                flip: true,
+            },
+            coin: CoinState {
+                closed: 3,
+                accepted: true,
+                credits: 12,
+                playing: true,
+                free_phase: 5,
+                counter: 4321,
+                bookkeeping: Bookkeeping { coins: 15, rejected: 2, credits_used: 3, games: 2, free: 1 },
+            },
+            assist: AssistState {
+                autofire: [0, 0, 0, 4, 0, 0, 0, 2, 0, 0],
+                held: [0, 0, 0, 3, 0, 0, 0, 1, 0, 0],
+                playing: Some(Playing {
+                    frame: 7,
+                    next: 1,
+                    inputs: Ev::P1Left.mask(),
+                    events: Macro::parse("0 p1left down\n12 p1shoot down\n20 p1left up").unwrap(),
+                }),
+            },
+            clocks: 0x0123_4567_89AB,
+            frames: 1234,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let snapshot = snapshot();
+
+        let data = snapshot.encode();
+
+        assert_eq!(ENCODED_SIZE + MACRO_SIZE + 3 * EVENT_SIZE, data.len());
+        assert_eq!(Ok(snapshot), Snapshot::decode(&data));
+    }
+
+    #[test]
+    fn without_macro_round_trip() {
+        let mut snapshot = snapshot();
+        snapshot.assist.playing = None;
+
+        let data = snapshot.encode();
+
+        assert_eq!(ENCODED_SIZE, data.len());
+        assert_eq!(Ok(snapshot), Snapshot::decode(&data));
+    }
+
+    #[test]
+    fn invalid_data() {
+        let mut data = snapshot().encode();
+
+        assert_eq!(Err(DecodeError::Size(ENCODED_SIZE - 1)), Snapshot::decode(&data[..ENCODED_SIZE - 1]));
+        let len = data.len();
+        assert_eq!(Err(DecodeError::Size(len - 1)), Snapshot::decode(&data[..len - 1]));
+        data[len - 2] = 10;
+        assert_eq!(Err(DecodeError::Macro), Snapshot::decode(&data));
+        data[4] = 9;
+        assert_eq!(Err(DecodeError::Version(9)), Snapshot::decode(&data));
+        assert_eq!(Err(DecodeError::Magic), Snapshot::decode(&data[1..]));
+    }
+}
@@ -0,0 +1,340 @@
+//! Spectator stream.
+//!
+//! A running session is broadcast as a stream of records: the inputs of every
+//! frame and, every `interval` frames, a keyframe with the machine snapshot.
+//! A spectator that joins late starts from the latest keyframe and replays the
+//! following inputs to catch up.
+//!
+//! Every record is encoded as a tag byte followed by the frame number (u64
+//! little endian) and:
+//!
+//! * `I` (input): the inputs mask of the frame (u32 little endian)
+//! * `K` (keyframe): the encoded `Snapshot` taken before the frame
+//!
+//! The broadcaster sends a keyframe after a ram change that the inputs don't
+//! reproduce too (see `SpaceInvaders::hiscore_restored()`).
+//!
+//! The recorded inputs already went through the broadcaster assists
+//! (autofire and macros): the spectator replays them as they are.
+//!
+//! The stream doesn't need any transport feature but ordering: `Channel` is
+//! the in memory implementation.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+
+use snapshot::{Snapshot, DecodeError};
+use SpaceInvaders;
+
+/// Default frames between two keyframes (10 seconds).
+pub const KEYFRAME_INTERVAL: u64 = 600;
+
+const INPUT_TAG: u8 = b'I';
+const KEYFRAME_TAG: u8 = b'K';
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Record {
+    Input { frame: u64, inputs: u32 },
+    Keyframe { frame: u64, snapshot: Snapshot },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StreamError {
+    Tag(u8),
+    Truncated,
+    Snapshot(DecodeError),
+    /// Got input for `got` but the spectator machine is at frame `expected`.
+    Frame { expected: u64, got: u64 },
+}
+
+impl fmt::Display for StreamError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StreamError::Tag(tag) => write!(f, "Unknown record tag {:02x}", tag),
+            StreamError::Truncated => write!(f, "Truncated record"),
+            StreamError::Snapshot(e) => write!(f, "Invalid keyframe: {}", e),
+            StreamError::Frame { expected, got } =>
+                write!(f, "Lost stream sync: input for frame {} at frame {}", got, expected),
+        }
+    }
+}
+
+fn le(data: &[u8]) -> u64 {
+    data.iter().rev().fold(0, |acc, &b| (acc << 8) | b as u64)
+}
+
+impl Record {
+    pub fn frame(&self) -> u64 {
+        match *self {
+            Record::Input { frame, .. } | Record::Keyframe { frame, .. } => frame,
+        }
+    }
+
+    pub fn is_keyframe(&self) -> bool {
+        match *self {
+            Record::Keyframe { .. } => true,
+            _ => false,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let (tag, payload) = match *self {
+            Record::Input { inputs, .. } => (INPUT_TAG, (0..4).map(|i| (inputs >> (8 * i)) as u8).collect::<Vec<_>>()),
+            Record::Keyframe { ref snapshot, .. } => (KEYFRAME_TAG, snapshot.encode()),
+        };
+        let frame = self.frame();
+        let mut out = vec![tag];
+        out.extend((0..8).map(|i| (frame >> (8 * i)) as u8));
+        out.extend(payload);
+        out
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, StreamError> {
+        if data.len() < 9 {
+            return Err(StreamError::Truncated);
+        }
+        let frame = le(&data[1..9]);
+        let payload = &data[9..];
+        match data[0] {
+            INPUT_TAG if payload.len() == 4 => Ok(Record::Input { frame, inputs: le(payload) as u32 }),
+            INPUT_TAG => Err(StreamError::Truncated),
+            KEYFRAME_TAG => Snapshot::decode(payload)
+                .map(|snapshot| Record::Keyframe { frame, snapshot })
+                .map_err(StreamError::Snapshot),
+            tag => Err(StreamError::Tag(tag)),
+        }
+    }
+}
+
+/// Produce the records of a running machine: use `frame()` in place of
+/// `set_inputs()` and `next_frame()`.
+pub struct Broadcaster {
+    interval: u64,
+}
+
+impl Default for Broadcaster {
+    fn default() -> Self {
+        Self::new(KEYFRAME_INTERVAL)
+    }
+}
+
+impl Broadcaster {
+    pub fn new(interval: u64) -> Self {
+        Broadcaster { interval: interval.max(1) }
+    }
+
+    /// Run a frame with the user `inputs` and return its records.
+    pub fn frame(&self, si: &mut SpaceInvaders, inputs: u32) -> Vec<Record> {
+        let frame = si.frame();
+        let mut records = Vec::with_capacity(2);
+        if frame % self.interval == 0 || si.hiscore_restored() {
+            records.push(Record::Keyframe { frame, snapshot: si.save_state() });
+        }
+        si.set_inputs(inputs);
+        let assisted = si.assist_mut().step(inputs);
+        si.replay_frame(assisted);
+        records.push(Record::Input { frame, inputs: assisted });
+        records
+    }
+}
+
+/// Follow a stream with a local machine.
+#[derive(Default)]
+pub struct Spectator {
+    synced: bool,
+}
+
+impl Spectator {
+    pub fn synced(&self) -> bool {
+        self.synced
+    }
+
+    /// Apply a record: inputs are ignored till the first keyframe.
+    pub fn feed(&mut self, si: &mut SpaceInvaders, record: &Record) -> Result<(), StreamError> {
+        match *record {
+            Record::Keyframe { ref snapshot, .. } => {
+                si.load_state(snapshot);
+                self.synced = true;
+            }
+            Record::Input { frame, inputs } if self.synced => {
+                if frame != si.frame() {
+                    self.synced = false;
+                    return Err(StreamError::Frame { expected: si.frame(), got: frame });
+                }
+                si.set_inputs(inputs);
+                si.replay_frame(inputs);
+            }
+            Record::Input { .. } => {}
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct Log {
+    records: Vec<Vec<u8>>,
+    last_keyframe: Option<usize>,
+}
+
+/// In memory broadcast channel: every `Receiver` get all the records published
+/// from the last keyframe before it subscribed.
+#[derive(Clone, Default)]
+pub struct Channel {
+    log: Rc<RefCell<Log>>,
+}
+
+impl Channel {
+    pub fn publish(&self, record: &Record) {
+        let mut log = self.log.borrow_mut();
+        if record.is_keyframe() {
+            log.last_keyframe = Some(log.records.len());
+        }
+        log.records.push(record.encode());
+    }
+
+    pub fn subscribe(&self) -> Receiver {
+        let log = self.log.borrow();
+        Receiver {
+            log: self.log.clone(),
+            cursor: log.last_keyframe.unwrap_or(log.records.len()),
+        }
+    }
+}
+
+pub struct Receiver {
+    log: Rc<RefCell<Log>>,
+    cursor: usize,
+}
+
+impl Receiver {
+    pub fn recv(&mut self) -> Option<Result<Record, StreamError>> {
+        let log = self.log.borrow();
+        let data = log.records.get(self.cursor)?;
+        self.cursor += 1;
+        Some(Record::decode(data))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use Game;
+    use Ev;
+    use hiscore;
+
+    #[test]
+    fn input_record_round_trip() {
+        let record = Record::Input { frame: 0x1234_5678, inputs: 0x3C1 };
+
+        assert_eq!(Ok(record.clone()), Record::decode(&record.encode()));
+    }
+
+    #[test]
+    fn keyframe_record_round_trip() {
+        let mut game = Game::new();
+        let mut si = game.space_invaders();
+        si.next_frame();
+        let record = Record::Keyframe { frame: si.frame(), snapshot: si.save_state() };
+
+        assert_eq!(Ok(record.clone()), Record::decode(&record.encode()));
+    }
+
+    #[test]
+    fn invalid_records() {
+        assert_eq!(Err(StreamError::Truncated), Record::decode(b"I\x00\x00"));
+        assert_eq!(Err(StreamError::Tag(b'X')), Record::decode(b"X\x00\x00\x00\x00\x00\x00\x00\x00"));
+    }
+
+    fn inputs(frame: u64) -> u32 {
+        match frame {
+            100..=104 => Ev::Coin.mask(),
+            150..=154 => Ev::P1Start.mask(),
+            f if f > 200 && f % 20 < 10 => Ev::P1Left.mask() | Ev::P1Shoot.mask(),
+            f if f > 200 => Ev::P1Right.mask(),
+            _ => 0,
+        }
+    }
+
+    #[test]
+    fn late_spectator_should_catch_up() {
+        let channel = Channel::default();
+        let broadcaster = Broadcaster::new(100);
+        let mut game = Game::new();
+        let mut si = game.space_invaders();
+        let mut spectator_game = Game::new();
+        let mut spectator_si = spectator_game.space_invaders();
+        let mut spectator = Spectator::default();
+        let mut receiver = None;
+
+        for _ in 0..400 {
+            let frame = si.frame();
+            if frame == 250 {
+                receiver = Some(channel.subscribe());
+            }
+            for record in broadcaster.frame(&mut si, inputs(frame)) {
+                channel.publish(&record);
+            }
+        }
+        let mut receiver = receiver.unwrap();
+        while let Some(record) = receiver.recv() {
+            spectator.feed(&mut spectator_si, &record.unwrap()).unwrap();
+        }
+
+        assert!(spectator.synced());
+        assert_eq!(si.save_state(), spectator_si.save_state());
+    }
+
+    #[test]
+    fn spectator_should_follow_the_hiscore_restore() {
+        let broadcaster = Broadcaster::default();
+        let mut game = Game::new();
+        let mut si = game.space_invaders();
+        si.enable_hiscore(Some(hiscore::encode(1230)));
+        let mut spectator_si = game.space_invaders();
+        spectator_si.enable_hiscore(Some(hiscore::encode(50)));
+        let mut spectator = Spectator::default();
+
+        for _ in 0..200 {
+            for record in broadcaster.frame(&mut si, 0) {
+                spectator.feed(&mut spectator_si, &record).unwrap();
+            }
+        }
+
+        assert_eq!(1230, spectator_si.hi_score());
+        assert_eq!(si.save_state(), spectator_si.save_state());
+    }
+
+    #[test]
+    fn spectator_should_follow_the_autofire() {
+        let broadcaster = Broadcaster::new(100);
+        let mut game = Game::new();
+        let mut si = game.space_invaders();
+        si.set_autofire(Ev::P1Shoot, 3);
+        let mut spectator_game = Game::new();
+        let mut spectator_si = spectator_game.space_invaders();
+        let mut spectator = Spectator::default();
+
+        for _ in 0..400 {
+            let frame = si.frame();
+            for record in broadcaster.frame(&mut si, inputs(frame)) {
+                spectator.feed(&mut spectator_si, &record).unwrap();
+            }
+        }
+
+        assert_eq!(si.save_state().ram, spectator_si.save_state().ram);
+        assert_eq!(si.save_state().vram, spectator_si.save_state().vram);
+    }
+
+    #[test]
+    fn spectator_should_wait_a_keyframe() {
+        let mut game = Game::new();
+        let mut si = game.space_invaders();
+        let mut spectator = Spectator::default();
+
+        spectator.feed(&mut si, &Record::Input { frame: 10, inputs: 0 }).unwrap();
+
+        assert!(!spectator.synced());
+        assert_eq!(0, si.frame());
+    }
+}
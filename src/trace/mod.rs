@@ -0,0 +1,347 @@
+//! Execution tracer.
+//!
+//! Every traced instruction produce a line in the following format (fields are hex
+//! numbers but `CYC` that is the decimal count of clocks executed before the
+//! instruction):
+//!
+//! ```text
+//! PC: 0000, AF: 0002, BC: 0000, DE: 0000, HL: 0000, SP: 0000, CYC: 0	(00) R 2000=00 W 2001=ff
+//! ```
+//!
+//! The part before the tab is the same layout used by the most common 8080
+//! emulators trace logs, so you can `cut -f1` it and diff against them. After the
+//! tab you find the opcode and every memory access done by the instruction
+//! (`R` for read and `W` for write).
+
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::Write;
+
+use rs8080::{Byte, Address, cpu::{CpuError, State}};
+
+/// How many instructions are attached to a crash report by default.
+pub const CRASH_HISTORY: usize = 1000;
+
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub struct Registers {
+    pub a: Byte,
+    pub flags: Byte,
+    pub b: Byte,
+    pub c: Byte,
+    pub d: Byte,
+    pub e: Byte,
+    pub h: Byte,
+    pub l: Byte,
+    pub sp: Address,
+    pub pc: Address,
+    /// Interrupts enabled (`EI`/`DI`).
+    pub inte: bool,
+    /// Stopped by `HLT`.
+    pub halted: bool,
+}
+
+/// Just the registers: the interrupt and halt flags are kept by the cpu
+/// (see `SpaceInvaders::registers()`).
+impl<'a> From<&'a State> for Registers {
+    fn from(state: &'a State) -> Self {
+        Registers {
+            a: state.a.val,
+            flags: state.flags.val,
+            b: state.b.val,
+            c: state.c.val,
+            d: state.d.val,
+            e: state.e.val,
+            h: state.h.val,
+            l: state.l.val,
+            sp: state.sp.val,
+            pc: state.pc.val,
+            inte: false,
+            halted: false,
+        }
+    }
+}
+
+impl Registers {
+    pub fn apply(&self, state: &mut State) {
+        state.a.val = self.a;
+        state.flags.val = self.flags;
+        state.b.val = self.b;
+        state.c.val = self.c;
+        state.d.val = self.d;
+        state.e.val = self.e;
+        state.h.val = self.h;
+        state.l.val = self.l;
+        state.sp.val = self.sp;
+        state.pc.val = self.pc;
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    Read(Address, Byte),
+    Write(Address, Byte),
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Access::Read(address, val) => write!(f, "R {:04x}={:02x}", address, val),
+            Access::Write(address, val) => write!(f, "W {:04x}={:02x}", address, val),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TraceEntry {
+    pub regs: Registers,
+    pub opcode: Option<Byte>,
+    pub clocks: u64,
+    pub accesses: Vec<Access>,
+}
+
+impl fmt::Display for TraceEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let r = &self.regs;
+        write!(f, "PC: {:04X}, AF: {:02X}{:02X}, BC: {:02X}{:02X}, DE: {:02X}{:02X}, \
+                   HL: {:02X}{:02X}, SP: {:04X}, CYC: {}\t",
+               r.pc, r.a, r.flags, r.b, r.c, r.d, r.e, r.h, r.l, r.sp, self.clocks)?;
+        match self.opcode {
+            Some(opcode) => write!(f, "({:02x})", opcode)?,
+            None => write!(f, "(--)")?,
+        }
+        for access in self.accesses.iter() {
+            write!(f, " {}", access)?;
+        }
+        Ok(())
+    }
+}
+
+/// Keep the last `capacity` executed instructions and optionally stream
+/// all of them to a `Write` sink. It's shared (by `Rc`) between `SpaceInvaders`,
+/// that marks the instructions boundaries, and `SIMmu` that records the memory
+/// accesses.
+pub struct Tracer {
+    enabled: Cell<bool>,
+    capacity: Cell<usize>,
+    current: RefCell<Option<TraceEntry>>,
+    history: RefCell<VecDeque<TraceEntry>>,
+    sink: RefCell<Option<Box<dyn Write>>>,
+}
+
+impl Tracer {
+    pub fn new(capacity: usize) -> Self {
+        Tracer {
+            enabled: Cell::new(false),
+            capacity: Cell::new(capacity),
+            current: RefCell::new(None),
+            history: RefCell::new(VecDeque::with_capacity(capacity)),
+            sink: RefCell::new(None),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled.get()
+    }
+
+    pub fn enable(&self, enabled: bool) {
+        self.enabled.set(enabled);
+        if !enabled {
+            *self.current.borrow_mut() = None;
+        }
+    }
+
+    pub fn set_capacity(&self, capacity: usize) {
+        self.capacity.set(capacity);
+        let mut history = self.history.borrow_mut();
+        while history.len() > capacity {
+            history.pop_front();
+        }
+    }
+
+    pub fn stream_to<W: Write + 'static>(&self, sink: W) {
+        *self.sink.borrow_mut() = Some(Box::new(sink));
+    }
+
+    pub fn close_stream(&self) {
+        if let Some(mut sink) = self.sink.borrow_mut().take() {
+            sink.flush().unwrap_or_else(|e| warn!("Cannot flush trace stream: {}", e));
+        }
+    }
+
+    pub fn begin(&self, regs: Registers, clocks: u64) {
+        if !self.enabled() {
+            return;
+        }
+        *self.current.borrow_mut() = Some(TraceEntry { regs, clocks, ..Default::default() });
+    }
+
+    pub fn access(&self, access: Access) {
+        if !self.enabled() {
+            return;
+        }
+        if let Some(ref mut entry) = *self.current.borrow_mut() {
+            match access {
+                Access::Read(address, val) if entry.opcode.is_none()
+                    && address == entry.regs.pc => entry.opcode = Some(val),
+                _ => entry.accesses.push(access)
+            }
+        }
+    }
+
+    pub fn end(&self) {
+        let entry = match self.current.borrow_mut().take() {
+            Some(entry) => entry,
+            None => return
+        };
+        if let Some(ref mut sink) = *self.sink.borrow_mut() {
+            writeln!(sink, "{}", entry).unwrap_or_else(|e| warn!("Cannot write trace: {}", e));
+        }
+        let capacity = self.capacity.get();
+        if capacity == 0 {
+            return;
+        }
+        let mut history = self.history.borrow_mut();
+        if history.len() >= capacity {
+            history.pop_front();
+        }
+        history.push_back(entry);
+    }
+
+    pub fn history(&self) -> Vec<TraceEntry> {
+        self.history.borrow().iter().cloned().collect()
+    }
+
+    pub fn clear(&self) {
+        self.history.borrow_mut().clear();
+    }
+
+    pub fn dump(&self) -> String {
+        self.history.borrow().iter()
+            .map(|e| e.to_string())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+impl Default for Tracer {
+    fn default() -> Self {
+        Self::new(CRASH_HISTORY)
+    }
+}
+
+/// A `CpuError` with the last executed instructions.
+pub struct Crash {
+    pub error: CpuError,
+    pub trace: Vec<TraceEntry>,
+}
+
+impl fmt::Debug for Crash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{:?}", self.error)?;
+        writeln!(f, "Last {} instructions:", self.trace.len())?;
+        for entry in self.trace.iter() {
+            writeln!(f, "{}", entry)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::rc::Rc;
+
+    fn tracer() -> Tracer {
+        let t = Tracer::new(3);
+        t.enable(true);
+        t
+    }
+
+    fn at(pc: Address) -> Registers {
+        Registers { pc, ..Default::default() }
+    }
+
+    fn run(tracer: &Tracer, pc: Address) {
+        tracer.begin(at(pc), 0);
+        tracer.access(Access::Read(pc, 0x00));
+        tracer.end();
+    }
+
+    #[test]
+    fn should_keep_just_last_entries() {
+        let tracer = tracer();
+
+        for pc in 0..5 {
+            run(&tracer, pc);
+        }
+
+        let pcs = tracer.history().iter().map(|e| e.regs.pc).collect::<Vec<_>>();
+        assert_eq!(vec![2, 3, 4], pcs);
+    }
+
+    #[test]
+    fn should_ignore_everything_when_disabled() {
+        let tracer = Tracer::new(3);
+
+        run(&tracer, 0x10);
+
+        assert!(tracer.history().is_empty());
+    }
+
+    #[test]
+    fn first_read_at_pc_is_the_opcode() {
+        let tracer = tracer();
+
+        tracer.begin(at(0x0100), 7);
+        tracer.access(Access::Read(0x0100, 0x32));
+        tracer.access(Access::Read(0x0101, 0x00));
+        tracer.access(Access::Read(0x0102, 0x20));
+        tracer.access(Access::Write(0x2000, 0xA5));
+        tracer.end();
+
+        let entry = &tracer.history()[0];
+        assert_eq!(Some(0x32), entry.opcode);
+        assert_eq!(3, entry.accesses.len());
+    }
+
+    #[test]
+    fn format_line() {
+        let entry = TraceEntry {
+            regs: Registers { a: 0x12, flags: 0x02, b: 0x34, c: 0x56, sp: 0x2400, pc: 0x1a5f, ..Default::default() },
+            opcode: Some(0xc3),
+            clocks: 42,
+            accesses: vec![Access::Read(0x1a60, 0x00), Access::Write(0x23fe, 0xff)],
+        };
+
+        assert_eq!("PC: 1A5F, AF: 1202, BC: 3456, DE: 0000, HL: 0000, SP: 2400, CYC: 42\t\
+                    (c3) R 1a60=00 W 23fe=ff", entry.to_string());
+    }
+
+    #[derive(Clone, Default)]
+    struct Sink(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Sink {
+        fn write(&mut self, buf: &[u8]) -> ::std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> ::std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn should_stream_all_entries() {
+        let tracer = tracer();
+        let sink = Sink::default();
+        tracer.stream_to(sink.clone());
+
+        for pc in 0..5 {
+            run(&tracer, pc);
+        }
+
+        let out = String::from_utf8(sink.0.borrow().clone()).unwrap();
+        assert_eq!(5, out.lines().count());
+    }
+}
@@ -0,0 +1,124 @@
+//! Golden frames regression tests.
+//!
+//! Every `tests/golden/NAME.movie` is played on a new machine and the video
+//! ram hash at its `check` frames is compared with the ones stored in
+//! `tests/golden/NAME.golden` (a `frame hash` line for each check).
+//!
+//! * A missing golden file is a failure: the golden files are committed
+//!   with the movies
+//! * `BLESS=1 cargo test --test golden` records (again) all golden files
+//! * `GOLDEN_PNG=1` also saves a screenshot for every blessed frame
+//!   (`NAME-FRAME.png`): when a check fails and its screenshot exists, the
+//!   actual screen and a diff (changed pixels in magenta) are written in
+//!   `target/golden`
+
+extern crate wasm_invaders;
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+use wasm_invaders::{Game, SpaceInvaders};
+use wasm_invaders::movie::Movie;
+use wasm_invaders::render::{png, Renderer, WIDTH, HEIGHT};
+
+fn golden_dir() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests").join("golden")
+}
+
+fn out_dir() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("target").join("golden")
+}
+
+fn flag(name: &str) -> bool {
+    env::var(name).map(|v| !v.is_empty() && v != "0").unwrap_or(false)
+}
+
+fn screen(si: &SpaceInvaders) -> Vec<u8> {
+    let mut renderer = Renderer::default();
+    renderer.render(&si.video_ram(), si.flipped());
+    renderer.rgba().to_vec()
+}
+
+fn parse_golden(text: &str) -> BTreeMap<u64, u64> {
+    text.lines()
+        .filter_map(|l| {
+            let mut words = l.split_whitespace();
+            let frame = words.next()?.parse().ok()?;
+            let hash = u64::from_str_radix(words.next()?, 16).ok()?;
+            Some((frame, hash))
+        })
+        .collect()
+}
+
+fn diff(expected: &[u8], actual: &[u8]) -> Vec<u8> {
+    expected.chunks(4).zip(actual.chunks(4))
+        .flat_map(|(e, a)| match e == a {
+            true => vec![a[0] / 4, a[1] / 4, a[2] / 4, 0xFF],
+            false => vec![0xFF, 0x00, 0xFF, 0xFF],
+        })
+        .collect()
+}
+
+fn check(name: &str) {
+    let dir = golden_dir();
+    let movie = Movie::parse(&fs::read_to_string(dir.join(format!("{}.movie", name))).unwrap())
+        .unwrap_or_else(|e| panic!("{}.movie: {}", name, e));
+    let mut game = Game::new();
+    let mut si = game.space_invaders();
+    let mut actual = BTreeMap::new();
+    let mut screens = BTreeMap::new();
+
+    movie.play(&mut si, |si| {
+        actual.insert(si.frame(), si.vram_hash());
+        screens.insert(si.frame(), screen(si));
+    });
+
+    let golden_path = dir.join(format!("{}.golden", name));
+    if flag("BLESS") {
+        let text = actual.iter()
+            .map(|(frame, hash)| format!("{} {:016x}\n", frame, hash))
+            .collect::<String>();
+        fs::write(&golden_path, text).unwrap();
+        if flag("GOLDEN_PNG") {
+            for (frame, rgba) in screens.iter() {
+                fs::write(dir.join(format!("{}-{}.png", name, frame)), png::encode(rgba, WIDTH, HEIGHT)).unwrap();
+            }
+        }
+        return;
+    }
+
+    let expected = match fs::read_to_string(&golden_path) {
+        Ok(text) => parse_golden(&text),
+        Err(e) => panic!("{}: {} (BLESS=1 to record it)", golden_path.display(), e),
+    };
+    let mut failed = Vec::new();
+    for (frame, hash) in actual.iter() {
+        if expected.get(frame) == Some(hash) {
+            continue;
+        }
+        failed.push(*frame);
+        let golden_png = dir.join(format!("{}-{}.png", name, frame));
+        if let Ok((_, _, golden)) = fs::read(&golden_png).map_err(|_| ()).and_then(|d| png::decode(&d).map_err(|_| ())) {
+            let out = out_dir();
+            fs::create_dir_all(&out).unwrap();
+            let rgba = &screens[frame];
+            fs::write(out.join(format!("{}-{}.png", name, frame)), png::encode(rgba, WIDTH, HEIGHT)).unwrap();
+            fs::write(out.join(format!("{}-{}-diff.png", name, frame)),
+                      png::encode(&diff(&golden, rgba), WIDTH, HEIGHT)).unwrap();
+        }
+    }
+    assert!(failed.is_empty(), "{}: frames {:?} don't match the golden ones \
+        (BLESS=1 to accept them)", name, failed);
+}
+
+#[test]
+fn attract() {
+    check("attract");
+}
+
+#[test]
+fn play() {
+    check("play");
+}
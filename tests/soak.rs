@@ -0,0 +1,156 @@
+//! Long deterministic scenarios that check the game state invariants (see
+//! `ram_map::GameState`) and that:
+//!
+//! * credits increase only after a coin
+//! * at game over the hi score is not lower than the scores
+//!
+//! `short_game` runs with the other tests; the long ones take minutes, so they
+//! are ignored by default (CI runs them in a job of their own):
+//!
+//! ```text
+//! cargo test --release --test soak -- --ignored
+//! ```
+
+extern crate wasm_invaders;
+
+use wasm_invaders::{Game, Ev, SpaceInvaders};
+use wasm_invaders::ram_map::GameState;
+
+const FRAMES_PER_MINUTE: u64 = 60 * 60;
+/// Frames the ROM can take to count a coin.
+const COIN_LATENCY: u64 = 60;
+
+fn state(si: &SpaceInvaders) -> GameState {
+    GameState::read(si).unwrap_or_else(|e| panic!("Frame {}: {}", si.frame(), e))
+}
+
+/// Check the invariants between frames.
+#[derive(Default)]
+struct Checker {
+    last: Option<GameState>,
+    last_coin: Option<u64>,
+    games_over: u32,
+}
+
+impl Checker {
+    fn check(&mut self, si: &SpaceInvaders, inputs: u32) {
+        let frame = si.frame();
+        if inputs & Ev::Coin.mask() != 0 {
+            self.last_coin = Some(frame);
+        }
+        let s = state(si);
+        if let Some(last) = self.last {
+            if s.credits > last.credits {
+                assert!(self.last_coin.map(|c| frame - c <= COIN_LATENCY).unwrap_or(false),
+                        "Frame {}: credits from {} to {} without a coin", frame, last.credits, s.credits);
+            }
+            if last.playing && !s.playing {
+                self.games_over += 1;
+                let best = s.scores[0].max(s.scores[1]);
+                assert!(s.hi_score >= best, "Frame {}: game over with hi score {} and score {}",
+                        frame, s.hi_score, best);
+            }
+        }
+        self.last = Some(s);
+    }
+}
+
+fn run<F: FnMut(u64) -> u32>(si: &mut SpaceInvaders, checker: &mut Checker, frames: u64, mut inputs: F) {
+    for _ in 0..frames {
+        let frame = si.frame();
+        let i = inputs(frame);
+        si.set_inputs(i);
+        si.next_frame();
+        checker.check(si, i);
+    }
+}
+
+/// Insert a coin and start a one player game.
+fn start(si: &mut SpaceInvaders, checker: &mut Checker) {
+    run(si, checker, 100, |_| 0);
+    run(si, checker, 10, |_| Ev::Coin.mask());
+    run(si, checker, 60, |_| 0);
+    assert_eq!(1, state(si).credits);
+    run(si, checker, 10, |_| Ev::P1Start.mask());
+    run(si, checker, 120, |_| 0);
+    assert!(state(si).playing);
+    assert_eq!(0, state(si).credits);
+}
+
+#[test]
+#[ignore]
+fn attract_mode_for_30_minutes() {
+    let mut game = Game::new();
+    let mut si = game.space_invaders();
+    let mut checker = Checker::default();
+
+    for _ in 0..30 {
+        run(&mut si, &mut checker, FRAMES_PER_MINUTE, |_| 0);
+
+        let s = state(&si);
+        assert!(!s.playing);
+        assert_eq!(0, s.credits);
+        assert_eq!([0, 0], s.scores);
+    }
+}
+
+/// Sweep the screen and keep shooting.
+fn player(frame: u64) -> u32 {
+    let shoot = match frame % 8 < 4 {
+        true => Ev::P1Shoot.mask(),
+        false => 0,
+    };
+    let direction = match frame / 150 % 2 {
+        0 => Ev::P1Left,
+        _ => Ev::P1Right,
+    };
+    shoot | direction.mask()
+}
+
+/// A few minutes of a game that a still player loses.
+#[test]
+fn short_game() {
+    const MAX_MINUTES: u64 = 5;
+    let mut game = Game::new();
+    let mut si = game.space_invaders();
+    let mut checker = Checker::default();
+    start(&mut si, &mut checker);
+
+    for _ in 0..MAX_MINUTES {
+        run(&mut si, &mut checker, FRAMES_PER_MINUTE, |frame| match frame % 8 < 4 {
+            true => Ev::P1Shoot.mask(),
+            false => 0,
+        });
+        if checker.games_over > 0 {
+            assert_eq!(0, state(&si).credits);
+            return;
+        }
+    }
+    panic!("Still playing after {} minutes", MAX_MINUTES);
+}
+
+#[test]
+#[ignore]
+fn full_game_till_wave_3() {
+    const MAX_MINUTES: u64 = 60;
+    let mut game = Game::new();
+    let mut si = game.space_invaders();
+    si.cheats_load(include_str!("../resources/invaders.xml")).unwrap();
+    let mut checker = Checker::default();
+    start(&mut si, &mut checker);
+    assert!(si.cheat_enable("Infinite Lives P1", true));
+
+    let mut score = 0;
+    for _ in 0..MAX_MINUTES {
+        run(&mut si, &mut checker, FRAMES_PER_MINUTE, player);
+        let s = state(&si);
+        assert!(s.playing, "Game over at frame {}", si.frame());
+        assert!(s.scores[0] >= score, "Score from {} to {}", score, s.scores[0]);
+        assert!(s.ships[0] > 0, "Lives are frozen");
+        score = s.scores[0];
+        if s.racks[0] >= 3 {
+            return;
+        }
+    }
+    panic!("Wave 3 not cleared in {} minutes: score {}", MAX_MINUTES, score);
+}
@@ -1,20 +1,37 @@
-import { Game, SpaceInvaders } from "wasm-invaders";
+import { Game, SpaceInvaders, Ev } from "wasm-invaders";
 import { memory } from "wasm-invaders/wasm_invaders_bg";
 
 
+const HISCORE_KEY = "wasm-invaders.hiscore";
+const BINDINGS_KEY = "wasm-invaders.bindings";
+
 const game = Game.new();
-const si = game.space_invaders();
-const width = game.width();
-const height = game.height();
+let si = game.space_invaders();
+si.enable_hiscore(localStorage.getItem(HISCORE_KEY));
+const loadBindings = () => {
+    const bindings = localStorage.getItem(BINDINGS_KEY);
+    if (bindings !== null) {
+        try {
+            si.set_bindings(bindings);
+        } catch (e) {
+            console.warn("Ignore saved bindings: " + e);
+        }
+    }
+};
+loadBindings();
+const width = game.screen_width();
+const height = game.screen_height();
 
 const canvas = document.getElementById("screen");
 const inMemoryCanvas = document.createElement('canvas');
 const inMemoryCanvasCtx = inMemoryCanvas.getContext('2d');
+const scaledCanvas = document.createElement('canvas');
+const scaledCanvasCtx = scaledCanvas.getContext('2d');
 const w = width * 3;
 const h = height * 3;
 inMemoryCanvas.width = width;
 inMemoryCanvas.height = height;
-canvas.width = h;
+canvas.width = w;
 canvas.height = h;
 
 const ctx = canvas.getContext('2d');
@@ -24,93 +41,75 @@ heading.textContent = game.name();
 const playPauseBtn = document.getElementById("play-pause");
 const coinBtn = document.getElementById("coin");
 const playBtn = document.getElementById("play");
-
-const imgData = new ImageData(width, height);
+const play2Btn = document.getElementById("play2");
+const tiltBtn = document.getElementById("tilt");
 
 let animationId = null;
+let lastTimeStamp = null;
+
+const renderLoop = (timeStamp) => {
+    pollGamepads();
+    if (lastTimeStamp !== null && si.run_for(timeStamp - lastTimeStamp) > 0) {
+        fps.render();
+        draw();
+        saveHiscore();
+    }
+    lastTimeStamp = timeStamp;
 
-const render = () => {
-
-    si.next_frame();
-
-    fps.render()
-
-    draw();
+    animationId = requestAnimationFrame(renderLoop);
 };
 
-const renderLoop = () => {
-    requestAnimationFrame(render);
-
-    animationId = setTimeout(renderLoop, 1000 / 60);
+const saveHiscore = () => {
+    const hiscore = si.take_hiscore();
+    if (hiscore !== undefined) {
+        localStorage.setItem(HISCORE_KEY, hiscore);
+    }
 };
 
-const BLACK = [0, 0, 0];
-const WHITE = [255, 255, 255];
-const RED = [255, 0, 0];
-const GREEN = [0, 255, 0];
-
-const color = (row, col) => {
-    col = 256 - col;
-    if (col <= 32) {
-        return WHITE;
-    }
-    if (col <= 64) {
-        return RED;
-    }
-    if (col <= 184) {
-        return WHITE;
+const draw = () => {
+    const rects = si.render();
+    if (si.post_processed()) {
+        if (rects.length > 0 || crt) {
+            drawScaled(si.scale_factor());
+        }
+        return;
     }
-    if (col <= 240) {
-        return GREEN;
+    const frame = new Uint8ClampedArray(memory.buffer, si.screen(), width * height * 4);
+    const imgData = new ImageData(frame, width, height);
+
+    for (let i = 0; i < rects.length; i += 4) {
+        inMemoryCanvasCtx.putImageData(imgData, 0, 0, rects[i], rects[i + 1], rects[i + 2], rects[i + 3]);
     }
-    if (row > 16 && row <= 134) {
-        return GREEN;
+    if (rects.length > 0) {
+        ctx.imageSmoothingEnabled = false;
+        ctx.drawImage(inMemoryCanvas, 0, 0, w, h);
     }
-    return WHITE
-}
+};
 
-const draw = () => {
-    const vramPtr = game.vram();
-    const vram = new Uint8Array(memory.buffer, vramPtr, (width * height / 8 ));
-
-    for (let row = 0; row < height; row++) {
-        for (let col = 0; col < width; col++) {
-            const idx = getIndex(row, col);
-            let p = idx>>3;
-            let val = (vram[p] & (0x1 << ( idx & 0x7 )))
-
-            let c = val === 0 ? BLACK : color(row, col);
-
-            let pos = ((row * width) + col) * 4;
-            imgData.data[pos] = c[0];
-            imgData.data[pos+1] = c[1];
-            imgData.data[pos+2] = c[2];
-            imgData.data[pos+3] = 255;
-        }
+const drawScaled = (factor) => {
+    const sw = width * factor;
+    const sh = height * factor;
+    if (scaledCanvas.width !== sw || scaledCanvas.height !== sh) {
+        scaledCanvas.width = sw;
+        scaledCanvas.height = sh;
     }
-    inMemoryCanvasCtx.putImageData(imgData,0,0);
+    const frame = new Uint8ClampedArray(memory.buffer, si.scaled(), sw * sh * 4);
+    scaledCanvasCtx.putImageData(new ImageData(frame, sw, sh), 0, 0);
     ctx.imageSmoothingEnabled = false;
-    ctx.save();
-    ctx.translate(w/2, h/2);
-    ctx.rotate( 3 * Math.PI / 2 );
-    ctx.translate(-h/2, -w/2);
-    ctx.drawImage(inMemoryCanvas, 0, 0, w, h);
-    ctx.restore();
-};
-
-const getIndex = (row, col) => {
-  return row * width + col;
+    ctx.drawImage(scaledCanvas, 0, 0, w, h);
 };
 
 const play = () => {
     playPauseBtn.textContent = "⏸";
-    renderLoop();
+    si.pause(false);
+    lastTimeStamp = null;
+    animationId = requestAnimationFrame(renderLoop);
 }
 
 const pause = () => {
     playPauseBtn.textContent = "▶";
-    // cancelAnimationFrame(animationId);
-    clearTimeout(animationId);
+    si.pause(true);
+    cancelAnimationFrame(animationId);
     animationId = null;
 }
 
@@ -136,30 +135,233 @@ const addGameButton = (btn, act) => {
     });
 }
 
+const settings = document.getElementById("settings");
+let upscaler = ["none", 1];
+let freePlay = false;
+let autofire = false;
+let macro = null;
+let crt = false;
+let moon = false;
+let phosphor = false;
+
+const applyCrt = () => {
+    si.set_phosphor(phosphor ? 16 : 0);
+    if (crt) {
+        si.set_crt(0.4, 0.3, 8.0, 0.08, moon);
+    } else {
+        si.crt_off();
+    }
+};
+
+const restart = () => {
+    const speed = si.speed();
+    saveHiscore();
+    si.free();
+    si = game.space_invaders();
+    si.enable_hiscore(localStorage.getItem(HISCORE_KEY));
+    loadBindings();
+    si.set_speed(speed);
+    si.set_upscaler(upscaler[0], upscaler[1]);
+    applyCrt();
+    si.set_free_play(freePlay ? 2 : 0);
+    si.set_autofire(Ev.P1Shoot, autofire ? 4 : 0);
+    if (macro !== null) {
+        si.set_macro("macro", macro);
+    }
+    si.pause(isPaused());
+};
+
+const renderSettings = () => {
+    for (let sw = 0; sw < game.dip_count(); sw++) {
+        const label = document.createElement("label");
+        label.textContent = game.dip_name(sw) + " ";
+        const select = document.createElement("select");
+        for (let v = 0; v < game.dip_value_count(sw); v++) {
+            const option = document.createElement("option");
+            option.value = v;
+            option.textContent = game.dip_label(sw, v);
+            option.selected = v === game.dip_get(sw);
+            select.appendChild(option);
+        }
+        select.addEventListener("change", event => {
+            game.dip_set(sw, parseInt(event.target.value));
+            // Dip switches are read by the new machine: restart it.
+            restart();
+        });
+        label.appendChild(select);
+        const item = document.createElement("li");
+        item.appendChild(label);
+        settings.appendChild(item);
+    }
+
+    const label = document.createElement("label");
+    label.textContent = "Cocktail Table ";
+    const cocktail = document.createElement("input");
+    cocktail.type = "checkbox";
+    cocktail.checked = game.cocktail();
+    cocktail.addEventListener("change", event => {
+        game.set_cocktail(event.target.checked);
+        restart();
+    });
+    label.appendChild(cocktail);
+    const item = document.createElement("li");
+    item.appendChild(label);
+    settings.appendChild(item);
+
+    const freePlayLabel = document.createElement("label");
+    freePlayLabel.textContent = "Free Play ";
+    const freePlayCheck = document.createElement("input");
+    freePlayCheck.type = "checkbox";
+    freePlayCheck.addEventListener("change", event => {
+        freePlay = event.target.checked;
+        si.set_free_play(freePlay ? 2 : 0);
+    });
+    freePlayLabel.appendChild(freePlayCheck);
+    const freePlayItem = document.createElement("li");
+    freePlayItem.appendChild(freePlayLabel);
+    settings.appendChild(freePlayItem);
+
+    const autofireLabel = document.createElement("label");
+    autofireLabel.textContent = "Autofire ";
+    const autofireCheck = document.createElement("input");
+    autofireCheck.type = "checkbox";
+    autofireCheck.addEventListener("change", event => {
+        autofire = event.target.checked;
+        si.set_autofire(Ev.P1Shoot, autofire ? 4 : 0);
+    });
+    autofireLabel.appendChild(autofireCheck);
+    const autofireItem = document.createElement("li");
+    autofireItem.appendChild(autofireLabel);
+    settings.appendChild(autofireItem);
+
+    const recordMacro = document.createElement("button");
+    recordMacro.textContent = "Record Macro";
+    recordMacro.addEventListener("click", event => {
+        if (si.stop_macro() === undefined) {
+            si.record_macro("macro");
+            recordMacro.textContent = "Stop Macro";
+        } else {
+            macro = si.macro_text("macro");
+            recordMacro.textContent = "Record Macro";
+        }
+    });
+    const playMacro = document.createElement("button");
+    playMacro.textContent = "Play Macro";
+    playMacro.addEventListener("click", event => {
+        si.play_macro("macro");
+    });
+    const macroItem = document.createElement("li");
+    macroItem.appendChild(recordMacro);
+    macroItem.appendChild(playMacro);
+    settings.appendChild(macroItem);
+
+    const selfTest = document.createElement("button");
+    selfTest.textContent = "Self Test";
+    selfTest.addEventListener("click", event => {
+        alert(game.self_test());
+    });
+    const selfTestItem = document.createElement("li");
+    selfTestItem.appendChild(selfTest);
+    settings.appendChild(selfTestItem);
+
+    const bindingsBtn = document.createElement("button");
+    bindingsBtn.textContent = "Bindings";
+    bindingsBtn.addEventListener("click", event => {
+        const bindings = prompt("Input bindings (action followed by sources, ';' between lines)",
+            si.bindings().trim().split("\n").join("; "));
+        if (bindings === null) {
+            return;
+        }
+        const text = bindings.split(";").join("\n");
+        try {
+            si.set_bindings(text);
+            localStorage.setItem(BINDINGS_KEY, text);
+        } catch (e) {
+            alert(e);
+        }
+    });
+    const bindingsItem = document.createElement("li");
+    bindingsItem.appendChild(bindingsBtn);
+    settings.appendChild(bindingsItem);
+
+    const speedLabel = document.createElement("label");
+    speedLabel.textContent = "Speed ";
+    const speed = document.createElement("select");
+    for (const s of [0.25, 0.5, 1, 2, 4, 8]) {
+        const option = document.createElement("option");
+        option.value = s;
+        option.textContent = s + "x";
+        option.selected = s === si.speed();
+        speed.appendChild(option);
+    }
+    speed.addEventListener("change", event => {
+        si.set_speed(parseFloat(event.target.value));
+    });
+    speedLabel.appendChild(speed);
+    const speedItem = document.createElement("li");
+    speedItem.appendChild(speedLabel);
+    settings.appendChild(speedItem);
+
+    const filterLabel = document.createElement("label");
+    filterLabel.textContent = "Filter ";
+    const filter = document.createElement("select");
+    for (const [name, f, factor] of [["None", "none", 1], ["Scale2x", "scale2x", 2],
+        ["Scale3x", "scale3x", 3], ["xBR", "xbr", 2]]) {
+        const option = document.createElement("option");
+        option.value = f + ":" + factor;
+        option.textContent = name;
+        filter.appendChild(option);
+    }
+    filter.addEventListener("change", event => {
+        const [f, factor] = event.target.value.split(":");
+        upscaler = [f, parseInt(factor)];
+        si.set_upscaler(f, upscaler[1]);
+    });
+    filterLabel.appendChild(filter);
+    const filterItem = document.createElement("li");
+    filterItem.appendChild(filterLabel);
+    settings.appendChild(filterItem);
+
+    for (const [name, set] of [["CRT ", v => { crt = v; }], ["Moon Backdrop ", v => { moon = v; }],
+        ["Phosphor ", v => { phosphor = v; }]]) {
+        const label = document.createElement("label");
+        label.textContent = name;
+        const check = document.createElement("input");
+        check.type = "checkbox";
+        check.addEventListener("change", event => {
+            set(event.target.checked);
+            applyCrt();
+        });
+        label.appendChild(check);
+        const item = document.createElement("li");
+        item.appendChild(label);
+        settings.appendChild(item);
+    }
+};
+
+renderSettings();
+
 const coin = (v) => { si.coin(v) };
 const plr = (v) => { si.play(v) };
 
 addGameButton(coinBtn, coin);
 addGameButton(playBtn, plr);
+addGameButton(play2Btn, (v) => { si.set_input(Ev.P2Start, v) });
+addGameButton(tiltBtn, (v) => { si.set_input(Ev.Tilt, v) });
 
 const keyboard = (event) => {
     const pressed = event.type === "keydown";
-    switch (event.key) {
-        case "ArrowLeft":
-            // Left pressed
-            si.left(pressed);
-            break;
-        case "ArrowRight":
-            // Right pressed
-            si.right(pressed);
-            break;
-        case " ":
-            // Space pressed
-            si.shoot(pressed);
-            break;
-        default: return;
+    if (event.key === "Tab") {
+        // Hold Tab for turbo
+        si.turbo(pressed);
+        event.preventDefault();
+        return;
+    }
+    // Auto repeats press again a pressed key (nothing changes) but must be
+    // prevented too, or held arrows and Space scroll the page
+    if (si.key(event.code, pressed)) {
+        event.preventDefault();
     }
-    event.preventDefault();
 }
 
 document.addEventListener("keydown", event => {
@@ -170,6 +372,37 @@ document.addEventListener("keyup", event => {
     keyboard(event);
 });
 
+window.addEventListener("blur", event => {
+    si.release_all();
+});
+
+const touches = (event) => {
+    const rect = canvas.getBoundingClientRect();
+    for (const t of event.changedTouches) {
+        if (event.type === "touchend" || event.type === "touchcancel") {
+            si.touch_end(t.identifier);
+        } else {
+            si.touch(t.identifier, (t.clientX - rect.left) / rect.width,
+                (t.clientY - rect.top) / rect.height);
+        }
+    }
+    event.preventDefault();
+};
+
+for (const type of ["touchstart", "touchmove", "touchend", "touchcancel"]) {
+    canvas.addEventListener(type, touches);
+}
+
+const pollGamepads = () => {
+    for (const pad of navigator.getGamepads ? navigator.getGamepads() : []) {
+        if (!pad) {
+            continue;
+        }
+        pad.buttons.forEach((b, i) => si.gamepad_button(pad.index, i, b.pressed));
+        pad.axes.forEach((v, i) => si.gamepad_axis(pad.index, i, v));
+    }
+};
+
 const fps = new class {
   constructor() {
     this.fps = document.getElementById("fps");